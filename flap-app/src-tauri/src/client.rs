@@ -1,6 +1,19 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
 use flap_lib::{
+    code::{server::NodeId, ShortCode},
+    crypto::transfer_id::TransferId,
+    error::{Error, Result},
     event::{get_event_handler, Event},
-    p2p::{receiver::P2pReceiver, sender::P2pSender},
+    fs::save::ConflictPolicy,
+    p2p::{
+        cancel::CancelReason, endpoint::P2pEndpointConfig, policy::TicketPolicy,
+        receiver::P2pReceiver, scheduler::SchedulerConfig, sender::P2pSender,
+    },
+    ticket::Ticket,
 };
 use tauri::{async_runtime, AppHandle, Emitter};
 
@@ -9,20 +22,52 @@ use crate::frontend_events;
 pub struct Client {
     p2p_sender: P2pSender,
     p2p_receiver: P2pReceiver,
+    /// Set from the settings, and read whenever a received file has a name that is taken.
+    conflict_policy: Arc<Mutex<ConflictPolicy>>,
+    /// Set from the settings. The default of the receiver is used if unset.
+    download_dir: Mutex<Option<PathBuf>>,
+    /// The rendezvous server of the settings. Short codes cannot be used without one.
+    rendezvous: Option<NodeId>,
     #[expect(dead_code)]
     tauri_app_handle: AppHandle,
 }
 
 impl Client {
-    pub async fn start(tauri_app_handle: AppHandle) -> Self {
-        let p2p_sender = P2pSender::new().await.unwrap();
-        let p2p_receiver = P2pReceiver::new().await.unwrap();
+    pub async fn start(
+        tauri_app_handle: AppHandle,
+        endpoint_config: P2pEndpointConfig,
+        rendezvous: Option<NodeId>,
+    ) -> Self {
+        let p2p_sender = P2pSender::new(
+            &endpoint_config,
+            TicketPolicy::default(),
+            SchedulerConfig::default(),
+        )
+        .await
+        .unwrap();
+        let mut p2p_receiver = P2pReceiver::new(&endpoint_config).await.unwrap();
+
+        // Conflicts are resolved with whatever the settings say at the time
+        let conflict_policy = Arc::new(Mutex::new(ConflictPolicy::default()));
+        let mut conflicts = p2p_receiver.ask_on_conflict();
+        let _conflict_task = async_runtime::spawn({
+            let conflict_policy = conflict_policy.clone();
+            async move {
+                while let Some(conflict) = conflicts.recv().await {
+                    let policy = *conflict_policy.lock().expect("lock is not poisoned");
+                    conflict.resolve(policy);
+                }
+            }
+        });
 
         let tauri_app_handle_c = tauri_app_handle.clone();
 
         let client = Self {
             p2p_sender,
             p2p_receiver,
+            conflict_policy,
+            download_dir: Mutex::new(None),
+            rendezvous,
             tauri_app_handle,
         };
 
@@ -69,6 +114,104 @@ impl Client {
                             )
                             .unwrap();
                     }
+                    // The app accepts every transfer for now
+                    Event::TransferOffered(..) => {}
+                    Event::TransferDeclined(file_transfer_id) => {
+                        tauri_app_handle_c
+                            .emit(
+                                "transfer-declined",
+                                frontend_events::TransferDeclinedEvent {
+                                    file_transfer_id: file_transfer_id.as_ref().to_vec(),
+                                },
+                            )
+                            .unwrap();
+                    }
+                    Event::TransferCancelled(file_transfer_id, _reason) => {
+                        tauri_app_handle_c
+                            .emit(
+                                "transfer-cancelled",
+                                frontend_events::TransferCancelledEvent {
+                                    file_transfer_id: file_transfer_id.as_ref().to_vec(),
+                                },
+                            )
+                            .unwrap();
+                    }
+                    Event::TransferRefused(file_transfer_id, reason) => {
+                        tauri_app_handle_c
+                            .emit(
+                                "transfer-refused",
+                                frontend_events::TransferRefusedEvent {
+                                    file_transfer_id: file_transfer_id.as_ref().to_vec(),
+                                    reason: format!("{reason:?}"),
+                                },
+                            )
+                            .unwrap();
+                    }
+                    Event::TransferPaused(file_transfer_id) => {
+                        tauri_app_handle_c
+                            .emit(
+                                "transfer-paused",
+                                frontend_events::TransferPausedEvent {
+                                    file_transfer_id: file_transfer_id.as_ref().to_vec(),
+                                },
+                            )
+                            .unwrap();
+                    }
+                    Event::TransferResumed(file_transfer_id) => {
+                        tauri_app_handle_c
+                            .emit(
+                                "transfer-resumed",
+                                frontend_events::TransferResumedEvent {
+                                    file_transfer_id: file_transfer_id.as_ref().to_vec(),
+                                },
+                            )
+                            .unwrap();
+                    }
+                    Event::Reconnecting(attempt) => {
+                        tauri_app_handle_c
+                            .emit(
+                                "reconnecting",
+                                frontend_events::ReconnectingEvent { attempt },
+                            )
+                            .unwrap();
+                    }
+                    Event::Reconnected => {
+                        tauri_app_handle_c.emit("reconnected", ()).unwrap();
+                    }
+                    Event::ManifestReceived(manifest) => {
+                        tauri_app_handle_c
+                            .emit(
+                                "manifest-received",
+                                frontend_events::ManifestReceivedEvent {
+                                    file_count: manifest.file_count(),
+                                    total_size: manifest.total_size(),
+                                },
+                            )
+                            .unwrap();
+                    }
+                    Event::ManifestRefused(reason) => {
+                        tauri_app_handle_c
+                            .emit(
+                                "manifest-refused",
+                                frontend_events::ManifestRefusedEvent {
+                                    reason: format!("{reason:?}"),
+                                },
+                            )
+                            .unwrap();
+                    }
+                    Event::BatchUpdate(bytes_received, total_size) => {
+                        tauri_app_handle_c
+                            .emit(
+                                "batch-update",
+                                frontend_events::BatchUpdateEvent {
+                                    bytes_received,
+                                    total_size,
+                                },
+                            )
+                            .unwrap();
+                    }
+                    // Resolved with the conflict policy of the settings
+                    Event::FileConflict(..) => {}
                 }
             }
         });
@@ -76,17 +219,104 @@ impl Client {
         client
     }
 
-    pub fn ticket_string(&self) -> String {
-        self.p2p_sender.ticket.convert()
+    pub async fn ticket_string(&self) -> String {
+        self.p2p_sender.ticket().await.convert()
+    }
+
+    pub fn rendezvous(&self) -> Option<NodeId> {
+        self.rendezvous
+    }
+
+    /// A short code for the next receiver, instead of the ticket. The receiver
+    /// that enters it is given the ticket in the background.
+    pub async fn code_string(&self, rendezvous: NodeId) -> Result<String> {
+        let rendezvous = self.p2p_sender.rendezvous(rendezvous);
+        let code = ShortCode::allocate(&rendezvous).await?;
+        let code_string = code.to_string();
+
+        let p2p_sender = self.p2p_sender.clone();
+        async_runtime::spawn(async move {
+            if let Err(err) = p2p_sender.send_code(&code, &rendezvous).await {
+                println!("Could not share the ticket: {err}");
+            }
+        });
+
+        Ok(code_string)
     }
 
     pub async fn send_file(&self, file_path: String) {
         self.p2p_sender.send(file_path).await.unwrap();
     }
 
-    pub async fn receive_file(&self, ticket_string: String) {
-        let ticket = ticket_string.parse().unwrap();
+    fn parse_transfer_id(transfer_id: Vec<u8>) -> Result<TransferId> {
+        Ok(TransferId(
+            transfer_id.try_into().map_err(|_| Error::UnknownTransfer)?,
+        ))
+    }
+
+    /// Cancels the transfer, whether this device is sending or receiving it.
+    pub fn cancel_transfer(&self, transfer_id: Vec<u8>) -> Result<()> {
+        let transfer_id = Self::parse_transfer_id(transfer_id)?;
+
+        self.p2p_sender
+            .cancel(transfer_id, CancelReason::UserRequested)
+            .or_else(|_| {
+                self.p2p_receiver
+                    .cancel(transfer_id, CancelReason::UserRequested)
+            })
+    }
+
+    /// Pauses the transfer, whether this device is sending or receiving it.
+    pub fn pause_transfer(&self, transfer_id: Vec<u8>) -> Result<()> {
+        let transfer_id = Self::parse_transfer_id(transfer_id)?;
+
+        self.p2p_sender
+            .pause(transfer_id)
+            .or_else(|_| self.p2p_receiver.pause(transfer_id))
+    }
+
+    pub fn resume_transfer(&self, transfer_id: Vec<u8>) -> Result<()> {
+        let transfer_id = Self::parse_transfer_id(transfer_id)?;
+
+        self.p2p_sender
+            .resume(transfer_id)
+            .or_else(|_| self.p2p_receiver.resume(transfer_id))
+    }
+
+    pub fn set_conflict_policy(&self, conflict_policy: ConflictPolicy) {
+        *self.conflict_policy.lock().expect("lock is not poisoned") = conflict_policy;
+    }
+
+    pub fn set_download_dir(&self, download_dir: PathBuf) {
+        *self.download_dir.lock().expect("lock is not poisoned") = Some(download_dir);
+    }
+
+    pub async fn receive_file(&self, ticket_string: String) -> Result<()> {
+        self.receive(ticket_string.parse()?).await
+    }
+
+    /// Receives with a short code, whose ticket is first fetched from `rendezvous`.
+    pub async fn receive_code(&self, rendezvous: NodeId, code_string: String) -> Result<()> {
+        let code: ShortCode = code_string.parse()?;
+        let ticket = code
+            .receive_ticket(&self.p2p_receiver.rendezvous(rendezvous))
+            .await?;
+
+        self.receive(ticket).await
+    }
+
+    async fn receive(&self, ticket: Ticket) -> Result<()> {
+        // The clone shares the running transfers, so they can still be cancelled or paused
+        let mut p2p_receiver = self.p2p_receiver.clone();
+        if let Some(download_dir) = self
+            .download_dir
+            .lock()
+            .expect("lock is not poisoned")
+            .clone()
+        {
+            p2p_receiver.set_download_dir(download_dir);
+        }
 
-        self.p2p_receiver.retrieve(ticket).await.unwrap();
+        p2p_receiver.retrieve(ticket).await
     }
 }
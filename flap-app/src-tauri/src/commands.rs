@@ -1,4 +1,7 @@
-use crate::client::Client;
+use flap_lib::fs::save::ConflictPolicy;
+use tauri::{AppHandle, Manager};
+
+use crate::{client::Client, settings::NetworkSettings};
 
 #[tauri::command]
 pub async fn send_file(client: tauri::State<'_, Client>, file_path: String) -> Result<(), ()> {
@@ -9,8 +12,110 @@ pub async fn send_file(client: tauri::State<'_, Client>, file_path: String) -> R
 }
 
 #[tauri::command]
-pub fn get_send_ticket(client: tauri::State<'_, Client>) -> String {
-    client.ticket_string()
+pub async fn get_send_ticket(client: tauri::State<'_, Client>) -> Result<String, ()> {
+    Ok(client.ticket_string().await)
+}
+
+#[tauri::command]
+pub async fn get_send_code(client: tauri::State<'_, Client>) -> Result<String, ()> {
+    let Some(rendezvous) = client.rendezvous() else {
+        println!("Set a rendezvous server to use short codes");
+        return Err(());
+    };
+
+    client.code_string(rendezvous).await.map_err(|err| {
+        println!("Could not make a code: {err}");
+    })
+}
+
+#[tauri::command]
+pub async fn cancel_transfer(
+    client: tauri::State<'_, Client>,
+    transfer_id: Vec<u8>,
+) -> Result<(), ()> {
+    client.cancel_transfer(transfer_id).map_err(|_| ())
+}
+
+#[tauri::command]
+pub async fn pause_transfer(
+    client: tauri::State<'_, Client>,
+    transfer_id: Vec<u8>,
+) -> Result<(), ()> {
+    client.pause_transfer(transfer_id).map_err(|_| ())
+}
+
+#[tauri::command]
+pub async fn resume_transfer(
+    client: tauri::State<'_, Client>,
+    transfer_id: Vec<u8>,
+) -> Result<(), ()> {
+    client.resume_transfer(transfer_id).map_err(|_| ())
+}
+
+#[tauri::command]
+pub async fn set_conflict_policy(
+    client: tauri::State<'_, Client>,
+    conflict_policy: String,
+) -> Result<(), ()> {
+    let conflict_policy = match conflict_policy.as_str() {
+        "overwrite" => ConflictPolicy::Overwrite,
+        "rename" => ConflictPolicy::Rename,
+        "skip" => ConflictPolicy::Skip,
+        _ => return Err(()),
+    };
+    client.set_conflict_policy(conflict_policy);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_download_dir(
+    client: tauri::State<'_, Client>,
+    download_dir: String,
+) -> Result<(), ()> {
+    client.set_download_dir(download_dir.into());
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_network_settings(app: AppHandle) -> Result<NetworkSettings, ()> {
+    let config_dir = app.path().app_config_dir().map_err(|_| ())?;
+
+    Ok(NetworkSettings::load(&config_dir))
+}
+
+/// Saved for the next time the app starts.
+#[tauri::command]
+pub async fn set_network_settings(
+    app: AppHandle,
+    network_settings: NetworkSettings,
+) -> Result<(), ()> {
+    // Refused now, rather than silently replaced by the default once restarted
+    if !network_settings.is_valid() {
+        return Err(());
+    }
+    let config_dir = app.path().app_config_dir().map_err(|_| ())?;
+
+    network_settings.save(&config_dir).map_err(|err| {
+        println!("Could not save the network settings: {err}");
+    })
+}
+
+#[tauri::command]
+pub async fn receive_code(client: tauri::State<'_, Client>, code_string: String) -> Result<(), ()> {
+    let Some(rendezvous) = client.rendezvous() else {
+        println!("Set a rendezvous server to use short codes");
+        return Err(());
+    };
+
+    println!("Begin receive");
+    client
+        .receive_code(rendezvous, code_string)
+        .await
+        .map_err(|err| {
+            println!("Could not receive: {err}");
+        })
 }
 
 #[tauri::command]
@@ -19,7 +124,7 @@ pub async fn receive_file(
     ticket_string: String,
 ) -> Result<(), ()> {
     println!("Begin receive");
-    client.receive_file(ticket_string).await;
-
-    Ok(())
+    client.receive_file(ticket_string).await.map_err(|err| {
+        println!("Could not receive: {err}");
+    })
 }
@@ -27,3 +27,62 @@ pub struct TransferUpdateEvent {
 pub struct TransferCompleteEvent {
     pub file_transfer_id: Vec<u8>,
 }
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferDeclinedEvent {
+    pub file_transfer_id: Vec<u8>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferCancelledEvent {
+    pub file_transfer_id: Vec<u8>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestReceivedEvent {
+    pub file_count: usize,
+    pub total_size: u64,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestRefusedEvent {
+    /// Why the files were refused, e.g. `NotEnoughSpace`.
+    pub reason: String,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchUpdateEvent {
+    pub bytes_received: u64,
+    pub total_size: u64,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferRefusedEvent {
+    pub file_transfer_id: Vec<u8>,
+    /// Why the file was refused, e.g. `NotEnoughSpace`.
+    pub reason: String,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferPausedEvent {
+    pub file_transfer_id: Vec<u8>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferResumedEvent {
+    pub file_transfer_id: Vec<u8>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconnectingEvent {
+    pub attempt: u32,
+}
@@ -1,10 +1,11 @@
 use tauri::{async_runtime, Manager};
 
-use crate::client::Client;
+use crate::{client::Client, settings::NetworkSettings};
 
 pub mod client;
 pub mod commands;
 pub mod frontend_events;
+pub mod settings;
 
 pub struct AppState {
     pub client: Client,
@@ -18,10 +19,21 @@ pub fn run() {
         //.plugin(tauri_plugin_opener::init())
         .setup(|app| {
             let handler = app.handle().clone();
+            let network_settings = app
+                .path()
+                .app_config_dir()
+                .map(|config_dir| NetworkSettings::load(&config_dir))
+                .unwrap_or_default();
             async_runtime::spawn(async move {
                 println!("Setting up client");
 
-                let client = Client::start(handler.clone()).await;
+                let endpoint_config = network_settings.endpoint_config().unwrap_or_default();
+                let client = Client::start(
+                    handler.clone(),
+                    endpoint_config,
+                    network_settings.rendezvous(),
+                )
+                .await;
                 handler.manage(client);
                 println!("Client has been set up");
             });
@@ -32,6 +44,15 @@ pub fn run() {
             commands::send_file,
             commands::receive_file,
             commands::get_send_ticket,
+            commands::get_send_code,
+            commands::receive_code,
+            commands::cancel_transfer,
+            commands::pause_transfer,
+            commands::resume_transfer,
+            commands::set_conflict_policy,
+            commands::set_download_dir,
+            commands::get_network_settings,
+            commands::set_network_settings,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
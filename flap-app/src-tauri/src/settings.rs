@@ -0,0 +1,92 @@
+use std::path::Path;
+
+use flap_lib::{
+    code::server::NodeId,
+    p2p::endpoint::{DiscoveryMode, P2pEndpointConfig, RelayConfig},
+};
+use serde::{Deserialize, Serialize};
+
+/// Where the network settings are saved, in the config directory of the app.
+const NETWORK_SETTINGS_FILE: &str = "network.json";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Discovery {
+    #[default]
+    N0,
+    LocalNetwork,
+    Disabled,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Relay {
+    #[default]
+    Default,
+    /// The relay at `relay_url`.
+    Custom,
+    Disabled,
+}
+
+/// How other devices are found and reached.
+///
+/// The endpoints are started along with the app, so changes apply once it restarts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkSettings {
+    pub discovery: Discovery,
+    pub relay: Relay,
+    pub relay_url: Option<String>,
+    /// The node id of the rendezvous server short codes go through.
+    /// Short codes cannot be used without one.
+    pub rendezvous: Option<String>,
+}
+
+impl NetworkSettings {
+    /// The settings saved in `config_dir`, or the default ones if there are none.
+    pub fn load(config_dir: &Path) -> Self {
+        std::fs::read(config_dir.join(NETWORK_SETTINGS_FILE))
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, config_dir: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(config_dir)?;
+        std::fs::write(
+            config_dir.join(NETWORK_SETTINGS_FILE),
+            serde_json::to_vec_pretty(self)?,
+        )
+    }
+
+    /// Whether the settings can be used once the app restarts.
+    pub fn is_valid(&self) -> bool {
+        self.endpoint_config().is_some()
+            && (self.rendezvous.is_none() || self.rendezvous().is_some())
+    }
+
+    /// `None` if unset, or not a valid node id.
+    pub fn rendezvous(&self) -> Option<NodeId> {
+        self.rendezvous.as_deref()?.parse().ok()
+    }
+
+    /// `None` if a custom relay is chosen without a valid URL.
+    pub fn endpoint_config(&self) -> Option<P2pEndpointConfig> {
+        let discovery = match self.discovery {
+            Discovery::N0 => DiscoveryMode::N0,
+            Discovery::LocalNetwork => DiscoveryMode::LocalNetwork,
+            Discovery::Disabled => DiscoveryMode::Disabled,
+        };
+        let relay = match self.relay {
+            Relay::Default => RelayConfig::Default,
+            Relay::Custom => RelayConfig::Custom(self.relay_url.as_deref()?.parse().ok()?),
+            Relay::Disabled => RelayConfig::Disabled,
+        };
+
+        Some(P2pEndpointConfig {
+            discovery,
+            relay,
+            bind_addr: None,
+        })
+    }
+}
@@ -35,6 +35,46 @@ type TransferCompleteEvent = {
   fileTransferId: TransferId;
 };
 
+type TransferDeclinedEvent = {
+  fileTransferId: TransferId;
+};
+
+type TransferCancelledEvent = {
+  fileTransferId: TransferId;
+};
+
+type ManifestReceivedEvent = {
+  fileCount: number;
+  totalSize: number;
+};
+
+type BatchUpdateEvent = {
+  bytesReceived: number;
+  totalSize: number;
+};
+
+// Every file the sender announced, and how much of them was received
+type Batch = {
+  fileCount: number;
+  totalSize: number;
+  bytesReceived: number;
+};
+
+const formatSize = (bytes: number) => {
+  const units = ['B', 'KB', 'MB', 'GB', 'TB'];
+  let unit = 0;
+  while (bytes >= 1000 && unit < units.length - 1) {
+    bytes /= 1000;
+    unit++;
+  }
+  return `${unit === 0 ? bytes : bytes.toFixed(1)} ${units[unit]}`;
+}
+
+type TransferRefusedEvent = {
+  fileTransferId: TransferId;
+  reason: string;
+};
+
 function App() {
   const [sendTicket, setSendTicket] = useState("");
   const [receiveTicket, setReceiveTicket] = useState("");
@@ -51,7 +91,11 @@ function App() {
   //   obtaining a transfer id)
   const [transfers, setTransfers] = useState<Map<string, Transfer>>(new Map());
   const [completedTransfers, setCompletedTransfers] = useState<FileMetadata[]>([]);
+  const [batch, setBatch] = useState<Batch | null>(null);
 
+  const useShortCode = () => {
+    invoke('get_send_code').then((code_string) => setSendTicket(code_string as string))
+  }
   const copyTicketToClipboard = async () => {
     console.log("Copied ticket to clipboard")
     await writeText(sendTicket);
@@ -102,6 +146,22 @@ function App() {
       setTransfers(newMap)
     });
 
+    listen<ManifestReceivedEvent>('manifest-received', (event) => {
+      setBatch((batch) => ({
+        fileCount: event.payload.fileCount,
+        totalSize: event.payload.totalSize,
+        bytesReceived: batch?.bytesReceived ?? 0,
+      }))
+    });
+
+    listen<BatchUpdateEvent>('batch-update', (event) => {
+      setBatch((batch) => batch && {
+        ...batch,
+        totalSize: event.payload.totalSize,
+        bytesReceived: event.payload.bytesReceived,
+      })
+    });
+
     listen('tauri://drag-drop', event => {
       let filePath: string = (event as any).payload.paths[0]
       addFile(filePath)
@@ -139,8 +199,48 @@ function App() {
       }
       setTransfersInProgress(transfersInProgress - 1)
     })
+
+    listen<TransferDeclinedEvent>('transfer-declined', (event) => {
+      const newTransfers = new Map(transfers)
+      newTransfers.delete(event.payload.fileTransferId.toString())
+      setTransfers(newTransfers)
+
+      if (transfersInProgress <= 1) {
+        setCrowFlying(false)
+      }
+      setTransfersInProgress(transfersInProgress - 1)
+    })
+
+    listen<TransferCancelledEvent>('transfer-cancelled', (event) => {
+      const newTransfers = new Map(transfers)
+      newTransfers.delete(event.payload.fileTransferId.toString())
+      setTransfers(newTransfers)
+
+      if (transfersInProgress <= 1) {
+        setCrowFlying(false)
+      }
+      setTransfersInProgress(transfersInProgress - 1)
+    })
+
+    listen<TransferRefusedEvent>('transfer-refused', (event) => {
+      console.log(`Transfer refused: ${event.payload.reason}`)
+
+      const newTransfers = new Map(transfers)
+      newTransfers.delete(event.payload.fileTransferId.toString())
+      setTransfers(newTransfers)
+
+      if (transfersInProgress <= 1) {
+        setCrowFlying(false)
+      }
+      setTransfersInProgress(transfersInProgress - 1)
+    })
   }, [transfers, setTransfers]);
 
+  // Transfers are keyed by the string of their id's bytes
+  const cancelTransfer = (transferId: string) => {
+    invoke('cancel_transfer', { transferId: transferId.split(',').map(Number) })
+  }
+
   const selectFileDialog = async () => {
     const filePath = await open({
       multiple: false,
@@ -173,6 +273,7 @@ function App() {
               <img className="icon" src="file-plus.svg" onClick={selectFileDialog} alt="Add a new file for this transfer" />
               <h3 onClick={copyTicketToClipboard}>{sendTicket}</h3>
             </div>
+            <button onClick={useShortCode}>Use a short code instead</button>
             <div className="transfers completed">
               {
                 [...completedTransfers].filter((metadata) => pendingSendingTransfers.get(metadata.fileName) !== undefined).map((metadata, i) => {
@@ -189,6 +290,7 @@ function App() {
                   return <div className="transfer" key={transfer_id}>
                     <b>{transfer.metadata.fileName}</b>
                     <progress max="100" value={transfer.progress === 0 ? undefined : transfer.progress}></progress>
+                    <img className="icon" src="x.svg" onClick={() => cancelTransfer(transfer_id)} alt="Cancel this transfer" />
                   </div>
                 })
               }
@@ -200,7 +302,11 @@ function App() {
               className="row"
               onSubmit={(e) => {
                 e.preventDefault();
-                invoke('receive_file', { ticketString: receiveTicket }).then(() => {
+                // Tickets start with "flap/", short codes with a number
+                const received = receiveTicket.startsWith("flap/")
+                  ? invoke('receive_file', { ticketString: receiveTicket })
+                  : invoke('receive_code', { codeString: receiveTicket });
+                received.then(() => {
                   console.log("File received")
                 })
               }}
@@ -208,15 +314,20 @@ function App() {
               <input
                 id="ticket-input"
                 onChange={(e) => { setReceiveTicket(e.target.value) }}
-                placeholder="flap/<id>/<key>"
+                placeholder="flap/<id>/<key> or 7-crow-feather"
               />
             </form>
+            {batch && <div className="batch">
+              <b>{batch.fileCount} files, {formatSize(batch.totalSize)} total</b>
+              <progress max="100" value={batch.totalSize === 0 ? 100 : (100 * batch.bytesReceived) / batch.totalSize}></progress>
+            </div>}
             <div className="transfers receiving">
               {
                 [...transfers].filter(([_transfer_id, transfer]) => !transfer.sending).map(([transfer_id, transfer]) => {
                   return <div className="transfer" key={transfer_id}>
                     <b>{transfer.metadata.fileName}</b>
                     <progress max="100" value={transfer.progress}></progress>
+                    <img className="icon" src="x.svg" onClick={() => cancelTransfer(transfer_id)} alt="Cancel this transfer" />
                   </div>
                 })
               }
@@ -1,9 +1,105 @@
+import { useEffect, useState } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { open } from "@tauri-apps/plugin-dialog";
 import { Modal } from "./Modal";
 
+type NetworkSettings = {
+    discovery: "n0" | "local-network" | "disabled",
+    relay: "default" | "custom" | "disabled",
+    relayUrl: string | null,
+    rendezvous: string | null,
+};
+
 export default function SettingsModal() {
+    const setConflictPolicy = (conflictPolicy: string) => {
+        invoke('set_conflict_policy', { conflictPolicy });
+    }
+
+    const [downloadDir, setDownloadDir] = useState<string | null>(null);
+    const chooseDownloadDir = async () => {
+        const dir = await open({
+            multiple: false,
+            directory: true,
+        });
+
+        if (dir) {
+            invoke('set_download_dir', { downloadDir: dir });
+            setDownloadDir(dir);
+        }
+    }
+
+    const [networkSettings, setNetworkSettings] = useState<NetworkSettings | null>(null);
+    const [networkSettingsError, setNetworkSettingsError] = useState(false);
+    useEffect(() => {
+        invoke('get_network_settings').then((settings) => setNetworkSettings(settings as NetworkSettings));
+    }, []);
+    const changeNetworkSettings = (change: Partial<NetworkSettings>) => {
+        if (!networkSettings) {
+            return;
+        }
+        const settings = { ...networkSettings, ...change };
+        setNetworkSettings(settings);
+        invoke('set_network_settings', { networkSettings: settings })
+            .then(() => setNetworkSettingsError(false))
+            .catch(() => setNetworkSettingsError(true));
+    }
+
     return <Modal
         button={<img className="icon" src="settings.svg" />}
     >
         <b>Settings modal</b>
+        <label>
+            When a received file has the name of another file
+            <select defaultValue="rename" onChange={(e) => setConflictPolicy(e.target.value)}>
+                <option value="rename">Rename it to "name (1)"</option>
+                <option value="overwrite">Overwrite the other file</option>
+                <option value="skip">Skip it</option>
+            </select>
+        </label>
+        <label>
+            Save received files in
+            <button onClick={chooseDownloadDir}>{downloadDir ?? "Flap Downloads"}</button>
+        </label>
+        {networkSettings && <>
+            <label>
+                Find other devices
+                <select
+                    value={networkSettings.discovery}
+                    onChange={(e) => changeNetworkSettings({ discovery: e.target.value as NetworkSettings["discovery"] })}
+                >
+                    <option value="n0">Through the n0 discovery service</option>
+                    <option value="local-network">On the local network only</option>
+                    <option value="disabled">Only through their tickets</option>
+                </select>
+            </label>
+            <label>
+                When devices cannot connect directly
+                <select
+                    value={networkSettings.relay}
+                    onChange={(e) => changeNetworkSettings({ relay: e.target.value as NetworkSettings["relay"] })}
+                >
+                    <option value="default">Use the n0 relays</option>
+                    <option value="custom">Use my own relay</option>
+                    <option value="disabled">Do not connect</option>
+                </select>
+            </label>
+            {networkSettings.relay === "custom" && <label>
+                Relay URL
+                <input
+                    value={networkSettings.relayUrl ?? ""}
+                    onChange={(e) => changeNetworkSettings({ relayUrl: e.target.value })}
+                />
+            </label>}
+            <label>
+                Rendezvous server for short codes
+                <input
+                    value={networkSettings.rendezvous ?? ""}
+                    onChange={(e) => changeNetworkSettings({ rendezvous: e.target.value || null })}
+                    placeholder="Node id"
+                />
+            </label>
+            {networkSettingsError && <span>Could not save the network settings, check the relay URL and the rendezvous server</span>}
+            <span>Network settings apply once Flap restarts</span>
+        </>}
     </Modal>;
-}
\ No newline at end of file
+}
@@ -1,48 +1,379 @@
-use clap::{Parser, Subcommand};
-use flap_lib::p2p::{receiver::P2pReceiver, sender::P2pSender};
+use std::{io::Write, net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use flap_lib::{
+    code::{
+        ShortCode,
+        server::{NodeId, RendezvousServer},
+    },
+    fs::save::ConflictPolicy,
+    p2p::{
+        endpoint::{DiscoveryMode, P2pEndpointConfig, RelayConfig, RelayUrl},
+        limits::SizeLimits,
+        policy::TicketPolicy,
+        receiver::P2pReceiver,
+        scheduler::{DEFAULT_MAX_STREAMS, SchedulerConfig},
+        selection::FileSelection,
+        sender::P2pSender,
+    },
+};
+use tokio::sync::{mpsc, oneshot};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
+    #[command(flatten)]
+    network: NetworkArgs,
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Args)]
+struct NetworkArgs {
+    /// Do not use the n0 discovery service to find the other device
+    #[arg(long, global = true)]
+    no_discovery: bool,
+    /// Find the other device on the local network instead, through multicast DNS
+    #[arg(long, global = true, conflicts_with = "no_discovery")]
+    local_discovery: bool,
+    /// Use this (self-hosted) relay server instead of the default ones
+    #[arg(long, global = true, conflicts_with = "no_relay")]
+    relay: Option<RelayUrl>,
+    /// Only connect directly to the other device, never through a relay
+    #[arg(long, global = true)]
+    no_relay: bool,
+    /// Address and port to listen on, e.g. 0.0.0.0:4242
+    #[arg(long, global = true)]
+    bind: Option<SocketAddr>,
+}
+
+impl NetworkArgs {
+    fn endpoint_config(&self) -> P2pEndpointConfig {
+        P2pEndpointConfig {
+            discovery: match (self.no_discovery, self.local_discovery) {
+                (true, _) => DiscoveryMode::Disabled,
+                (false, true) => DiscoveryMode::LocalNetwork,
+                (false, false) => DiscoveryMode::N0,
+            },
+            relay: match (&self.relay, self.no_relay) {
+                (_, true) => RelayConfig::Disabled,
+                (Some(relay_url), false) => RelayConfig::Custom(relay_url.clone()),
+                (None, false) => RelayConfig::Default,
+            },
+            bind_addr: self.bind,
+        }
+    }
+}
+
+/// What to do with a received file whose name is taken by another file
+#[derive(Clone, Copy, ValueEnum)]
+enum OnConflict {
+    /// Replace the other file
+    Overwrite,
+    /// Save it as "name (1).ext"
+    Rename,
+    /// Keep the other file and do not receive this one
+    Skip,
+    /// Ask every time
+    Ask,
+}
+
 #[derive(Subcommand)]
 enum Commands {
-    /// Adds files to myapp
+    /// Sends a file or a directory
     Send {
         file_path: String,
+        /// How many devices can receive with the same ticket
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        max_uses: u32,
+        /// How long the ticket can be used for, in seconds
+        #[arg(long)]
+        ttl: Option<u64>,
+        /// Show a short code, such as 7-crow-feather, instead of the ticket
+        #[arg(long, requires = "rendezvous")]
+        code: bool,
+        /// The node id of the rendezvous server the short code goes through
+        #[arg(long)]
+        rendezvous: Option<NodeId>,
     },
     Receive {
+        /// The ticket, or the short code with --code
         ticket_string: String,
+        /// Receive with a short code instead of a ticket
+        #[arg(long, requires = "rendezvous")]
+        code: bool,
+        /// The node id of the rendezvous server the short code goes through
+        #[arg(long)]
+        rendezvous: Option<NodeId>,
+        /// Accept every file without asking
+        #[arg(short, long)]
+        yes: bool,
+        /// What to do with a received file whose name is taken by another file
+        #[arg(long, value_enum, default_value_t = OnConflict::Rename)]
+        on_conflict: OnConflict,
+        /// Where to save received files. Defaults to "Flap Downloads", in the download directory
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Refuse files larger than this many bytes
+        #[arg(long)]
+        max_file_size: Option<u64>,
+        /// Refuse files once this many bytes were received in total
+        #[arg(long)]
+        max_session_size: Option<u64>,
+        /// Only receive files matching this pattern, such as '*.pdf' or 'album/*.jpg'. Can be repeated
+        #[arg(long = "only", value_name = "PATTERN")]
+        only: Vec<String>,
+        /// List the files the sender is about to send, without receiving them
+        #[arg(long)]
+        list: bool,
+        /// How many files to receive at once
+        #[arg(long, default_value_t = DEFAULT_MAX_STREAMS)]
+        max_streams: usize,
+    },
+    /// Lists the senders on the local network. Senders must use --local-discovery too
+    Nearby {
+        /// How long to listen for senders, in seconds
+        #[arg(long, default_value_t = 5)]
+        wait: u64,
     },
+    /// Runs a rendezvous server, through which short codes are exchanged
+    Rendezvous,
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
+    let endpoint_config = cli.network.endpoint_config();
 
     // You can check for the existence of subcommands, and if found use their
     // matches just as you would the top level cmd
     match cli.command {
-        Commands::Send { file_path } => {
-            let sender = P2pSender::new().await.unwrap();
+        Commands::Send {
+            file_path,
+            max_uses,
+            ttl,
+            code,
+            rendezvous,
+        } => {
+            let policy = TicketPolicy::new(max_uses, ttl.map(Duration::from_secs)).unwrap();
+            let sender = P2pSender::new(&endpoint_config, policy, SchedulerConfig::default())
+                .await
+                .unwrap();
 
             println!("start sending file...");
             sender.send(file_path).await.unwrap();
-            let ticket = sender.ticket;
 
-            println!("File is ready. The ticket is: {}", ticket.convert());
+            match rendezvous.filter(|_| code) {
+                Some(server) => {
+                    let rendezvous = sender.rendezvous(server);
+                    let code = ShortCode::allocate(&rendezvous).await.unwrap();
+                    println!("File is ready. The code is: {code}");
+
+                    if let Err(err) = sender.send_code(&code, &rendezvous).await {
+                        eprintln!("Could not share the ticket: {err}");
+                        std::process::exit(1);
+                    }
+                }
+                None => {
+                    let ticket = sender.ticket().await;
+                    println!("File is ready. The ticket is: {}", ticket.convert());
+                }
+            }
 
             tokio::signal::ctrl_c().await.unwrap();
         }
-        Commands::Receive { ticket_string } => {
-            let receiver = P2pReceiver::new().await.unwrap();
-            let ticket = ticket_string.parse().unwrap();
-            let _retrieved_bytes = receiver.retrieve(ticket).await.unwrap();
+        Commands::Receive {
+            ticket_string,
+            code,
+            rendezvous,
+            yes,
+            on_conflict,
+            output,
+            max_file_size,
+            max_session_size,
+            only,
+            list,
+            max_streams,
+        } => {
+            let mut receiver = P2pReceiver::new(&endpoint_config).await.unwrap();
+            let ticket = match rendezvous.filter(|_| code) {
+                Some(server) => {
+                    let code: ShortCode = ticket_string.parse().unwrap();
+                    match code.receive_ticket(&receiver.rendezvous(server)).await {
+                        Ok(ticket) => ticket,
+                        Err(err) => {
+                            eprintln!("Could not get the ticket: {err}");
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                None => ticket_string.parse().unwrap(),
+            };
+
+            if list {
+                match receiver.list(ticket).await {
+                    Ok(manifest) => {
+                        for entry in &manifest.entries {
+                            println!(
+                                "{}\t{} bytes",
+                                entry.relative_path.display(),
+                                entry.file_size
+                            );
+                        }
+                        println!(
+                            "{} file(s), {} bytes in total",
+                            manifest.file_count(),
+                            manifest.total_size()
+                        );
+                    }
+                    Err(err) => {
+                        eprintln!("Could not list files: {err}");
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+
+            receiver.set_max_streams(max_streams);
+            if !only.is_empty() {
+                receiver.set_selection(FileSelection::Only(only));
+            }
+            if let Some(output) = output {
+                receiver.set_download_dir(output);
+            }
+            receiver.set_size_limits(SizeLimits {
+                max_file_size,
+                max_session_size,
+            });
+            let prompter = Prompter::spawn();
+            match on_conflict {
+                OnConflict::Overwrite => receiver.set_conflict_policy(ConflictPolicy::Overwrite),
+                OnConflict::Rename => receiver.set_conflict_policy(ConflictPolicy::Rename),
+                OnConflict::Skip => receiver.set_conflict_policy(ConflictPolicy::Skip),
+                OnConflict::Ask => {
+                    let mut conflicts = receiver.ask_on_conflict();
+                    let prompter = prompter.clone();
+                    tokio::spawn(async move {
+                        while let Some(conflict) = conflicts.recv().await {
+                            let question = format!(
+                                "{} already exists. Overwrite it, rename the new file, or skip it? [o/r/S] ",
+                                conflict.path.display()
+                            );
+                            let answer = prompter.ask(question).await;
+
+                            conflict.resolve(match answer.as_str() {
+                                "o" | "overwrite" => ConflictPolicy::Overwrite,
+                                "r" | "rename" => ConflictPolicy::Rename,
+                                _ => ConflictPolicy::Skip,
+                            });
+                        }
+                    });
+                }
+            }
+            if !yes {
+                let mut offers = receiver.ask_before_receiving();
+                tokio::spawn(async move {
+                    while let Some(offer) = offers.recv().await {
+                        let question = format!(
+                            "Receive {} ({} file(s), {} bytes)? [y/N] ",
+                            offer.metadata.file_name,
+                            offer.file_count(),
+                            offer.metadata.file_size
+                        );
+                        let accepted = prompter.confirm(question).await;
+
+                        if accepted {
+                            offer.accept();
+                        } else {
+                            offer.decline();
+                        }
+                    }
+                });
+            }
+
+            if let Err(err) = receiver.retrieve(ticket).await {
+                eprintln!("Could not receive: {err}");
+                std::process::exit(1);
+            }
             println!("Rcv complete");
         }
+        Commands::Nearby { wait } => {
+            let endpoint_config = P2pEndpointConfig {
+                discovery: DiscoveryMode::LocalNetwork,
+                ..endpoint_config
+            };
+            let receiver = P2pReceiver::new(&endpoint_config).await.unwrap();
+            let senders = receiver.nearby_senders(Duration::from_secs(wait)).await;
+
+            if senders.is_empty() {
+                println!("No sender found on the local network");
+            }
+            for sender in senders {
+                println!("{}", sender.node_id);
+            }
+        }
+        Commands::Rendezvous => {
+            let server = RendezvousServer::spawn(&endpoint_config).await.unwrap();
+            println!(
+                "Rendezvous server is running. Its node id is: {}",
+                server.endpoint().node_id()
+            );
+
+            tokio::signal::ctrl_c().await.unwrap();
+        }
+    }
+}
+
+/// Asks the questions of every task on the terminal one at a time, so that
+/// each answer read goes to the question it was typed for.
+#[derive(Clone)]
+struct Prompter(mpsc::UnboundedSender<(String, oneshot::Sender<String>)>);
+
+impl Prompter {
+    /// Spawns the only task reading the terminal.
+    fn spawn() -> Self {
+        let (questions_tx, mut questions_rx) =
+            mpsc::unbounded_channel::<(String, oneshot::Sender<String>)>();
+        tokio::spawn(async move {
+            while let Some((question, answer_tx)) = questions_rx.recv().await {
+                let answer = tokio::task::spawn_blocking(move || prompt(&question))
+                    .await
+                    .unwrap_or_default();
+                let _ = answer_tx.send(answer);
+            }
+        });
+
+        Self(questions_tx)
     }
+
+    /// Waits for the questions asked before, then for the lowercase answer to this one.
+    /// The answer is empty if the terminal cannot be read.
+    async fn ask(&self, question: String) -> String {
+        let (answer_tx, answer_rx) = oneshot::channel();
+        if self.0.send((question, answer_tx)).is_err() {
+            return String::new();
+        }
+
+        answer_rx.await.unwrap_or_default()
+    }
+
+    /// Asks a yes/no question. Anything but yes is a no.
+    async fn confirm(&self, question: String) -> bool {
+        matches!(self.ask(question).await.as_str(), "y" | "yes")
+    }
+}
+
+/// Asks a question on the terminal, returning the lowercase answer.
+/// The answer is empty if the terminal cannot be read.
+fn prompt(question: &str) -> String {
+    print!("{question}");
+    let _ = std::io::stdout().flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return String::new();
+    }
+
+    answer.trim().to_lowercase()
 }
@@ -0,0 +1,110 @@
+//! Sends a file between two processes on the same machine, which find each
+//! other through multicast DNS alone.
+//!
+//! Needs multicast on one of the network interfaces, which CI may not have,
+//! so it only runs when asked to:
+//! `FLAP_TEST_LOCAL_NETWORK=1 cargo test -p flap-cli --test local_network`
+
+use std::{
+    io::{BufRead, BufReader},
+    path::Path,
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+
+use flap_lib::{
+    p2p::{
+        endpoint::{DiscoveryMode, P2pEndpointConfig, RelayConfig},
+        receiver::P2pReceiver,
+    },
+    ticket::Ticket,
+};
+
+const ENABLE_VAR: &str = "FLAP_TEST_LOCAL_NETWORK";
+
+/// How long the file has to arrive once the sender is found.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The sending process, killed once the test is over, even if it failed.
+struct SenderProcess(Child);
+
+impl SenderProcess {
+    /// Starts sending `file_path`, and waits for the ticket it prints.
+    fn spawn(file_path: &Path) -> (Self, Ticket) {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_flap-cli"))
+            .args(["--local-discovery", "--no-relay", "send"])
+            .arg(file_path)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("the CLI starts");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let sender = Self(child);
+
+        let ticket = BufReader::new(stdout)
+            .lines()
+            .map_while(Result::ok)
+            .find_map(|line| {
+                line.split_once("The ticket is: ")
+                    .map(|(_, ticket)| ticket.trim().parse().expect("the ticket is valid"))
+            })
+            .expect("the sender prints its ticket");
+
+        (sender, ticket)
+    }
+}
+
+impl Drop for SenderProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn finds_sender_on_local_network() {
+    if std::env::var_os(ENABLE_VAR).is_none() {
+        eprintln!("Skipped, set {ENABLE_VAR}=1 to run it");
+        return;
+    }
+
+    let send_dir = tempfile::tempdir().unwrap();
+    let download_dir = tempfile::tempdir().unwrap();
+    let content: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let file_path = send_dir.path().join("nearby");
+    std::fs::write(&file_path, &content).unwrap();
+
+    let (_sender, mut ticket) = SenderProcess::spawn(&file_path);
+
+    let config = P2pEndpointConfig {
+        discovery: DiscoveryMode::LocalNetwork,
+        relay: RelayConfig::Disabled,
+        bind_addr: None,
+    };
+    let mut receiver = P2pReceiver::new(&config).await.unwrap();
+    receiver.set_download_dir(download_dir.path().to_path_buf());
+
+    let nearby_senders = receiver.nearby_senders(Duration::from_secs(10)).await;
+    assert!(
+        nearby_senders
+            .iter()
+            .any(|node_addr| node_addr.node_id == ticket.node_id)
+    );
+
+    // The addresses of the sender are found through discovery alone
+    ticket.relay_url = None;
+    ticket.direct_addresses.clear();
+    let task = tokio::spawn(async move { receiver.retrieve(ticket).await });
+
+    // Files only get their name once complete
+    let downloaded = download_dir.path().join("nearby");
+    tokio::time::timeout(TRANSFER_TIMEOUT, async {
+        while !downloaded.exists() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("the file is received in time");
+    task.abort();
+
+    assert_eq!(std::fs::read(downloaded).unwrap(), content);
+}
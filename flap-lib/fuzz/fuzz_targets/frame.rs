@@ -0,0 +1,13 @@
+#![no_main]
+
+use bytes::Bytes;
+use flap_lib::p2p::frame::Frame;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(frame) = Frame::read_from_frame(Bytes::copy_from_slice(data)) {
+        // Anything we accept must encode back to the same frame
+        let roundtrip = Frame::read_from_frame(frame.to_bytes().into()).unwrap();
+        assert_eq!(roundtrip, frame);
+    }
+});
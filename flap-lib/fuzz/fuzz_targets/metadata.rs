@@ -0,0 +1,11 @@
+#![no_main]
+
+use bytes::Bytes;
+use flap_lib::fs::metadata::FlapFileMetadata;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(metadata) = FlapFileMetadata::from_bytes(Bytes::copy_from_slice(data)) {
+        assert_eq!(metadata.to_bytes(), data);
+    }
+});
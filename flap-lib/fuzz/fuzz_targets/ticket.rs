@@ -0,0 +1,8 @@
+#![no_main]
+
+use flap_lib::ticket::Ticket;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let _ = data.parse::<Ticket>();
+});
@@ -0,0 +1,306 @@
+//! Short codes, such as `7-crow-feather`, that can be read over the phone
+//! instead of a full [`Ticket`].
+//!
+//! The sender allocates a nameplate on a [`Rendezvous`] and shows the code.
+//! Both sides then run SPAKE2 with the code as password, and derive the
+//! [`MasterKey`] of the ticket from the SPAKE2 key. The sender only hands its
+//! address, sealed with the SPAKE2 key, to the receiver. The master key then
+//! protects the Noise handshake as usual.
+//!
+//! Someone guessing the code gets a single online attempt: the nameplate is
+//! released as soon as the receiver fails to prove it knows the code.
+
+pub mod rendezvous;
+pub mod server;
+mod words;
+
+use std::{fmt::Display, future::Future, str::FromStr};
+
+use bytes::Bytes;
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit,
+    aead::{Aead, Payload},
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+use crate::{
+    code::{
+        rendezvous::{Phase, Rendezvous, Side},
+        words::WORDS,
+    },
+    crypto::{master_key::MasterKey, random_array},
+    error::{Error, Result},
+    ticket::Ticket,
+};
+
+/// Each word carries one byte of the password.
+const CODE_WORDS: usize = 2;
+
+const SENDER_IDENTITY: &[u8] = b"flap_code_sender";
+const RECEIVER_IDENTITY: &[u8] = b"flap_code_receiver";
+
+/// A nameplate followed by words, e.g. `7-crow-feather`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShortCode {
+    pub nameplate: u16,
+    words: [&'static str; CODE_WORDS],
+}
+
+impl ShortCode {
+    pub fn generate(nameplate: u16) -> Self {
+        let words = random_array::<CODE_WORDS>().map(|byte| WORDS[byte as usize]);
+
+        Self { nameplate, words }
+    }
+
+    /// A new code, on a nameplate allocated on `rendezvous`.
+    pub async fn allocate(rendezvous: &impl Rendezvous) -> Result<Self> {
+        Ok(Self::generate(rendezvous.allocate().await?))
+    }
+
+    /// Keys derived from the SPAKE2 key, one per purpose.
+    fn derive_key(pake_key: &[u8], info: &[u8]) -> ChaCha20Poly1305 {
+        let hk = Hkdf::<Sha256>::new(None, pake_key);
+        let mut key = [0u8; 32];
+        hk.expand(info, &mut key).expect("valid length output");
+
+        ChaCha20Poly1305::new(&key.into())
+    }
+
+    /// The master key of the ticket, which never goes through the rendezvous.
+    fn derive_master_key(pake_key: &[u8]) -> MasterKey {
+        let hk = Hkdf::<Sha256>::new(None, pake_key);
+        let mut key = [0u8; 16];
+        hk.expand(b"flap_code_master_key", &mut key)
+            .expect("valid length output");
+
+        MasterKey(key)
+    }
+
+    /// Runs SPAKE2 with the other side through `rendezvous`, and returns the shared key.
+    ///
+    /// If the other side used another code, both sides end up with different keys.
+    async fn exchange_pake(&self, rendezvous: &impl Rendezvous, side: Side) -> Result<Vec<u8>> {
+        let password = Password::new(self.to_string());
+        let sender = Identity::new(SENDER_IDENTITY);
+        let receiver = Identity::new(RECEIVER_IDENTITY);
+
+        let (spake, message) = match side {
+            Side::Sender => Spake2::<Ed25519Group>::start_a(&password, &sender, &receiver),
+            Side::Receiver => Spake2::<Ed25519Group>::start_b(&password, &sender, &receiver),
+        };
+
+        rendezvous
+            .post(self.nameplate, side, Phase::Pake, message.into())
+            .await?;
+        let their_message = rendezvous.fetch(self.nameplate, side, Phase::Pake).await?;
+
+        Ok(spake.finish(&their_message)?)
+    }
+
+    /// Gives a ticket to the receiver that enters this code.
+    ///
+    /// Once the receiver proved it knows the code, `issue_ticket` makes the
+    /// ticket out of the master key both sides derived, and the receiver is
+    /// told where to find the sender. Fails with [`Error::WrongCode`] if the
+    /// receiver entered another code. The code can never be used again in both cases.
+    pub async fn send_ticket<F>(
+        &self,
+        rendezvous: &impl Rendezvous,
+        issue_ticket: impl FnOnce(MasterKey) -> F,
+    ) -> Result<Ticket>
+    where
+        F: Future<Output = Ticket>,
+    {
+        let result = self.share_ticket(rendezvous, issue_ticket).await;
+        rendezvous.release(self.nameplate).await?;
+
+        result
+    }
+
+    async fn share_ticket<F>(
+        &self,
+        rendezvous: &impl Rendezvous,
+        issue_ticket: impl FnOnce(MasterKey) -> F,
+    ) -> Result<Ticket>
+    where
+        F: Future<Output = Ticket>,
+    {
+        let pake_key = self.exchange_pake(rendezvous, Side::Sender).await?;
+
+        let confirmation = rendezvous
+            .fetch(self.nameplate, Side::Sender, Phase::Confirm)
+            .await?;
+        Self::derive_key(&pake_key, b"flap_code_confirm")
+            .decrypt(&Default::default(), confirmation.as_ref())
+            .map_err(|_| Error::WrongCode)?;
+
+        // Issued before the receiver can connect with it
+        let ticket = issue_ticket(Self::derive_master_key(&pake_key)).await;
+
+        // Each key seals a single message, so the nonce is never reused
+        let sealed_node_addr = Self::derive_key(&pake_key, b"flap_code_node_addr").encrypt(
+            &Default::default(),
+            Payload::from(ticket.encode_node_addr().as_ref()),
+        )?;
+        rendezvous
+            .post(
+                self.nameplate,
+                Side::Sender,
+                Phase::NodeAddr,
+                sealed_node_addr.into(),
+            )
+            .await?;
+
+        Ok(ticket)
+    }
+
+    /// Gets the ticket of the sender that shows this code.
+    pub async fn receive_ticket(&self, rendezvous: &impl Rendezvous) -> Result<Ticket> {
+        let pake_key = self.exchange_pake(rendezvous, Side::Receiver).await?;
+
+        let confirmation = Self::derive_key(&pake_key, b"flap_code_confirm")
+            .encrypt(&Default::default(), &[][..])?;
+        rendezvous
+            .post(
+                self.nameplate,
+                Side::Receiver,
+                Phase::Confirm,
+                confirmation.into(),
+            )
+            .await?;
+
+        let sealed_node_addr: Bytes = rendezvous
+            .fetch(self.nameplate, Side::Receiver, Phase::NodeAddr)
+            .await
+            // The sender gives up on the code when our confirmation is wrong
+            .map_err(|err| match err {
+                Error::UnknownCode => Error::WrongCode,
+                err => err,
+            })?;
+        let node_addr = Self::derive_key(&pake_key, b"flap_code_node_addr")
+            .decrypt(&Default::default(), sealed_node_addr.as_ref())
+            .map_err(|_| Error::WrongCode)?;
+
+        Ticket::decode_node_addr(node_addr.into(), Self::derive_master_key(&pake_key))
+    }
+}
+
+impl Display for ShortCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.nameplate)?;
+        for word in self.words {
+            write!(f, "-{word}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for ShortCode {
+    type Err = Error;
+
+    /// Accepts codes typed with different case or surrounding spaces.
+    fn from_str(s: &str) -> Result<Self> {
+        let code = s.trim().to_lowercase();
+        let mut parts = code.split('-');
+
+        let nameplate = parts
+            .next()
+            .and_then(|nameplate| nameplate.parse().ok())
+            .ok_or(Error::CodeParseError)?;
+
+        let mut words = [""; CODE_WORDS];
+        for word in &mut words {
+            let part = parts.next().ok_or(Error::CodeParseError)?;
+            let index = WORDS
+                .binary_search(&part)
+                .map_err(|_| Error::CodeParseError)?;
+            *word = WORDS[index];
+        }
+
+        if parts.next().is_some() {
+            return Err(Error::CodeParseError);
+        }
+
+        Ok(Self { nameplate, words })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::*;
+    use crate::code::rendezvous::LocalRendezvous;
+
+    fn ticket(master_key: MasterKey) -> Ticket {
+        let node_id = SecretKey::from_bytes(&random_array()).public();
+        Ticket::make(node_id, master_key)
+    }
+
+    #[test]
+    fn code_roundtrip() {
+        let code = ShortCode::generate(7);
+        assert_eq!(code.to_string().parse::<ShortCode>().unwrap(), code);
+        assert_eq!(
+            " 7-Crow-FEATHER ".parse::<ShortCode>().unwrap().to_string(),
+            "7-crow-feather"
+        );
+
+        for invalid in [
+            "",
+            "7",
+            "7-crow",
+            "crow-feather",
+            "7-crow-feather-owl",
+            "7-crow-unicorn",
+        ] {
+            assert!(invalid.parse::<ShortCode>().is_err(), "{invalid}");
+        }
+
+        assert!(WORDS.is_sorted());
+    }
+
+    #[tokio::test]
+    async fn exchanges_ticket() {
+        let rendezvous = LocalRendezvous::new();
+
+        let code = ShortCode::allocate(&rendezvous).await.unwrap();
+        let typed_code: ShortCode = code.to_string().parse().unwrap();
+
+        let (sent, received) = tokio::join!(
+            code.send_ticket(&rendezvous, async |master_key| ticket(master_key)),
+            typed_code.receive_ticket(&rendezvous)
+        );
+        let ticket = sent.unwrap();
+        let received = received.unwrap();
+
+        assert_eq!(received.node_id, ticket.node_id);
+        assert_eq!(
+            received.master_key().encode_to_string(),
+            ticket.master_key().encode_to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_code_burns_the_code() {
+        let rendezvous = LocalRendezvous::new();
+
+        let code = ShortCode::allocate(&rendezvous).await.unwrap();
+        let mut guess = code.clone();
+        guess.words[1] = WORDS.iter().find(|word| **word != code.words[1]).unwrap();
+
+        let (sent, received) = tokio::join!(
+            code.send_ticket(&rendezvous, async |master_key| ticket(master_key)),
+            guess.receive_ticket(&rendezvous)
+        );
+        assert!(matches!(sent, Err(Error::WrongCode)));
+        assert!(matches!(received, Err(Error::WrongCode)));
+
+        // Even the right code no longer works
+        assert!(code.receive_ticket(&rendezvous).await.is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Where both devices meet to run the code exchange.
+//!
+//! Each code is tied to a nameplate, a small number naming a mailbox on the
+//! rendezvous. Both sides post their messages in that mailbox, and fetch the
+//! messages of the other side.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::pin,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Sender,
+    Receiver,
+}
+
+impl Side {
+    pub fn other(self) -> Self {
+        match self {
+            Side::Sender => Side::Receiver,
+            Side::Receiver => Side::Sender,
+        }
+    }
+}
+
+/// The steps of the code exchange, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// The SPAKE2 messages, posted by both sides.
+    Pake,
+    /// Proof from the receiver that it knows the code.
+    Confirm,
+    /// The sealed address of the sender, posted by the sender.
+    NodeAddr,
+}
+
+/// A service both devices can reach before knowing anything about each other.
+pub trait Rendezvous {
+    /// Reserves a nameplate that is not in use.
+    fn allocate(&self) -> impl Future<Output = Result<u16>> + Send;
+
+    /// Posts the message of `side` for `phase`.
+    ///
+    /// A side may post only once per phase. This is what limits anyone
+    /// guessing a code to a single attempt.
+    fn post(
+        &self,
+        nameplate: u16,
+        side: Side,
+        phase: Phase,
+        message: Bytes,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Waits for the message the other side posted for `phase`.
+    fn fetch(
+        &self,
+        nameplate: u16,
+        side: Side,
+        phase: Phase,
+    ) -> impl Future<Output = Result<Bytes>> + Send;
+
+    /// Frees the nameplate. Messages can no longer be posted to it.
+    fn release(&self, nameplate: u16) -> impl Future<Output = Result<()>> + Send;
+}
+
+#[derive(Debug, Default)]
+struct Mailbox {
+    messages: HashMap<(Side, Phase), Bytes>,
+    released: bool,
+}
+
+/// A rendezvous living in memory, for devices running in the same process.
+///
+/// Stands in for a rendezvous server in tests.
+#[derive(Debug, Clone, Default)]
+pub struct LocalRendezvous {
+    mailboxes: Arc<Mutex<HashMap<u16, Mailbox>>>,
+    posted: Arc<Notify>,
+}
+
+impl LocalRendezvous {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Rendezvous for LocalRendezvous {
+    async fn allocate(&self) -> Result<u16> {
+        let mut mailboxes = self.mailboxes.lock().expect("lock is not poisoned");
+        // Small numbers make short codes. Released nameplates are used again.
+        let nameplate = (1..=u16::MAX)
+            .find(|nameplate| {
+                mailboxes
+                    .get(nameplate)
+                    .is_none_or(|mailbox| mailbox.released)
+            })
+            .ok_or(Error::CodeAlreadyUsed)?;
+        mailboxes.insert(nameplate, Mailbox::default());
+
+        Ok(nameplate)
+    }
+
+    async fn post(&self, nameplate: u16, side: Side, phase: Phase, message: Bytes) -> Result<()> {
+        {
+            let mut mailboxes = self.mailboxes.lock().expect("lock is not poisoned");
+            let mailbox = mailboxes
+                .get_mut(&nameplate)
+                .filter(|mailbox| !mailbox.released)
+                .ok_or(Error::UnknownCode)?;
+
+            if mailbox.messages.contains_key(&(side, phase)) {
+                return Err(Error::CodeAlreadyUsed);
+            }
+            mailbox.messages.insert((side, phase), message);
+        }
+
+        self.posted.notify_waiters();
+
+        Ok(())
+    }
+
+    async fn fetch(&self, nameplate: u16, side: Side, phase: Phase) -> Result<Bytes> {
+        loop {
+            // Registered before looking, so that no post is missed in between
+            let mut posted = pin!(self.posted.notified());
+            posted.as_mut().enable();
+
+            {
+                let mailboxes = self.mailboxes.lock().expect("lock is not poisoned");
+                let mailbox = mailboxes.get(&nameplate).ok_or(Error::UnknownCode)?;
+
+                if let Some(message) = mailbox.messages.get(&(side.other(), phase)) {
+                    return Ok(message.clone());
+                }
+                if mailbox.released {
+                    return Err(Error::UnknownCode);
+                }
+            }
+
+            posted.await;
+        }
+    }
+
+    async fn release(&self, nameplate: u16) -> Result<()> {
+        if let Some(mailbox) = self
+            .mailboxes
+            .lock()
+            .expect("lock is not poisoned")
+            .get_mut(&nameplate)
+        {
+            mailbox.released = true;
+        }
+
+        self.posted.notify_waiters();
+
+        Ok(())
+    }
+}
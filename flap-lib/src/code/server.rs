@@ -0,0 +1,425 @@
+//! A [`Rendezvous`] reached over the network, for devices that know nothing
+//! about each other yet.
+//!
+//! The server is an iroh node like any other, which both devices reach through
+//! its node id. Each request is sent on a stream of its own, and answered on it.
+//! The nameplates a device allocated are released once it disconnects.
+//!
+//! Each side of a nameplate belongs to the first device that posts or fetches
+//! as that side, so that nobody else can read or write its messages.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+pub use iroh::NodeId;
+use iroh::{
+    NodeAddr,
+    endpoint::{Connection, RecvStream, SendStream},
+    protocol::{AcceptError, Router},
+};
+use tokio::task::JoinSet;
+
+use crate::{
+    code::rendezvous::{LocalRendezvous, Phase, Rendezvous, Side},
+    error::{Error, ProtocolError, Result},
+    p2p::endpoint::{P2pEndpoint, P2pEndpointConfig},
+};
+
+pub const RENDEZVOUS_ALPN: &[u8] = b"flap-rendezvous";
+
+/// Messages are small, such as SPAKE2 messages and sealed addresses.
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// How many nameplates a single device may hold at once.
+pub(crate) const MAX_NAMEPLATES_PER_CONNECTION: usize = 16;
+
+/// How long a fetch waits for the other side, which is as long as someone
+/// is given to type the code.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNKNOWN_CODE: u8 = 1;
+const STATUS_CODE_ALREADY_USED: u8 = 2;
+const STATUS_TOO_MANY_CODES: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Request {
+    Allocate,
+    Post(u16, Side, Phase, Bytes),
+    Fetch(u16, Side, Phase),
+    Release(u16),
+}
+
+impl Request {
+    const HEADER_LENGTH: usize = size_of::<u8>() * 3 + size_of::<u16>() * 2;
+
+    /// [(u8)(u16)(u8)(u8)(u16)(message)]
+    /// (u8) is the kind of request
+    /// (u16) is the nameplate, 0 when allocating
+    /// (u8) is the side, and (u8) the phase, 0 when unused
+    /// (u16) is the length of the message that follows, only posted messages have one
+    async fn write(&self, send: &mut SendStream) -> Result<()> {
+        let (kind, nameplate, side, phase, message) = match self {
+            Request::Allocate => (0, 0, None, None, Bytes::new()),
+            Request::Post(nameplate, side, phase, message) => {
+                (1, *nameplate, Some(*side), Some(*phase), message.clone())
+            }
+            Request::Fetch(nameplate, side, phase) => {
+                (2, *nameplate, Some(*side), Some(*phase), Bytes::new())
+            }
+            Request::Release(nameplate) => (3, *nameplate, None, None, Bytes::new()),
+        };
+
+        let mut bytes = BytesMut::with_capacity(Self::HEADER_LENGTH + message.len());
+        bytes.put_u8(kind);
+        bytes.put_u16(nameplate);
+        bytes.put_u8(side.map_or(0, encode_side));
+        bytes.put_u8(phase.map_or(0, encode_phase));
+        bytes.put_u16(message.len() as u16);
+        bytes.put(message);
+
+        send.write_all(&bytes).await?;
+
+        Ok(())
+    }
+
+    async fn read(recv: &mut RecvStream) -> Result<Self> {
+        let mut header = [0u8; Self::HEADER_LENGTH];
+        recv.read_exact(&mut header).await?;
+        let mut header = &header[..];
+
+        let kind = header.get_u8();
+        let nameplate = header.get_u16();
+        let side = header.get_u8();
+        let phase = header.get_u8();
+        let message = read_message(recv, header.get_u16()).await?;
+
+        Ok(match kind {
+            0 => Request::Allocate,
+            1 => Request::Post(nameplate, decode_side(side)?, decode_phase(phase)?, message),
+            2 => Request::Fetch(nameplate, decode_side(side)?, decode_phase(phase)?),
+            3 => Request::Release(nameplate),
+            _ => return Err(ProtocolError::MalformedRendezvousMessage.into()),
+        })
+    }
+}
+
+fn encode_side(side: Side) -> u8 {
+    match side {
+        Side::Sender => 1,
+        Side::Receiver => 2,
+    }
+}
+
+fn decode_side(side: u8) -> Result<Side> {
+    match side {
+        1 => Ok(Side::Sender),
+        2 => Ok(Side::Receiver),
+        _ => Err(ProtocolError::MalformedRendezvousMessage.into()),
+    }
+}
+
+fn encode_phase(phase: Phase) -> u8 {
+    match phase {
+        Phase::Pake => 1,
+        Phase::Confirm => 2,
+        Phase::NodeAddr => 3,
+    }
+}
+
+fn decode_phase(phase: u8) -> Result<Phase> {
+    match phase {
+        1 => Ok(Phase::Pake),
+        2 => Ok(Phase::Confirm),
+        3 => Ok(Phase::NodeAddr),
+        _ => Err(ProtocolError::MalformedRendezvousMessage.into()),
+    }
+}
+
+async fn read_message(recv: &mut RecvStream, len: u16) -> Result<Bytes> {
+    let len = len as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(ProtocolError::MalformedRendezvousMessage.into());
+    }
+
+    let mut message = vec![0u8; len];
+    recv.read_exact(&mut message).await?;
+
+    Ok(message.into())
+}
+
+/// [(u8)(u16)(message)]
+/// (u8) is the status, [`STATUS_OK`] or the error
+/// (u16) is the length of the message that follows
+async fn write_response(send: &mut SendStream, response: Result<Bytes>) -> Result<()> {
+    let (status, message) = match response {
+        Ok(message) => (STATUS_OK, message),
+        Err(Error::UnknownCode) => (STATUS_UNKNOWN_CODE, Bytes::new()),
+        Err(Error::CodeAlreadyUsed) => (STATUS_CODE_ALREADY_USED, Bytes::new()),
+        Err(Error::TooManyCodes) => (STATUS_TOO_MANY_CODES, Bytes::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut bytes = BytesMut::with_capacity(size_of::<u8>() + size_of::<u16>() + message.len());
+    bytes.put_u8(status);
+    bytes.put_u16(message.len() as u16);
+    bytes.put(message);
+    send.write_all(&bytes).await?;
+
+    Ok(())
+}
+
+async fn read_response(recv: &mut RecvStream) -> Result<Bytes> {
+    let mut header = [0u8; size_of::<u8>() + size_of::<u16>()];
+    recv.read_exact(&mut header).await?;
+    let mut header = &header[..];
+
+    let status = header.get_u8();
+    let message = read_message(recv, header.get_u16()).await?;
+
+    match status {
+        STATUS_OK => Ok(message),
+        STATUS_UNKNOWN_CODE => Err(Error::UnknownCode),
+        STATUS_CODE_ALREADY_USED => Err(Error::CodeAlreadyUsed),
+        STATUS_TOO_MANY_CODES => Err(Error::TooManyCodes),
+        _ => Err(ProtocolError::MalformedRendezvousMessage.into()),
+    }
+}
+
+/// Serves mailboxes to the devices that connect to it.
+#[derive(Debug, Clone, Default)]
+pub struct RendezvousServer {
+    mailboxes: LocalRendezvous,
+    /// The connection each side of a nameplate belongs to, by its stable id.
+    sides: Arc<Mutex<HashMap<(u16, Side), usize>>>,
+}
+
+impl RendezvousServer {
+    /// Starts serving on a new endpoint, until the returned router is shut down.
+    pub async fn spawn(config: &P2pEndpointConfig) -> Result<Router> {
+        let p2p_endpoint = P2pEndpoint::start(config).await?;
+
+        Ok(Router::builder(p2p_endpoint.deref().clone())
+            .accept(RENDEZVOUS_ALPN, Self::default())
+            .spawn())
+    }
+
+    /// Answers a single request of the connection of `connection_id`.
+    /// `allocated` holds the nameplates allocated by the device.
+    async fn answer(
+        &self,
+        mut send: SendStream,
+        mut recv: RecvStream,
+        connection_id: usize,
+        allocated: &Mutex<HashSet<u16>>,
+    ) -> Result<()> {
+        let response = match Request::read(&mut recv).await? {
+            Request::Allocate => {
+                if allocated.lock().expect("lock is not poisoned").len()
+                    >= MAX_NAMEPLATES_PER_CONNECTION
+                {
+                    Err(Error::TooManyCodes)
+                } else {
+                    self.mailboxes.allocate().await.map(|nameplate| {
+                        // Sides taken before it was allocated do not count
+                        self.free_sides(nameplate);
+                        allocated
+                            .lock()
+                            .expect("lock is not poisoned")
+                            .insert(nameplate);
+                        Bytes::copy_from_slice(&nameplate.to_be_bytes())
+                    })
+                }
+            }
+            Request::Post(nameplate, side, phase, message) => {
+                match self.claim(nameplate, side, connection_id) {
+                    Ok(()) => self
+                        .mailboxes
+                        .post(nameplate, side, phase, message)
+                        .await
+                        .map(|()| Bytes::new()),
+                    Err(err) => Err(err),
+                }
+            }
+            Request::Fetch(nameplate, side, phase) => {
+                match self.claim(nameplate, side, connection_id) {
+                    // Nobody posted in time, the code was likely given up on
+                    Ok(()) => tokio::time::timeout(
+                        FETCH_TIMEOUT,
+                        self.mailboxes.fetch(nameplate, side, phase),
+                    )
+                    .await
+                    .unwrap_or(Err(Error::UnknownCode)),
+                    Err(err) => Err(err),
+                }
+            }
+            Request::Release(nameplate) => {
+                // Only the device that allocated a nameplate may release it
+                let released = allocated
+                    .lock()
+                    .expect("lock is not poisoned")
+                    .remove(&nameplate);
+                if released {
+                    self.release(nameplate).await?;
+                }
+
+                Ok(Bytes::new())
+            }
+        };
+
+        write_response(&mut send, response).await?;
+        send.finish()?;
+
+        Ok(())
+    }
+
+    /// Binds `side` of `nameplate` to the connection of `connection_id`,
+    /// unless another connection already took it.
+    fn claim(&self, nameplate: u16, side: Side, connection_id: usize) -> Result<()> {
+        let mut sides = self.sides.lock().expect("lock is not poisoned");
+        let owner = sides.entry((nameplate, side)).or_insert(connection_id);
+        if *owner != connection_id {
+            return Err(Error::CodeAlreadyUsed);
+        }
+
+        Ok(())
+    }
+
+    fn free_sides(&self, nameplate: u16) {
+        self.sides
+            .lock()
+            .expect("lock is not poisoned")
+            .retain(|(side_nameplate, _), _| *side_nameplate != nameplate);
+    }
+
+    /// Frees `nameplate`, along with both of its sides.
+    async fn release(&self, nameplate: u16) -> Result<()> {
+        self.mailboxes.release(nameplate).await?;
+        self.free_sides(nameplate);
+
+        Ok(())
+    }
+}
+
+impl iroh::protocol::ProtocolHandler for RendezvousServer {
+    fn accept(
+        &self,
+        connection: Connection,
+    ) -> impl Future<Output = std::result::Result<(), AcceptError>> + Send {
+        Box::pin(async move {
+            let connection_id = connection.stable_id();
+            let allocated = Arc::new(Mutex::new(HashSet::new()));
+            let mut requests = JoinSet::new();
+
+            loop {
+                tokio::select! {
+                    stream = connection.accept_bi() => {
+                        let Ok((send, recv)) = stream else {
+                            break;
+                        };
+                        let (server, allocated) = (self.clone(), allocated.clone());
+                        requests.spawn(async move {
+                            let _ = server.answer(send, recv, connection_id, &allocated).await;
+                        });
+                    }
+                    Some(_) = requests.join_next() => {}
+                }
+            }
+
+            // Nobody is left to read the answers
+            requests.abort_all();
+            let allocated = std::mem::take(&mut *allocated.lock().expect("lock is not poisoned"));
+            for nameplate in allocated {
+                let _ = self.release(nameplate).await;
+            }
+            // The sides it took on nameplates of other devices are free again
+            self.sides
+                .lock()
+                .expect("lock is not poisoned")
+                .retain(|_, owner| *owner != connection_id);
+
+            Ok(())
+        })
+    }
+}
+
+/// A [`RendezvousServer`], reached through the endpoint of a sender or receiver.
+#[derive(Debug, Clone)]
+pub struct RemoteRendezvous {
+    p2p_endpoint: P2pEndpoint,
+    server: NodeAddr,
+    /// Kept open, as the server releases the allocated nameplates once disconnected.
+    connection: Arc<tokio::sync::Mutex<Option<Connection>>>,
+}
+
+impl RemoteRendezvous {
+    pub fn new(p2p_endpoint: P2pEndpoint, server: impl Into<NodeAddr>) -> Self {
+        Self {
+            p2p_endpoint,
+            server: server.into(),
+            connection: Default::default(),
+        }
+    }
+
+    async fn connection(&self) -> Result<Connection> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection
+            .as_ref()
+            .filter(|connection| connection.close_reason().is_none())
+        {
+            return Ok(connection.clone());
+        }
+
+        let new_connection = self
+            .p2p_endpoint
+            .connect(self.server.clone(), RENDEZVOUS_ALPN)
+            .await?;
+        *connection = Some(new_connection.clone());
+
+        Ok(new_connection)
+    }
+
+    async fn request(&self, request: Request) -> Result<Bytes> {
+        let (mut send, mut recv) = self.connection().await?.open_bi().await?;
+        request.write(&mut send).await?;
+        send.finish()?;
+
+        read_response(&mut recv).await
+    }
+}
+
+impl Rendezvous for RemoteRendezvous {
+    async fn allocate(&self) -> Result<u16> {
+        let mut nameplate = self.request(Request::Allocate).await?;
+        if nameplate.remaining() != size_of::<u16>() {
+            return Err(ProtocolError::MalformedRendezvousMessage.into());
+        }
+
+        Ok(nameplate.get_u16())
+    }
+
+    async fn post(&self, nameplate: u16, side: Side, phase: Phase, message: Bytes) -> Result<()> {
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(ProtocolError::MalformedRendezvousMessage.into());
+        }
+        self.request(Request::Post(nameplate, side, phase, message))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn fetch(&self, nameplate: u16, side: Side, phase: Phase) -> Result<Bytes> {
+        self.request(Request::Fetch(nameplate, side, phase)).await
+    }
+
+    async fn release(&self, nameplate: u16) -> Result<()> {
+        self.request(Request::Release(nameplate)).await?;
+
+        Ok(())
+    }
+}
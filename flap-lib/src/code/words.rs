@@ -0,0 +1,34 @@
+//! Words used in short codes.
+//!
+//! Every word is easy to spell and to say out loud, and no word is a prefix of another.
+
+/// 256 words, so that each word of a code carries one byte.
+pub const WORDS: [&str; 256] = [
+    "acorn", "adult", "aisle", "alarm", "album", "alley", "amber", "anchor", "angle", "ankle",
+    "apple", "apron", "arrow", "attic", "autumn", "badge", "bagel", "baker", "bamboo", "banjo",
+    "barley", "barrel", "basin", "basket", "beach", "beacon", "beard", "beetle", "bell", "bench",
+    "berry", "bicycle", "bishop", "blade", "blanket", "blossom", "board", "bonnet", "bottle",
+    "boulder", "bracket", "branch", "brick", "bridge", "broom", "bubble", "bucket", "buffalo",
+    "bugle", "bundle", "butter", "button", "cabin", "cable", "cactus", "camel", "candle", "canoe",
+    "canyon", "carpet", "carrot", "castle", "cedar", "cello", "chalk", "cherry", "chimney",
+    "cider", "circle", "clover", "cobalt", "cocoa", "comet", "copper", "coral", "cotton", "cougar",
+    "cradle", "crater", "crow", "crystal", "cupboard", "dagger", "daisy", "dancer", "delta",
+    "desert", "dinner", "dolphin", "donkey", "dragon", "drum", "eagle", "echo", "elbow", "ember",
+    "engine", "falcon", "feather", "fern", "fiddle", "finch", "flame", "flute", "forest", "fossil",
+    "fountain", "fox", "galaxy", "garden", "garlic", "geyser", "ginger", "glacier", "globe",
+    "goose", "granite", "grape", "gravel", "guitar", "hammer", "harbor", "harvest", "hazel",
+    "helmet", "heron", "hill", "honey", "horizon", "hornet", "iceberg", "igloo", "island", "ivory",
+    "jacket", "jaguar", "jasmine", "jelly", "jungle", "kayak", "kettle", "kitten", "ladder",
+    "lagoon", "lantern", "lemon", "lettuce", "lily", "lizard", "lobster", "locket", "lotus",
+    "magnet", "mango", "maple", "marble", "meadow", "melon", "meteor", "mirror", "mitten",
+    "monkey", "moose", "mosaic", "muffin", "mushroom", "napkin", "nectar", "needle", "nest",
+    "nickel", "noodle", "nutmeg", "oasis", "ocean", "olive", "onion", "orange", "orchid", "otter",
+    "owl", "oyster", "paddle", "panda", "parrot", "peach", "pebble", "pelican", "pencil", "pepper",
+    "piano", "pickle", "pigeon", "pillow", "pine", "planet", "plum", "pocket", "pond", "poppy",
+    "potato", "puzzle", "quartz", "quill", "rabbit", "radish", "rainbow", "raven", "ribbon",
+    "river", "robin", "rocket", "saddle", "salmon", "sandal", "scarf", "shadow", "shell", "silver",
+    "sparrow", "spider", "spoon", "squirrel", "stone", "sugar", "summer", "sunset", "swan",
+    "thunder", "tiger", "timber", "tomato", "tractor", "trumpet", "tulip", "tunnel", "turtle",
+    "umbrella", "valley", "velvet", "violin", "volcano", "wagon", "walnut", "walrus", "whale",
+    "wheat", "willow", "window", "wizard", "wolf", "yacht", "yarn", "yogurt", "zebra", "zipper",
+];
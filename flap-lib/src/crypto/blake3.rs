@@ -1,61 +1,288 @@
+//! BLAKE3 tree hashes of files, so that each block is verified as it arrives.
+//!
+//! Files are split into blocks of [`FILE_BLOCK_SIZE`] bytes, a power of two
+//! multiple of the BLAKE3 chunk length. Each block is then a subtree of the
+//! BLAKE3 tree of the whole file, whose root is the usual BLAKE3 hash of the file.
+//!
+//! The sender hashes each file right before sending it, announces the root,
+//! and sends along each block the hashes of the siblings on its path to the
+//! root, like Bao does. The receiver checks each block against the root on
+//! its own, so a transfer can resume from any block without rehashing what
+//! came before.
+//!
+//! Every block of a partial file was checked as it arrived, so to resume,
+//! the receiver only proves its last block with its [`boundary_hash`]. The
+//! sender has the hashes of its own blocks at hand, so it checks it without
+//! reading the file again.
+
+use std::io::SeekFrom;
+
+use blake3::hazmat::{
+    ChainingValue, HasherExt, Mode, merge_subtrees_non_root, merge_subtrees_root,
+};
 use bytes::BytesMut;
-use tokio::{fs::File, io::AsyncReadExt};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
 
-use crate::error::Result;
+use crate::{
+    crypto::encryption_stream::FILE_BLOCK_SIZE,
+    error::{Error, Result},
+};
 
+/// The BLAKE3 hash of a file, which is also the root of its tree.
 pub type FileHash = [u8; 32];
 
-/// A `blake3` hasher, used to verify that the final
-/// file is valid after a transfer's completion.
-///
-/// Even though the AEAD guarantees that the bytes
-/// received are what the sender intended, there
-/// is no proven guarantee as to whether the file
-/// was sent in order, or bytes were skipped, etc.
-///
-/// This is why this check is done.
-#[derive(Debug, Default)]
-pub struct Blake3(blake3::Hasher);
-
-impl Blake3 {
-    pub fn update_hasher(&mut self, data: &[u8]) {
-        self.0.update(data);
-    }
-
-    pub fn finalize_hash(&mut self) -> FileHash {
-        self.0.finalize().into()
-    }
-
-    /// Reads entire file given and returns the hasher in its current state.
-    ///
-    /// Used for resuming a partially completed transfer, since we still want
-    /// to verify the hash of the entire file.
-    ///
-    /// Reads up to `max` bytes. In our case, `max` is the file length of the
-    /// partial file the receiver already has. The sender has the full file, so
-    /// they want to read only up to the portion the receiver has to get the partial
-    /// hash.
-    pub async fn partial_hash(file: &mut File, max: Option<u64>) -> Result<Self> {
-        let mut hasher = Self::default();
-        const BUF_SIZE: usize = 1 << 16;
-        let mut total_read = 0;
-        let mut file_buf = BytesMut::zeroed(BUF_SIZE);
-        let max_seek = max.unwrap_or(file.metadata().await?.len()) as usize;
+/// How many blocks a file of `file_size` bytes has. Empty files have a single empty block.
+pub fn block_count(file_size: u64) -> u64 {
+    file_size.div_ceil(FILE_BLOCK_SIZE as u64).max(1)
+}
+
+/// How many bytes the block at `index` has. Only the last block may be shorter.
+fn block_len(file_size: u64, index: u64) -> u64 {
+    file_size
+        .saturating_sub(index * FILE_BLOCK_SIZE as u64)
+        .min(FILE_BLOCK_SIZE as u64)
+}
+
+fn block_hash(index: u64, block: &[u8]) -> ChainingValue {
+    let mut hasher = blake3::Hasher::new();
+    hasher.set_input_offset(index * FILE_BLOCK_SIZE as u64);
+    hasher.update(block);
+
+    hasher.finalize_non_root()
+}
+
+fn parent_hash(left: &ChainingValue, right: &ChainingValue, is_root: bool) -> ChainingValue {
+    if is_root {
+        merge_subtrees_root(left, right, Mode::Hash).into()
+    } else {
+        merge_subtrees_non_root(left, right, Mode::Hash)
+    }
+}
+
+/// The hash of the last block of the first `len` bytes of `file`, which
+/// only reads that block. `len` must be a whole, non zero number of blocks.
+pub async fn boundary_hash(file: &mut File, len: u64) -> Result<FileHash> {
+    debug_assert!(len > 0 && len.is_multiple_of(FILE_BLOCK_SIZE as u64));
+
+    let index = len / FILE_BLOCK_SIZE as u64 - 1;
+    file.seek(SeekFrom::Start(index * FILE_BLOCK_SIZE as u64))
+        .await?;
+    let mut buf = BytesMut::zeroed(FILE_BLOCK_SIZE);
+    if read_block(file, &mut buf).await? != FILE_BLOCK_SIZE {
+        return Err(Error::FileReadError);
+    }
+
+    Ok(block_hash(index, &buf))
+}
+
+/// Reads as many bytes as fit in `buf`, unless the end of the file comes first.
+pub async fn read_block(file: &mut File, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+
+    Ok(filled)
+}
+
+/// Every hash of the tree of a file, kept by the sender to prove each block
+/// while the file is sent.
+#[derive(Debug, Clone)]
+pub struct HashTree {
+    /// The hashes of the blocks first, then of their parents, up to the children of the root.
+    /// A node without a sibling is moved up a level as is.
+    levels: Vec<Vec<ChainingValue>>,
+    root: FileHash,
+}
+
+impl HashTree {
+    /// Reads the whole file to hash each of its blocks.
+    pub async fn from_file(file: &mut File) -> Result<Self> {
+        let mut buf = BytesMut::zeroed(FILE_BLOCK_SIZE);
+        let mut blocks = Vec::new();
+        let mut single_block_root = FileHash::default();
+
         loop {
-            match file.read(&mut file_buf).await? {
-                0 => break,
-                len => {
-                    if max_seek > 0 && max_seek - total_read < len {
-                        hasher.update_hasher(&file_buf[0..max_seek - total_read]);
-                        break;
-                    } else {
-                        hasher.update_hasher(&file_buf[0..len]);
-                        total_read += len;
-                    }
-                }
+            let len = read_block(file, &mut buf).await?;
+            // A file that fits in a single block is hashed as the root itself
+            if blocks.is_empty() {
+                single_block_root = *blake3::hash(&buf[..len]).as_bytes();
+            }
+            if len == 0 {
+                break;
+            }
+            blocks.push(block_hash(blocks.len() as u64, &buf[..len]));
+            if len < FILE_BLOCK_SIZE {
+                break;
             }
         }
 
-        Ok(hasher)
+        Ok(Self::from_blocks(blocks, single_block_root))
+    }
+
+    /// Builds the tree from the hashes of the blocks, `single_block_root`
+    /// being the root if there is only one block.
+    fn from_blocks(blocks: Vec<ChainingValue>, single_block_root: FileHash) -> Self {
+        let mut levels = vec![blocks];
+        let mut root = single_block_root;
+
+        while let Some(level) = levels.last().filter(|level| level.len() > 1) {
+            let is_root = level.len() == 2;
+            let parents: Vec<ChainingValue> = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => parent_hash(left, right, is_root),
+                    [promoted] => *promoted,
+                    _ => unreachable!("chunks of 2"),
+                })
+                .collect();
+
+            if is_root {
+                root = parents[0];
+                break;
+            }
+            levels.push(parents);
+        }
+
+        Self { levels, root }
+    }
+
+    pub fn root(&self) -> FileHash {
+        self.root
+    }
+
+    /// The [`boundary_hash`] of the first `block_count` blocks, if the file has that many.
+    pub fn boundary_hash(&self, block_count: u64) -> Option<FileHash> {
+        let index = block_count.checked_sub(1)?;
+
+        self.levels[0].get(index as usize).copied()
+    }
+
+    /// The hashes needed to check the block at `index` against the root, from the bottom up.
+    pub fn proof(&self, index: u64) -> Vec<FileHash> {
+        let mut proof = Vec::new();
+        let mut index = index as usize;
+
+        for level in &self.levels {
+            if level.len() == 1 {
+                break;
+            }
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            index /= 2;
+        }
+
+        proof
+    }
+}
+
+/// Checks that `block` is the block at `index` of the file of `file_size`
+/// bytes hashed to `root`, using the hashes of `proof`.
+pub fn verify_block(
+    root: &FileHash,
+    file_size: u64,
+    index: u64,
+    block: &[u8],
+    proof: &[FileHash],
+) -> Result<()> {
+    let block_count = block_count(file_size);
+    if index >= block_count || block.len() as u64 != block_len(file_size, index) {
+        return Err(Error::InvalidBlake3Hash);
+    }
+
+    let hash = if block_count == 1 {
+        *blake3::hash(block).as_bytes()
+    } else {
+        let mut hash = block_hash(index, block);
+        let mut proof = proof.iter();
+        let (mut index, mut width) = (index, block_count);
+
+        while width > 1 {
+            // Nodes without a sibling are moved up as is
+            if index ^ 1 < width {
+                let sibling = proof.next().ok_or(Error::InvalidBlake3Hash)?;
+                let is_root = width == 2;
+                hash = if index % 2 == 0 {
+                    parent_hash(&hash, sibling, is_root)
+                } else {
+                    parent_hash(sibling, &hash, is_root)
+                };
+            }
+            index /= 2;
+            width = width.div_ceil(2);
+        }
+
+        if proof.next().is_some() {
+            return Err(Error::InvalidBlake3Hash);
+        }
+
+        hash
+    };
+
+    if &hash == root {
+        Ok(())
+    } else {
+        Err(Error::InvalidBlake3Hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    async fn tree_of(content: &[u8]) -> HashTree {
+        let mut file = File::from_std(tempfile::tempfile().unwrap());
+        file.write_all(content).await.unwrap();
+        file.seek(SeekFrom::Start(0)).await.unwrap();
+
+        HashTree::from_file(&mut file).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn root_is_the_blake3_hash() {
+        let block = FILE_BLOCK_SIZE;
+
+        for len in [0, 1, block, block + 1, 2 * block, 3 * block + 5, 5 * block] {
+            let content: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let tree = tree_of(&content).await;
+            assert_eq!(tree.root(), *blake3::hash(&content).as_bytes(), "{len}");
+
+            for (index, block) in content.chunks(block).enumerate() {
+                let index = index as u64;
+                let proof = tree.proof(index);
+                verify_block(&tree.root(), len as u64, index, block, &proof).unwrap();
+
+                // Tampered with, or claimed to be another block
+                let mut tampered = block.to_vec();
+                tampered[0] ^= 1;
+                assert!(verify_block(&tree.root(), len as u64, index, &tampered, &proof).is_err());
+                assert!(verify_block(&tree.root(), len as u64, index + 1, block, &proof).is_err());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn boundary_hash_matches_tree() {
+        let block = FILE_BLOCK_SIZE;
+        let content: Vec<u8> = (0..3 * block + 5).map(|i| (i % 251) as u8).collect();
+        let tree = tree_of(&content).await;
+
+        let mut file = File::from_std(tempfile::tempfile().unwrap());
+        file.write_all(&content[..2 * block]).await.unwrap();
+        let partial = boundary_hash(&mut file, 2 * block as u64).await.unwrap();
+
+        assert_eq!(tree.boundary_hash(2), Some(partial));
+        assert_ne!(tree.boundary_hash(1), Some(partial));
+        assert_eq!(tree.boundary_hash(0), None);
+        assert_eq!(tree.boundary_hash(5), None);
     }
 }
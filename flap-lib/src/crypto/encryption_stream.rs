@@ -1,29 +1,45 @@
-use std::io::ErrorKind;
+use std::time::Duration;
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use iroh::{
     PublicKey, SecretKey,
-    endpoint::{RecvStream, SendStream, VarInt},
+    endpoint::{ReadError, ReadExactError, RecvStream, SendStream, VarInt, WriteError},
 };
 use snow::TransportState;
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
-};
+use tokio::{fs::File, io::AsyncWriteExt};
 
 use crate::{
-    crypto::{blake3::Blake3, transfer_id::TransferId, x25519},
-    error::{Error, Result},
+    crypto::{
+        blake3::{FileHash, HashTree, block_count, read_block, verify_block},
+        transfer_id::TransferId,
+        x25519,
+    },
+    error::{CloseReason, Error, ProtocolError, Result},
+    event::{Event, get_event_handler},
     fs::metadata::FlapFileMetadata,
-    p2p::frame::Frame,
+    p2p::{
+        cancel::{CancelReason, CancelToken},
+        frame::{Frame, MAX_FRAME_OPTIONAL_DATA_SIZE},
+        limits::RefuseReason,
+        manifest::{Manifest, ManifestEntry},
+        pause::PauseToken,
+        version::{NegotiatedProtocol, ProtocolHello},
+    },
     ticket::Ticket,
 };
 
 #[cfg(feature = "tracing")]
 use tracing::info;
 
-static NOISE_PATTERN: &'static str = "Noise_KKhfs+psk2_25519+Kyber1024_ChaChaPoly_BLAKE2s";
+static NOISE_PATTERN: &str = "Noise_KKhfs+psk2_25519+Kyber1024_ChaChaPoly_BLAKE2s";
 pub(crate) const MAX_NOISE_MESSAGE_LENGTH: usize = u16::MAX as usize;
+/// How many bytes of a file are read and sent per [`Frame::FileData`].
+pub const FILE_BLOCK_SIZE: usize = 1 << 15;
+/// The largest file metadata accepted from a peer, in bytes, which is enough
+/// for directories of a few hundred thousand files.
+pub(crate) const MAX_FILE_METADATA_SIZE: usize = 64 << 20;
+/// How long the peer has to take the cancel frame, before the stream is reset anyway.
+const CANCEL_FRAME_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct EncryptionStream {
     /// The stream to encrypt to.
@@ -32,9 +48,52 @@ pub struct EncryptionStream {
     recv_stream: RecvStream,
     send_buffer: Vec<u8>,
     recv_buffer: Vec<u8>,
-    file_hash: Blake3,
+    file_blocks: FileBlocks,
     noise: TransportState,
     transfer_id: TransferId,
+    /// The protocol version and capabilities both peers agreed on.
+    protocol: NegotiatedProtocol,
+    cancel_token: Option<CancelToken>,
+    pause_token: Option<PauseToken>,
+    paused_locally: bool,
+    paused_by_peer: bool,
+    /// The length of the message being read.
+    recv_msg_len: [u8; size_of::<u16>()],
+    /// How many bytes of the message being read, length included, were read so far.
+    recv_read: usize,
+}
+
+/// The file being sent or received, and which of its blocks comes next.
+enum FileBlocks {
+    None,
+    Sending {
+        tree: HashTree,
+        file_size: u64,
+        next_block: u64,
+    },
+    Receiving {
+        root_hash: FileHash,
+        file_size: u64,
+        next_block: u64,
+        /// Bytes of the file written so far, counting those resumed from.
+        bytes_received: u64,
+    },
+}
+
+/// What the sender opened a stream for.
+pub enum StreamOpening {
+    /// Sending a file or directory.
+    Item(FlapFileMetadata),
+    /// Announcing files, see [`crate::p2p::manifest`]. The first part of the
+    /// manifest, and whether it is the last one.
+    Manifest(Vec<ManifestEntry>, bool),
+}
+
+/// What happened first while waiting for the peer.
+enum Incoming {
+    Frame(Result<Frame>),
+    Cancelled(CancelReason),
+    PauseChanged,
 }
 
 impl EncryptionStream {
@@ -48,7 +107,6 @@ impl EncryptionStream {
     ) -> Result<Self> {
         let mut send_buffer = vec![0u8; MAX_NOISE_MESSAGE_LENGTH];
         let mut recv_buffer = vec![0u8; MAX_NOISE_MESSAGE_LENGTH];
-        let file_hash = Blake3::default();
 
         let stream_id = VarInt::from(send_stream.id()).into_inner().to_be_bytes();
         let file_key = ticket.master_key().file_key();
@@ -66,37 +124,45 @@ impl EncryptionStream {
             .psk(2, file_key.as_bytes())
             .expect("psk is 32 bytes long and has valid location");
 
+        // Both handshake messages carry the peer's protocol version and capabilities
+        let local_hello = ProtocolHello::default();
+        let mut payload = BytesMut::zeroed(MAX_NOISE_MESSAGE_LENGTH);
+
         // Due to QUIC, sender needs to be initiator
-        let handshake_state = if is_sender {
+        let (handshake_state, remote_hello) = if is_sender {
             // Receiver initiates the connection
             let mut handshake_state = initiator.build_initiator()?;
 
-            let len = handshake_state.write_message(&[], &mut send_buffer)?;
+            let len = handshake_state.write_message(&local_hello.to_bytes(), &mut send_buffer)?;
             Self::send_msg(&mut send_stream, &mut send_buffer[0..len]).await?;
 
             // wait for handshake response
             let len = Self::recv_msg(&mut recv_stream, &mut recv_buffer).await?;
-            handshake_state.read_message(&recv_buffer[0..len], &mut send_buffer)?;
+            let len = handshake_state.read_message(&recv_buffer[0..len], &mut payload)?;
+            let remote_hello = ProtocolHello::from_bytes(payload.split_to(len).freeze())?;
 
             debug_assert!(handshake_state.is_handshake_finished());
 
-            handshake_state
+            (handshake_state, remote_hello)
         } else {
             let mut handshake_state = initiator.build_responder()?;
 
             // wait for handshake
             let len = Self::recv_msg(&mut recv_stream, &mut recv_buffer).await?;
-            handshake_state.read_message(&recv_buffer[0..len], &mut send_buffer)?;
+            let len = handshake_state.read_message(&recv_buffer[0..len], &mut payload)?;
+            let remote_hello = ProtocolHello::from_bytes(payload.split_to(len).freeze())?;
 
             // send response
-            let len = handshake_state.write_message(&[], &mut send_buffer)?;
+            let len = handshake_state.write_message(&local_hello.to_bytes(), &mut send_buffer)?;
             Self::send_msg(&mut send_stream, &mut send_buffer[0..len]).await?;
 
             debug_assert!(handshake_state.is_handshake_finished());
 
-            handshake_state
+            (handshake_state, remote_hello)
         };
 
+        let protocol = local_hello.negotiate(&remote_hello)?;
+
         let transfer_id = TransferId(
             handshake_state
                 .get_handshake_hash()
@@ -107,16 +173,23 @@ impl EncryptionStream {
         let noise = handshake_state.into_transport_mode()?;
 
         #[cfg(feature = "tracing")]
-        info!("Noise handshake complete");
+        info!("Noise handshake complete, using protocol {protocol:?}");
 
         Ok(Self {
+            protocol,
             send_stream,
             recv_stream,
-            file_hash,
+            file_blocks: FileBlocks::None,
             send_buffer,
             recv_buffer,
             noise,
             transfer_id,
+            cancel_token: None,
+            pause_token: None,
+            paused_locally: false,
+            paused_by_peer: false,
+            recv_msg_len: [0; size_of::<u16>()],
+            recv_read: 0,
         })
     }
 
@@ -129,16 +202,28 @@ impl EncryptionStream {
             send_stream.id()
         );
 
-        send_stream.write_u16(send_buffer.len() as u16).await?;
-        send_stream.write_all(&send_buffer).await?;
+        let msg_len = (send_buffer.len() as u16).to_be_bytes();
+        send_stream
+            .write_all(&msg_len)
+            .await
+            .map_err(Self::map_write_error)?;
+        send_stream
+            .write_all(send_buffer)
+            .await
+            .map_err(Self::map_write_error)?;
         send_stream.flush().await?;
 
         Ok(())
     }
 
     #[inline]
-    async fn recv_msg(recv_stream: &mut RecvStream, recv_buffer: &mut Vec<u8>) -> Result<usize> {
-        let msg_len = recv_stream.read_u16().await?;
+    async fn recv_msg(recv_stream: &mut RecvStream, recv_buffer: &mut [u8]) -> Result<usize> {
+        let mut msg_len = [0u8; size_of::<u16>()];
+        recv_stream
+            .read_exact(&mut msg_len)
+            .await
+            .map_err(Self::map_read_error)?;
+        let msg_len = u16::from_be_bytes(msg_len);
 
         #[cfg(feature = "tracing")]
         info!(
@@ -149,31 +234,335 @@ impl EncryptionStream {
 
         recv_stream
             .read_exact(&mut (recv_buffer[0..msg_len as usize]))
-            .await?;
+            .await
+            .map_err(Self::map_read_error)?;
 
         Ok(msg_len as usize)
     }
 
-    pub fn set_file_hasher(&mut self, hasher: Blake3) {
-        self.file_hash = hasher;
+    /// Reads the next message into `recv_buffer`, and returns its length.
+    ///
+    /// Unlike [`Self::recv_msg`], this is cancel-safe: the part of the message
+    /// that was already read is kept until the next call.
+    async fn recv_msg_resumable(&mut self) -> Result<usize> {
+        const LEN_SIZE: usize = size_of::<u16>();
+
+        loop {
+            let buf = if self.recv_read < LEN_SIZE {
+                &mut self.recv_msg_len[self.recv_read..]
+            } else {
+                let msg_len = u16::from_be_bytes(self.recv_msg_len) as usize;
+                if self.recv_read == LEN_SIZE + msg_len {
+                    self.recv_read = 0;
+
+                    return Ok(msg_len);
+                }
+
+                &mut self.recv_buffer[self.recv_read - LEN_SIZE..msg_len]
+            };
+
+            match self.recv_stream.read(buf).await {
+                Ok(Some(read)) => self.recv_read += read,
+                Ok(None) => {
+                    return Err(Self::map_read_error(ReadExactError::FinishedEarly(
+                        self.recv_read,
+                    )));
+                }
+                Err(err) => return Err(Self::map_read_error(err.into())),
+            }
+        }
+    }
+
+    /// The peer aborting the stream is reported with the reason it gave.
+    fn map_read_error(err: ReadExactError) -> Error {
+        match err {
+            ReadExactError::ReadError(ReadError::Reset(code)) => {
+                CloseReason::from_code(code).into()
+            }
+            err => err.into(),
+        }
+    }
+
+    fn map_write_error(err: WriteError) -> Error {
+        match err {
+            WriteError::Stopped(code) => CloseReason::from_code(code).into(),
+            err => err.into(),
+        }
+    }
+
+    /// Aborts both directions of the stream, letting the peer know why.
+    ///
+    /// Used instead of panicking when a transfer fails, so that only the
+    /// stream of the failed transfer is closed.
+    pub fn abort(&mut self, err: &Error) {
+        let code = CloseReason::from(err).to_code();
+
+        // The stream may already be closed, in which case there is nothing to do
+        let _ = self.send_stream.reset(code);
+        let _ = self.recv_stream.stop(code);
+    }
+
+    /// Sends the file hashed to `tree` from `seek`, which must be the start of a block.
+    pub fn set_sending_file(&mut self, tree: HashTree, file_size: u64, seek: u64) {
+        debug_assert!(seek.is_multiple_of(FILE_BLOCK_SIZE as u64));
+
+        self.file_blocks = FileBlocks::Sending {
+            tree,
+            file_size,
+            next_block: seek / FILE_BLOCK_SIZE as u64,
+        };
+    }
+
+    /// Receives the file hashed to `root_hash` from `seek`, which must be the start of a block.
+    pub fn set_receiving_file(&mut self, root_hash: FileHash, file_size: u64, seek: u64) {
+        debug_assert!(seek.is_multiple_of(FILE_BLOCK_SIZE as u64));
+
+        self.file_blocks = FileBlocks::Receiving {
+            root_hash,
+            file_size,
+            next_block: seek / FILE_BLOCK_SIZE as u64,
+            bytes_received: seek,
+        };
     }
 
     pub fn transfer_id(&self) -> TransferId {
         self.transfer_id
     }
 
+    pub fn protocol(&self) -> NegotiatedProtocol {
+        self.protocol
+    }
+
+    /// Lets `cancel_token` cancel the transfer while frames are read or written.
+    pub(crate) fn set_cancel_token(&mut self, cancel_token: CancelToken) {
+        self.cancel_token = Some(cancel_token);
+    }
+
+    /// Resolves once the transfer is cancelled on our side, never if it cannot be.
+    async fn cancelled(cancel_token: &mut Option<CancelToken>) -> CancelReason {
+        match cancel_token {
+            Some(cancel_token) => cancel_token.cancelled().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Cancels the transfer on our side.
+    ///
+    /// The cancel frame can only be sent between two frames.
+    /// Otherwise, the peer only sees the stream being reset.
+    async fn cancel(&mut self, reason: CancelReason, between_frames: bool) -> Error {
+        let code = CloseReason::Cancelled.to_code();
+
+        if between_frames {
+            let sent = tokio::time::timeout(
+                CANCEL_FRAME_TIMEOUT,
+                self.write_frame_now(Frame::Cancel(reason)),
+            )
+            .await;
+
+            if matches!(sent, Ok(Ok(()))) {
+                // Finished instead of reset, so that the frame is still delivered
+                let _ = self.send_stream.finish();
+                let _ = self.recv_stream.stop(code);
+
+                return Error::TransferCancelled(reason);
+            }
+        }
+
+        let _ = self.send_stream.reset(code);
+        let _ = self.recv_stream.stop(code);
+
+        Error::TransferCancelled(reason)
+    }
+
+    /// Called once the peer stopped reading because it cancelled the transfer.
+    /// The reason is in the frame it sent before.
+    async fn peer_cancelled(&mut self) -> Error {
+        let read_reason = async {
+            loop {
+                match self.read_frame_now().await {
+                    Ok(Frame::Cancel(reason)) => return reason,
+                    Ok(_) => {}
+                    Err(_) => return CancelReason::Unknown,
+                }
+            }
+        };
+        let reason = tokio::time::timeout(CANCEL_FRAME_TIMEOUT, read_reason)
+            .await
+            .unwrap_or(CancelReason::Unknown);
+        let _ = self.send_stream.reset(CloseReason::Cancelled.to_code());
+
+        Error::TransferCancelled(reason)
+    }
+
+    /// Lets `pause_token` pause the transfer between file blocks.
+    pub(crate) fn set_pause_token(&mut self, pause_token: PauseToken) {
+        self.pause_token = Some(pause_token);
+    }
+
+    /// Whether either side has the transfer paused.
+    fn is_paused(&self) -> bool {
+        self.paused_locally || self.paused_by_peer
+    }
+
+    /// Resolves once the transfer is paused or resumed on our side, never if it cannot be.
+    async fn pause_changed(pause_token: &mut Option<PauseToken>) {
+        match pause_token {
+            Some(pause_token) => pause_token.changed().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn send_pause_event(&self, paused: bool) {
+        get_event_handler().send_event(if paused {
+            Event::TransferPaused(self.transfer_id)
+        } else {
+            Event::TransferResumed(self.transfer_id)
+        });
+    }
+
+    /// Lets the peer know if we paused or resumed the transfer since last time.
+    async fn apply_local_pause(&mut self) -> Result<()> {
+        let paused = self.pause_token.as_ref().is_some_and(PauseToken::is_paused);
+        if paused == self.paused_locally {
+            return Ok(());
+        }
+        self.paused_locally = paused;
+
+        self.write_frame(if paused { Frame::Pause } else { Frame::Resume })
+            .await?;
+        self.send_pause_event(paused);
+
+        Ok(())
+    }
+
+    /// Takes in the cancel, pause and resume frames of the peer, and returns any other frame.
+    fn take_control_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        match frame {
+            Frame::Cancel(reason) => {
+                // The peer is gone, there is nothing left to send
+                let _ = self.send_stream.reset(CloseReason::Cancelled.to_code());
+
+                Err(Error::TransferCancelled(reason))
+            }
+            Frame::Pause | Frame::Resume => {
+                let paused = frame == Frame::Pause;
+                if paused != self.paused_by_peer {
+                    self.paused_by_peer = paused;
+                    self.send_pause_event(paused);
+                }
+
+                Ok(None)
+            }
+            frame => Ok(Some(frame)),
+        }
+    }
+
+    /// Waits for the next frame, unless the transfer is cancelled by either side first.
+    ///
+    /// Returns `None` as soon as either side paused or resumed the transfer.
+    async fn next_frame(&mut self) -> Result<Option<Frame>> {
+        self.apply_local_pause().await?;
+
+        let mut cancel_token = self.cancel_token.take();
+        let mut pause_token = self.pause_token.take();
+        let incoming = match cancel_token.as_ref().and_then(CancelToken::reason) {
+            Some(reason) => Incoming::Cancelled(reason),
+            None => tokio::select! {
+                frame = self.read_frame_now() => Incoming::Frame(frame),
+                reason = Self::cancelled(&mut cancel_token) => Incoming::Cancelled(reason),
+                () = Self::pause_changed(&mut pause_token) => Incoming::PauseChanged,
+            },
+        };
+        self.cancel_token = cancel_token;
+        self.pause_token = pause_token;
+
+        match incoming {
+            Incoming::Frame(frame) => self.take_control_frame(frame?),
+            Incoming::Cancelled(reason) => Err(self.cancel(reason, true).await),
+            // Applied by the next call
+            Incoming::PauseChanged => Ok(None),
+        }
+    }
+
+    /// Reads the next frame, unless the transfer is cancelled by either side first.
+    ///
+    /// Keeps up with either side pausing or resuming the transfer in the meantime.
     async fn read_frame(&mut self) -> Result<Frame> {
-        let len = Self::recv_msg(&mut self.recv_stream, &mut self.recv_buffer).await?;
+        loop {
+            if let Some(frame) = self.next_frame().await? {
+                return Ok(frame);
+            }
+        }
+    }
+
+    /// Waits until neither side has the transfer paused.
+    ///
+    /// The sender only reads from the receiver here, between two file blocks.
+    async fn wait_while_paused(&mut self) -> Result<()> {
+        // Takes in what the receiver sent since the last block, without waiting for more
+        loop {
+            let frame = tokio::select! {
+                biased;
+                frame = self.read_frame_now() => frame?,
+                () = std::future::ready(()) => break,
+            };
+            if let Some(frame) = self.take_control_frame(frame)? {
+                return Err(Self::unexpected_while_sending(frame));
+            }
+        }
+        self.apply_local_pause().await?;
+
+        while self.is_paused() {
+            if let Some(frame) = self.next_frame().await? {
+                return Err(Self::unexpected_while_sending(frame));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unexpected_while_sending(frame: Frame) -> Error {
+        ProtocolError::UnexpectedFrame {
+            expected: "Pause",
+            received: frame.kind(),
+        }
+        .into()
+    }
+
+    /// Writes a frame, unless the transfer is cancelled by either side first.
+    async fn write_frame(&mut self, frame: Frame) -> Result<()> {
+        let mut cancel_token = self.cancel_token.take();
+        let written = match cancel_token.as_ref().and_then(CancelToken::reason) {
+            Some(reason) => Err((reason, true)),
+            None => tokio::select! {
+                written = self.write_frame_now(frame) => Ok(written),
+                // Part of the frame may already be written
+                reason = Self::cancelled(&mut cancel_token) => Err((reason, false)),
+            },
+        };
+        self.cancel_token = cancel_token;
+
+        match written {
+            Ok(Err(Error::TransferCancelled(_))) => Err(self.peer_cancelled().await),
+            Ok(written) => written,
+            Err((reason, between_frames)) => Err(self.cancel(reason, between_frames).await),
+        }
+    }
+
+    /// Cancel-safe, a frame is never partially read.
+    async fn read_frame_now(&mut self) -> Result<Frame> {
+        let len = self.recv_msg_resumable().await?;
         let mut payload = BytesMut::zeroed(MAX_NOISE_MESSAGE_LENGTH);
         let len = self
             .noise
             .read_message(&self.recv_buffer[0..len], &mut payload)?;
-        let frame = Frame::read_from_frame(payload.split_to(len).into()).await?;
+        let frame = Frame::read_from_frame(payload.split_to(len).into())?;
 
         Ok(frame)
     }
 
-    async fn write_frame(&mut self, frame: Frame) -> Result<()> {
+    async fn write_frame_now(&mut self, frame: Frame) -> Result<()> {
         let serialized_frame = frame.to_bytes();
 
         let len = self
@@ -184,64 +573,279 @@ impl EncryptionStream {
         Ok(())
     }
 
-    pub async fn send_ready(&mut self, seek: u64) -> Result<()> {
-        self.write_frame(Frame::PleaseSendFile(seek)).await?;
+    /// Asks for the file from `seek`, proving that we have what comes before
+    /// with the `boundary_hash` of the partial file.
+    pub async fn send_ready(&mut self, seek: u64, boundary_hash: FileHash) -> Result<()> {
+        self.write_frame(Frame::PleaseSendFile(seek, boundary_hash))
+            .await?;
 
         Ok(())
     }
 
-    pub async fn wait_for_ready(&mut self) -> Result<u64> {
+    /// Announces the hash of the next file, that each of its blocks is checked against.
+    pub async fn send_file_hash(&mut self, root_hash: FileHash) -> Result<()> {
+        self.write_frame(Frame::NextFileHash(root_hash)).await?;
+
+        Ok(())
+    }
+
+    pub async fn wait_for_file_hash(&mut self) -> Result<FileHash> {
         match self.read_frame().await? {
-            Frame::PleaseSendFile(seek) => Ok(seek),
-            _ => panic!("unexpected response from sender"),
+            Frame::NextFileHash(root_hash) => Ok(root_hash),
+            frame => Err(ProtocolError::UnexpectedFrame {
+                expected: "NextFileHash",
+                received: frame.kind(),
+            }
+            .into()),
         }
     }
 
-    pub async fn get_file_metadata(&mut self) -> Result<FlapFileMetadata> {
+    /// Lets the receiver know where the file will be sent from,
+    /// which is from the start if its partial file did not match.
+    pub async fn send_file_start(&mut self, seek: u64) -> Result<()> {
+        self.write_frame(Frame::SendingFileFrom(seek)).await?;
+
+        Ok(())
+    }
+
+    pub async fn wait_for_file_start(&mut self) -> Result<u64> {
         match self.read_frame().await? {
-            Frame::IWillSendThisFile(metadata) => Ok(metadata),
-            _ => panic!("unexpected response from sender"),
+            Frame::SendingFileFrom(seek) => Ok(seek),
+            frame => Err(ProtocolError::UnexpectedFrame {
+                expected: "SendingFileFrom",
+                received: frame.kind(),
+            }
+            .into()),
         }
     }
 
-    pub async fn send_file_metadata(&mut self, metadata: FlapFileMetadata) -> Result<()> {
-        self.write_frame(Frame::IWillSendThisFile(metadata)).await?;
+    /// Lets the sender know we do not want the next file, but want the ones after it.
+    pub async fn send_skip(&mut self) -> Result<()> {
+        self.write_frame(Frame::SkipFile).await?;
 
         Ok(())
     }
 
-    pub async fn recv_next_file_block(&mut self, file: &mut File) -> Result<usize> {
+    /// Lets the sender know we do not want the file or directory it offered.
+    pub async fn send_decline(&mut self) -> Result<()> {
+        self.write_frame(Frame::DeclineFile).await?;
+
+        Ok(())
+    }
+
+    /// Lets the sender know we refuse the next file, and why.
+    pub async fn send_refuse(&mut self, reason: RefuseReason) -> Result<()> {
+        self.write_frame(Frame::RefuseFile(reason)).await?;
+
+        Ok(())
+    }
+
+    /// Waits for the receiver to ask for the next file. `None` if it skips it.
+    pub async fn wait_for_ready(&mut self) -> Result<Option<(u64, FileHash)>> {
         match self.read_frame().await? {
-            Frame::FileData(file_data) => {
-                self.file_hash.update_hasher(&file_data);
-                match file.write_all(&file_data).await {
-                    Err(e) => {
-                        if e.kind() == ErrorKind::UnexpectedEof {
-                            // We assume only one EOF per file, located
-                            // at the end of the file. Therefore this is the
-                            // EOF of the file that was sent to us.
-                            // At this point the entire file should have been written.
-                            file.flush().await?;
-
-                            Ok(0)
-                        } else {
-                            // Other, actual I/O error.
-                            Err(Error::FileIoError(e))
-                        }
+            Frame::PleaseSendFile(seek, boundary_hash) => Ok(Some((seek, boundary_hash))),
+            Frame::SkipFile => Ok(None),
+            Frame::DeclineFile => Err(Error::TransferDeclined),
+            Frame::RefuseFile(reason) => Err(Error::TransferRefused(reason)),
+            frame => Err(ProtocolError::UnexpectedFrame {
+                expected: "PleaseSendFile",
+                received: frame.kind(),
+            }
+            .into()),
+        }
+    }
+
+    /// Waits for the first frame of a stream, which tells what the sender opened it for.
+    pub async fn wait_for_opening(&mut self) -> Result<StreamOpening> {
+        match self.read_frame().await? {
+            Frame::IWillSendThisFile(metadata) => Ok(StreamOpening::Item(metadata)),
+            Frame::FileMetadataPart(part, last) => Ok(StreamOpening::Item(
+                self.wait_for_file_metadata_parts(part, last).await?,
+            )),
+            Frame::Manifest(entries, last) => Ok(StreamOpening::Manifest(entries, last)),
+            frame => Err(ProtocolError::UnexpectedFrame {
+                expected: "IWillSendThisFile",
+                received: frame.kind(),
+            }
+            .into()),
+        }
+    }
+
+    /// Announces the files of `manifest`, in as many frames as needed.
+    pub async fn send_manifest(&mut self, manifest: &Manifest) -> Result<()> {
+        for frame in manifest.to_frames()? {
+            self.write_frame(frame).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the next part of a manifest, and whether it is the last one.
+    pub async fn wait_for_manifest(&mut self) -> Result<(Vec<ManifestEntry>, bool)> {
+        match self.read_frame().await? {
+            Frame::Manifest(entries, last) => Ok((entries, last)),
+            frame => Err(ProtocolError::UnexpectedFrame {
+                expected: "Manifest",
+                received: frame.kind(),
+            }
+            .into()),
+        }
+    }
+
+    /// Asks for the files at `indices` of the last manifest received, in as
+    /// many frames as needed.
+    pub async fn send_file_request(&mut self, indices: &[u32]) -> Result<()> {
+        // The flag and the index count come first
+        const CAPACITY: usize =
+            (MAX_FRAME_OPTIONAL_DATA_SIZE - size_of::<u8>() - size_of::<u16>()) / size_of::<u32>();
+
+        let mut parts = indices.chunks(CAPACITY).peekable();
+        if parts.peek().is_none() {
+            return self
+                .write_frame(Frame::RequestFiles(Vec::new(), true))
+                .await;
+        }
+        while let Some(part) = parts.next() {
+            let last = parts.peek().is_none();
+            self.write_frame(Frame::RequestFiles(part.to_vec(), last))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for every part of the receiver's answer to the last manifest sent,
+    /// and returns the indices of the files it asked for.
+    ///
+    /// Fails with [`Error::TransferRefused`] if the files do not fit on the receiver.
+    pub async fn wait_for_file_request(&mut self) -> Result<Vec<u32>> {
+        let mut indices = Vec::new();
+        loop {
+            match self.read_frame().await? {
+                Frame::RequestFiles(part, last) => {
+                    indices.extend(part);
+                    if last {
+                        return Ok(indices);
                     }
-                    _ => {
-                        file.flush().await?;
+                }
+                Frame::RefuseFile(reason) if indices.is_empty() => {
+                    return Err(Error::TransferRefused(reason));
+                }
+                frame => {
+                    return Err(ProtocolError::UnexpectedFrame {
+                        expected: "RequestFiles",
+                        received: frame.kind(),
+                    }
+                    .into());
+                }
+            }
+        }
+    }
 
-                        Ok(file_data.len())
+    /// Sends the metadata of the file or directory, in as many frames as needed.
+    pub async fn send_file_metadata(&mut self, metadata: FlapFileMetadata) -> Result<()> {
+        let bytes = metadata.to_bytes();
+        if bytes.len() <= MAX_FRAME_OPTIONAL_DATA_SIZE {
+            return self.write_frame(Frame::IWillSendThisFile(metadata)).await;
+        }
+        if bytes.len() > MAX_FILE_METADATA_SIZE {
+            return Err(Error::MetadataTooLarge);
+        }
+
+        // The flag comes first
+        const CAPACITY: usize = MAX_FRAME_OPTIONAL_DATA_SIZE - size_of::<u8>();
+
+        let mut parts = bytes.chunks(CAPACITY).peekable();
+        while let Some(part) = parts.next() {
+            let last = parts.peek().is_none();
+            self.write_frame(Frame::FileMetadataPart(bytes.slice_ref(part), last))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for every part of file metadata sent in [`Frame::FileMetadataPart`]
+    /// frames, starting with `part`, and decodes it.
+    async fn wait_for_file_metadata_parts(
+        &mut self,
+        part: Bytes,
+        mut last: bool,
+    ) -> Result<FlapFileMetadata> {
+        let mut bytes = BytesMut::from(part);
+        while !last {
+            match self.read_frame().await? {
+                Frame::FileMetadataPart(part, is_last) => {
+                    if bytes.len() + part.len() > MAX_FILE_METADATA_SIZE {
+                        return Err(ProtocolError::OversizedMetadata(MAX_FILE_METADATA_SIZE).into());
                     }
+                    bytes.extend_from_slice(&part);
+                    last = is_last;
+                }
+                frame => {
+                    return Err(ProtocolError::UnexpectedFrame {
+                        expected: "FileMetadataPart",
+                        received: frame.kind(),
+                    }
+                    .into());
                 }
             }
-            Frame::TransferComplete(sender_file_hash) => {
-                let our_file_hash = self.file_hash.finalize_hash();
+        }
 
+        FlapFileMetadata::from_bytes(bytes.freeze())
+    }
+
+    /// Receives the next block of the file, checking it against the file's hash.
+    ///
+    /// Returns how many bytes the block has, or 0 once the whole file was received.
+    pub async fn recv_next_file_block(&mut self, file: &mut File) -> Result<usize> {
+        let frame = self.read_frame().await?;
+        let FileBlocks::Receiving {
+            root_hash,
+            file_size,
+            next_block,
+            bytes_received,
+        } = &mut self.file_blocks
+        else {
+            // No file was started, see `Self::set_receiving_file`
+            return Err(ProtocolError::UnexpectedFrame {
+                expected: "SendingFileFrom",
+                received: frame.kind(),
+            }
+            .into());
+        };
+
+        match frame {
+            Frame::FileData(block, proof) => {
+                // Nothing past the announced size is written, whatever its hash
+                if *next_block >= block_count(*file_size)
+                    || *bytes_received + block.len() as u64 > *file_size
+                {
+                    return Err(ProtocolError::FileOverrun {
+                        file_size: *file_size,
+                    }
+                    .into());
+                }
+                verify_block(root_hash, *file_size, *next_block, &block, &proof)?;
+                *next_block += 1;
+
+                file.write_all(&block).await?;
+                file.flush().await?;
+                *bytes_received += block.len() as u64;
+
+                Ok(block.len())
+            }
+            Frame::TransferComplete(sender_file_hash) => {
                 file.sync_all().await?;
 
-                if sender_file_hash != our_file_hash {
+                // Every block was checked, but the last ones may be missing
+                if *bytes_received < *file_size {
+                    Err(ProtocolError::FileTruncated {
+                        received: *bytes_received,
+                        file_size: *file_size,
+                    }
+                    .into())
+                } else if sender_file_hash != *root_hash {
                     Err(Error::InvalidBlake3Hash)
                 } else {
                     #[cfg(feature = "tracing")]
@@ -250,31 +854,279 @@ impl EncryptionStream {
                     Ok(0)
                 }
             }
-            _ => unreachable!("file decryption shouldn't contain other types of frames"),
+            frame => Err(ProtocolError::UnexpectedFrame {
+                expected: "FileData",
+                received: frame.kind(),
+            }
+            .into()),
         }
     }
 
+    /// Sends the next block of the file along with its proof.
+    ///
+    /// Returns how many bytes the block has, or 0 once the whole file was sent.
     pub async fn send_next_file_block(
         &mut self,
         file: &mut File,
         file_buf: &mut BytesMut,
     ) -> Result<usize> {
-        let bytes_read = file.read(file_buf).await?;
+        self.wait_while_paused().await?;
+
+        let FileBlocks::Sending {
+            tree,
+            file_size,
+            next_block,
+        } = &mut self.file_blocks
+        else {
+            // No file was started, see `Self::set_sending_file`
+            return Err(Error::FileNotStarted);
+        };
 
+        if *next_block * FILE_BLOCK_SIZE as u64 >= *file_size {
+            let root_hash = tree.root();
+            self.write_frame(Frame::TransferComplete(root_hash)).await?;
+
+            return Ok(0);
+        }
+
+        let bytes_read = read_block(file, file_buf).await?;
         if bytes_read == 0 {
-            let final_file_hash = self.file_hash.finalize_hash();
+            // The file was truncated since it was hashed
+            return Err(Error::FileReadError);
+        }
+        let proof = tree.proof(*next_block);
+        *next_block += 1;
 
-            self.write_frame(Frame::TransferComplete(final_file_hash))
-                .await?;
-            self.send_stream.finish()?;
+        let file_data = Bytes::copy_from_slice(&file_buf[..bytes_read]);
+        self.write_frame(Frame::FileData(file_data, proof)).await?;
 
-            Ok(0)
-        } else {
-            self.file_hash.update_hasher(&file_buf);
-            self.write_frame(Frame::FileData(file_buf.clone().freeze()))
-                .await?;
+        Ok(bytes_read)
+    }
+
+    /// Gracefully closes the sending side of the stream once every file has been sent.
+    pub fn finish(&mut self) -> Result<()> {
+        self.send_stream.finish()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::SeekFrom;
+
+    use iroh::{Watcher, endpoint::Connection};
+    use tokio::io::AsyncSeekExt;
 
-            Ok(bytes_read)
+    use super::*;
+    use crate::{
+        crypto::master_key::MasterKey,
+        p2p::{
+            ALPN,
+            endpoint::{P2pEndpoint, P2pEndpointConfig},
+        },
+    };
+
+    /// Both sides of a stream between two endpoints on localhost.
+    struct StreamPair {
+        sender: EncryptionStream,
+        receiver: EncryptionStream,
+        // The streams are closed with their connection and endpoint
+        _connections: [Connection; 2],
+        _endpoints: [P2pEndpoint; 2],
+    }
+
+    impl StreamPair {
+        async fn new() -> Self {
+            let config = P2pEndpointConfig::loopback();
+            let sender_endpoint = P2pEndpoint::start(&config).await.unwrap();
+            let receiver_endpoint = P2pEndpoint::start(&config).await.unwrap();
+            let ticket = Ticket::make(
+                sender_endpoint.node_addr().initialized().await,
+                MasterKey::generate(),
+            );
+
+            let accept = async {
+                let incoming = sender_endpoint.accept().await.unwrap();
+                let connection = incoming.await.unwrap();
+                let (tx, rx) = connection.open_bi().await.unwrap();
+                let stream = EncryptionStream::initiate(
+                    true,
+                    sender_endpoint.secret_key(),
+                    &receiver_endpoint.node_id(),
+                    tx,
+                    rx,
+                    &ticket,
+                )
+                .await
+                .unwrap();
+
+                (connection, stream)
+            };
+            let connect = async {
+                let connection = receiver_endpoint
+                    .connect(ticket.node_addr(), ALPN)
+                    .await
+                    .unwrap();
+                let (tx, rx) = connection.accept_bi().await.unwrap();
+                let stream = EncryptionStream::initiate(
+                    false,
+                    receiver_endpoint.secret_key(),
+                    &ticket.node_id,
+                    tx,
+                    rx,
+                    &ticket,
+                )
+                .await
+                .unwrap();
+
+                (connection, stream)
+            };
+            let ((sender_connection, sender), (receiver_connection, receiver)) =
+                tokio::join!(accept, connect);
+
+            Self {
+                sender,
+                receiver,
+                _connections: [sender_connection, receiver_connection],
+                _endpoints: [sender_endpoint, receiver_endpoint],
+            }
         }
     }
+
+    async fn file_with(content: &[u8]) -> File {
+        let mut file = File::from_std(tempfile::tempfile().unwrap());
+        file.write_all(content).await.unwrap();
+        file.seek(SeekFrom::Start(0)).await.unwrap();
+
+        file
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocks_before_the_file_starts_are_errors() {
+        let StreamPair {
+            mut sender,
+            mut receiver,
+            ..
+        } = StreamPair::new().await;
+
+        let mut file = file_with(b"block").await;
+        let mut file_buf = BytesMut::zeroed(FILE_BLOCK_SIZE);
+        assert!(matches!(
+            sender.send_next_file_block(&mut file, &mut file_buf).await,
+            Err(Error::FileNotStarted)
+        ));
+
+        sender
+            .write_frame(Frame::FileData(Bytes::from_static(b"block"), Vec::new()))
+            .await
+            .unwrap();
+        let mut output = file_with(&[]).await;
+        assert!(matches!(
+            receiver.recv_next_file_block(&mut output).await,
+            Err(Error::Protocol(ProtocolError::UnexpectedFrame {
+                received: "FileData",
+                ..
+            }))
+        ));
+    }
+
+    /// Both sides start sending and receiving a file of `content`.
+    async fn start_file(pair: &mut StreamPair, content: &[u8]) -> (File, File) {
+        let mut file = file_with(content).await;
+        let tree = HashTree::from_file(&mut file).await.unwrap();
+        file.seek(SeekFrom::Start(0)).await.unwrap();
+
+        pair.receiver
+            .set_receiving_file(tree.root(), content.len() as u64, 0);
+        pair.sender.set_sending_file(tree, content.len() as u64, 0);
+
+        (file, file_with(&[]).await)
+    }
+
+    /// The receiver aborts with `err`, which the sender sees as a protocol error.
+    async fn assert_aborted(pair: &mut StreamPair, err: &Error) {
+        pair.receiver.abort(err);
+        assert!(matches!(
+            pair.sender.wait_for_ready().await,
+            Err(Error::ClosedByPeer(CloseReason::ProtocolError))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sending_more_than_announced_is_an_error() {
+        let mut pair = StreamPair::new().await;
+        let content = vec![7; FILE_BLOCK_SIZE];
+        let (mut file, mut output) = start_file(&mut pair, &content).await;
+
+        let mut file_buf = BytesMut::zeroed(FILE_BLOCK_SIZE);
+        pair.sender
+            .send_next_file_block(&mut file, &mut file_buf)
+            .await
+            .unwrap();
+        assert_eq!(
+            pair.receiver
+                .recv_next_file_block(&mut output)
+                .await
+                .unwrap(),
+            FILE_BLOCK_SIZE
+        );
+
+        // A block past the end of the file
+        pair.sender
+            .write_frame(Frame::FileData(Bytes::from_static(b"more"), Vec::new()))
+            .await
+            .unwrap();
+        let err = pair
+            .receiver
+            .recv_next_file_block(&mut output)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Protocol(ProtocolError::FileOverrun { file_size }) if file_size == FILE_BLOCK_SIZE as u64
+        ));
+        assert_aborted(&mut pair, &err).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sending_less_than_announced_is_an_error() {
+        let mut pair = StreamPair::new().await;
+        let content = vec![7; 2 * FILE_BLOCK_SIZE];
+        let (mut file, mut output) = start_file(&mut pair, &content).await;
+        let root_hash = HashTree::from_file(&mut file_with(&content).await)
+            .await
+            .unwrap()
+            .root();
+
+        let mut file_buf = BytesMut::zeroed(FILE_BLOCK_SIZE);
+        pair.sender
+            .send_next_file_block(&mut file, &mut file_buf)
+            .await
+            .unwrap();
+        assert_eq!(
+            pair.receiver
+                .recv_next_file_block(&mut output)
+                .await
+                .unwrap(),
+            FILE_BLOCK_SIZE
+        );
+
+        // Completing the file without its last block
+        pair.sender
+            .write_frame(Frame::TransferComplete(root_hash))
+            .await
+            .unwrap();
+        let err = pair
+            .receiver
+            .recv_next_file_block(&mut output)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Protocol(ProtocolError::FileTruncated { received, file_size })
+                if received == FILE_BLOCK_SIZE as u64 && file_size == 2 * FILE_BLOCK_SIZE as u64
+        ));
+        assert_aborted(&mut pair, &err).await;
+    }
 }
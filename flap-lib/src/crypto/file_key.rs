@@ -18,9 +18,9 @@ impl MasterKey {
 
         let ikm = self.0.as_slice();
 
-        let hk = Hkdf::<Sha256>::new(None, &ikm);
+        let hk = Hkdf::<Sha256>::new(None, ikm);
         let mut file_key = [0u8; 32];
-        hk.expand(&HKDF_INFO, &mut file_key)
+        hk.expand(HKDF_INFO, &mut file_key)
             .expect("valid length output");
 
         FileKey(file_key)
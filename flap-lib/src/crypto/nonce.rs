@@ -7,8 +7,8 @@ use crate::crypto::master_key::MasterKey;
 
 /// A nonce for use in the AEAD stream cipher.
 ///
-/// This should only be used once, but I will design
-/// the app in a way that the Ticket is a one-time code.
+/// This should only be used once. Tickets are single-use by
+/// default, see [`crate::p2p::policy::TicketPolicy`].
 pub struct AeadStreamNonce([u8; 19]);
 
 impl MasterKey {
@@ -17,9 +17,9 @@ impl MasterKey {
 
         let ikm = self.0.as_slice();
 
-        let hk = Hkdf::<Sha256>::new(None, &ikm);
+        let hk = Hkdf::<Sha256>::new(None, ikm);
         let mut nonce = [0u8; 19];
-        hk.expand(&HKDF_INFO, &mut nonce)
+        hk.expand(HKDF_INFO, &mut nonce)
             .expect("valid length output");
 
         AeadStreamNonce(nonce)
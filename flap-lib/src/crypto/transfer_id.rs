@@ -10,9 +10,9 @@ pub struct TransferId(pub [u8; 32]);
 impl TransferId {
     pub fn new(ticket: &Ticket, stream_id: StreamId) -> TransferId {
         let mut hasher = Sha256::new();
-        hasher.update(&ticket.master_key().0);
+        hasher.update(ticket.master_key().0);
         hasher.update(ticket.node_id.as_bytes());
-        hasher.update(&VarInt::from(stream_id).into_inner().to_le_bytes());
+        hasher.update(VarInt::from(stream_id).into_inner().to_le_bytes());
 
         let hash = hasher.finalize();
         TransferId(hash.into())
@@ -1,27 +1,34 @@
+use std::path::PathBuf;
+
+use iroh::endpoint::VarInt;
 use thiserror::Error;
 
+use crate::p2p::{cancel::CancelReason, limits::RefuseReason};
+
 pub type Result<T> = core::prelude::v1::Result<T, Error>;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("unknown error")]
     Unknown,
-    #[error("frame serialization error")]
-    SerializationError,
+    #[error("The other device sent invalid data: {0}")]
+    Protocol(#[from] ProtocolError),
+    #[error("The other device aborted the transfer ({0:?})")]
+    ClosedByPeer(CloseReason),
     #[error("P2P accept error")]
-    AcceptError(#[from] iroh::protocol::AcceptError),
+    AcceptError(#[source] Box<iroh::protocol::AcceptError>),
     #[error("P2P connection error")]
-    ConnectionError(#[from] iroh::endpoint::ConnectionError),
+    ConnectionError(#[source] Box<iroh::endpoint::ConnectionError>),
     #[error("P2P bind error")]
-    BindError(#[from] iroh::endpoint::BindError),
+    BindError(#[source] Box<iroh::endpoint::BindError>),
     #[error("P2P connect error")]
-    ConnectError(#[from] iroh::endpoint::ConnectError),
+    ConnectError(#[source] Box<iroh::endpoint::ConnectError>),
     #[error("P2P write error")]
-    WriteError(#[from] iroh::endpoint::WriteError),
+    WriteError(#[source] Box<iroh::endpoint::WriteError>),
     #[error("P2P receive error")]
-    ReadError(#[from] iroh::endpoint::ReadError),
+    ReadError(#[source] Box<iroh::endpoint::ReadError>),
     #[error("P2P receive error")]
-    ReadExactError(#[from] iroh::endpoint::ReadExactError),
+    ReadExactError(#[source] Box<iroh::endpoint::ReadExactError>),
     #[error("P2P connection closed")]
     ClosedStream(#[from] iroh::endpoint::ClosedStream),
     #[error("Could not prepare file to encrypt")]
@@ -40,6 +47,202 @@ pub enum Error {
     FileAlreadyAdded,
     #[error("Filesystem IO error")]
     FileIoError(#[from] std::io::Error),
-    #[error("The final file hash is invalid")]
+    #[error("Could not find the user's download directory. Choose where to save files instead.")]
+    NoDownloadDir,
+    #[error("Could not create the download directory {0:?}")]
+    DownloadDirError(PathBuf, #[source] std::io::Error),
+    #[error("A block of the file does not match the file's hash")]
     InvalidBlake3Hash,
+    #[error("The directory has too many entries to be sent at once")]
+    MetadataTooLarge,
+    #[error("Received a file name that is not allowed: {0:?}")]
+    InvalidFileName(String),
+    #[error(
+        "The other device uses an incompatible version of Flap (ours: {local}, theirs: {remote}). Both devices should update Flap."
+    )]
+    ProtocolVersionMismatch { local: u16, remote: u16 },
+    #[error("The other device does not support this feature. It should update Flap.")]
+    UnsupportedByPeer,
+    #[error("Could not read code. Codes look like 7-crow-feather")]
+    CodeParseError,
+    #[error("The code is wrong, or someone else tried to use it. Ask for a new code.")]
+    WrongCode,
+    #[error("No device is waiting for this code")]
+    UnknownCode,
+    #[error("This code has already been used. Ask for a new code.")]
+    CodeAlreadyUsed,
+    #[error("Too many codes are in use at once. Use them before asking for more.")]
+    TooManyCodes,
+    #[error("The code exchange failed")]
+    PakeError(#[from] spake2::Error),
+    #[error("This ticket has expired. Ask for a new ticket.")]
+    TicketExpired,
+    #[error("This ticket has already been used by another device. Ask for a new ticket.")]
+    TicketAlreadyUsed,
+    #[error("A ticket must be usable at least once")]
+    TicketNeverUsable,
+    #[error("The other device declined the transfer")]
+    TransferDeclined,
+    #[error("The transfer was cancelled ({0:?})")]
+    TransferCancelled(CancelReason),
+    #[error("The receiver refused the transfer ({0:?})")]
+    TransferRefused(RefuseReason),
+    #[error("No transfer with this id is running")]
+    UnknownTransfer,
+    #[error("Blocks of a file were sent before the file was started")]
+    FileNotStarted,
+}
+
+// iroh's errors are large, they are boxed to keep every `Result` small.
+macro_rules! boxed_from {
+    ($($variant:ident($err:ty)),* $(,)?) => {
+        $(
+            impl From<$err> for Error {
+                fn from(err: $err) -> Self {
+                    Error::$variant(Box::new(err))
+                }
+            }
+        )*
+    };
+}
+
+boxed_from!(
+    AcceptError(iroh::protocol::AcceptError),
+    ConnectionError(iroh::endpoint::ConnectionError),
+    BindError(iroh::endpoint::BindError),
+    ConnectError(iroh::endpoint::ConnectError),
+    WriteError(iroh::endpoint::WriteError),
+    ReadError(iroh::endpoint::ReadError),
+    ReadExactError(iroh::endpoint::ReadExactError),
+);
+
+/// Errors caused by a peer that does not follow the protocol.
+///
+/// These can be triggered by any remote peer, so they must never panic.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    #[error("unknown frame header {0:#04x}")]
+    UnknownFrame(u8),
+    #[error("expected {expected} frame, received {received} frame")]
+    UnexpectedFrame {
+        expected: &'static str,
+        received: &'static str,
+    },
+    #[error("malformed file metadata")]
+    MalformedMetadata,
+    #[error("file metadata larger than {0} bytes")]
+    OversizedMetadata(usize),
+    #[error("malformed manifest")]
+    MalformedManifest,
+    #[error("malformed file request")]
+    MalformedFileRequest,
+    #[error("truncated payload")]
+    TruncatedPayload,
+    #[error("cannot resume the file from byte {0}")]
+    InvalidResumeOffset(u64),
+    #[error("sent more than the {file_size} bytes announced for the file")]
+    FileOverrun { file_size: u64 },
+    #[error("the file ended after {received} of its {file_size} bytes")]
+    FileTruncated { received: u64, file_size: u64 },
+    #[error("malformed rendezvous message")]
+    MalformedRendezvousMessage,
+}
+
+/// Why a stream was aborted, sent to the peer as the QUIC stream error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Unknown,
+    /// The peer sent something that does not follow the protocol.
+    ProtocolError,
+    /// The peer sent a file name that is not allowed.
+    InvalidFileName,
+    /// The peers could not agree on a protocol version or feature.
+    Incompatible,
+    /// A local filesystem error happened.
+    IoError,
+    /// The file received did not match the hash sent.
+    InvalidHash,
+    /// The ticket can no longer be redeemed.
+    TicketExpired,
+    /// The ticket was redeemed by another device.
+    TicketAlreadyUsed,
+    /// The receiver declined the transfer.
+    Declined,
+    /// Either side cancelled the transfer.
+    Cancelled,
+    /// The receiver refused the file, see [`RefuseReason`].
+    Refused,
+}
+
+impl CloseReason {
+    pub fn to_code(self) -> VarInt {
+        VarInt::from_u32(match self {
+            CloseReason::Unknown => 0,
+            CloseReason::ProtocolError => 1,
+            CloseReason::InvalidFileName => 2,
+            CloseReason::Incompatible => 3,
+            CloseReason::IoError => 4,
+            CloseReason::InvalidHash => 5,
+            CloseReason::TicketExpired => 6,
+            CloseReason::TicketAlreadyUsed => 7,
+            CloseReason::Declined => 8,
+            CloseReason::Cancelled => 9,
+            CloseReason::Refused => 10,
+        })
+    }
+
+    pub fn from_code(code: VarInt) -> Self {
+        match code.into_inner() {
+            1 => CloseReason::ProtocolError,
+            2 => CloseReason::InvalidFileName,
+            3 => CloseReason::Incompatible,
+            4 => CloseReason::IoError,
+            5 => CloseReason::InvalidHash,
+            6 => CloseReason::TicketExpired,
+            7 => CloseReason::TicketAlreadyUsed,
+            8 => CloseReason::Declined,
+            9 => CloseReason::Cancelled,
+            10 => CloseReason::Refused,
+            _ => CloseReason::Unknown,
+        }
+    }
+}
+
+impl From<&Error> for CloseReason {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::Protocol(_) | Error::SnowError(_) => CloseReason::ProtocolError,
+            Error::InvalidFileName(_) => CloseReason::InvalidFileName,
+            Error::ProtocolVersionMismatch { .. } | Error::UnsupportedByPeer => {
+                CloseReason::Incompatible
+            }
+            Error::FileIoError(_)
+            | Error::FileReadError
+            | Error::NoDownloadDir
+            | Error::DownloadDirError(..) => CloseReason::IoError,
+            Error::InvalidBlake3Hash => CloseReason::InvalidHash,
+            Error::TicketExpired => CloseReason::TicketExpired,
+            Error::TicketAlreadyUsed => CloseReason::TicketAlreadyUsed,
+            Error::TransferDeclined => CloseReason::Declined,
+            Error::TransferCancelled(_) => CloseReason::Cancelled,
+            Error::TransferRefused(_) => CloseReason::Refused,
+            _ => CloseReason::Unknown,
+        }
+    }
+}
+
+impl From<CloseReason> for Error {
+    /// Rejected tickets, declined, cancelled and refused transfers are reported
+    /// as such, everything else as an abort.
+    fn from(reason: CloseReason) -> Self {
+        match reason {
+            CloseReason::TicketExpired => Error::TicketExpired,
+            CloseReason::TicketAlreadyUsed => Error::TicketAlreadyUsed,
+            CloseReason::Declined => Error::TransferDeclined,
+            // The reason comes with the cancel frame, if it is read at all
+            CloseReason::Cancelled => Error::TransferCancelled(CancelReason::Unknown),
+            CloseReason::Refused => Error::TransferRefused(RefuseReason::Unknown),
+            reason => Error::ClosedByPeer(reason),
+        }
+    }
 }
@@ -1,17 +1,51 @@
-use std::sync::{Arc, OnceLock};
+use std::{
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+};
 
 use tokio::sync::{Mutex, MutexGuard, mpsc};
 
 #[cfg(feature = "tracing")]
 use tracing::info;
 
-use crate::{crypto::transfer_id::TransferId, fs::metadata::FlapFileMetadata};
+use crate::{
+    crypto::transfer_id::TransferId,
+    fs::metadata::FlapFileMetadata,
+    p2p::{cancel::CancelReason, limits::RefuseReason, manifest::Manifest},
+};
 
 #[derive(Debug)]
 pub enum Event {
     TransferUpdate(TransferId, u64),
     PreparingFile(TransferId, FlapFileMetadata, bool /* sending? */),
     TransferComplete(TransferId),
+    /// The sender offers a file or directory, see [`crate::p2p::receiver::TransferOffer`].
+    TransferOffered(TransferId, FlapFileMetadata),
+    TransferDeclined(TransferId),
+    /// Either side cancelled the transfer.
+    TransferCancelled(TransferId, CancelReason),
+    /// The receiver refused a file of the transfer, which stops there.
+    TransferRefused(TransferId, RefuseReason),
+    /// Either side paused the transfer.
+    TransferPaused(TransferId),
+    /// The side that paused the transfer resumed it.
+    TransferResumed(TransferId),
+    /// The connection to the sender was lost, and the receiver tries reconnecting.
+    Reconnecting(u32 /* attempt */),
+    /// The receiver is connected to the sender again.
+    Reconnected,
+    /// The sender announced files it is about to send, see [`crate::p2p::manifest`].
+    /// Holds every file announced since the receiver connected.
+    ManifestReceived(Manifest),
+    /// The files of a manifest do not fit, and none of them is received.
+    ManifestRefused(RefuseReason),
+    /// Bytes received out of the total size of every announced file.
+    BatchUpdate(u64 /* bytes received */, u64 /* total size */),
+    /// A received file has the name of another file, see [`crate::p2p::receiver::FileConflict`].
+    FileConflict(
+        TransferId,
+        PathBuf, /* relative to the download directory */
+    ),
 }
 
 static EVENT_HANDLER: OnceLock<EventHandler> = OnceLock::new();
@@ -49,3 +83,9 @@ impl EventHandler {
         self.receiver.lock().await
     }
 }
+
+impl Default for EventHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
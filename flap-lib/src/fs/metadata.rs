@@ -1,57 +1,274 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use tokio::fs::metadata;
+use tokio::fs::{metadata, read_dir, symlink_metadata};
+
+use crate::error::{Error, ProtocolError, Result};
 
 pub const MAX_METADATA_LENGTH_ALLOWED: u64 = 1 << 13; // 8kB max
 
+/// How deep a directory tree sent over the wire may be nested.
+/// Protects the decoder from recursing forever on hostile input.
+pub const MAX_DIR_DEPTH: usize = 64;
+
+const KIND_FILE: u8 = 0x00;
+const KIND_DIR: u8 = 0x01;
+
 /// Basic file metadata structure.
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct FlapFileMetadata {
-    // TODO: We will allow dirs in the duture
     pub(crate) is_file: bool,
     // If this is a directory, list files inside directory
-    // We only support files for now
     pub(crate) dir_file_entries: Option<Vec<FlapFileMetadata>>,
+    /// For directories, this is the total size of every file inside of it.
     pub file_size: u64,
     pub file_name: String,
 }
 
 impl FlapFileMetadata {
-    pub async fn from_path(file_path: &PathBuf) -> Self {
-        let metadata = metadata(file_path).await.unwrap();
+    /// Reads the metadata of the file or directory at `file_path`.
+    ///
+    /// Directories are walked recursively. Entries are sorted by name so
+    /// that both peers agree on the order in which files are transferred.
+    /// Symbolic links are skipped.
+    pub async fn from_path(file_path: &PathBuf) -> Result<Self> {
+        let metadata = metadata(file_path)
+            .await
+            .map_err(|_| Error::FileReadError)?;
+        let file_name = file_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(Error::FileReadError)?
+            .to_string();
 
-        Self {
-            is_file: metadata.is_file(),
-            dir_file_entries: None,
-            // TODO: bruh
-            file_name: file_path
-                .file_name()
-                .unwrap()
-                .to_os_string()
-                .into_string()
-                .unwrap(),
-            file_size: metadata.len(),
+        if metadata.is_dir() {
+            let entries = Self::read_dir_entries(file_path).await?;
+            let file_size = entries.iter().map(|entry| entry.file_size).sum();
+
+            Ok(Self {
+                is_file: false,
+                dir_file_entries: Some(entries),
+                file_size,
+                file_name,
+            })
+        } else {
+            Ok(Self {
+                is_file: true,
+                dir_file_entries: None,
+                file_size: metadata.len(),
+                file_name,
+            })
         }
     }
 
-    pub async fn from_bytes(mut bytes: Bytes) -> Self {
+    async fn read_dir_entries(dir_path: &Path) -> Result<Vec<Self>> {
+        let mut paths = Vec::new();
+        let mut dir = read_dir(dir_path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            if symlink_metadata(entry.path()).await?.is_symlink() {
+                continue;
+            }
+            paths.push(entry.path());
+        }
+        paths.sort();
+
+        let mut entries = Vec::with_capacity(paths.len());
+        for path in paths {
+            entries.push(Box::pin(Self::from_path(&path)).await?);
+        }
+
+        Ok(entries)
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.is_file
+    }
+
+    pub fn dir_file_entries(&self) -> Option<&[FlapFileMetadata]> {
+        self.dir_file_entries.as_deref()
+    }
+
+    /// Every file contained in this metadata, in transfer order, along with
+    /// its path relative to the parent of this file or directory.
+    ///
+    /// For a single file, this is only the file itself.
+    pub fn files(&self) -> Vec<(PathBuf, &FlapFileMetadata)> {
+        let mut files = Vec::new();
+        self.collect_files(PathBuf::new(), &mut files);
+
+        files
+    }
+
+    fn collect_files<'a>(&'a self, parent: PathBuf, files: &mut Vec<(PathBuf, &'a Self)>) {
+        let path = parent.join(&self.file_name);
+        match &self.dir_file_entries {
+            Some(entries) => {
+                for entry in entries {
+                    entry.collect_files(path.clone(), files);
+                }
+            }
+            None => files.push((path, self)),
+        }
+    }
+
+    pub fn from_bytes(mut bytes: Bytes) -> Result<Self> {
+        let metadata = Self::decode(&mut bytes, 0)?;
+
+        if bytes.has_remaining() {
+            return Err(ProtocolError::MalformedMetadata.into());
+        }
+
+        Ok(metadata)
+    }
+
+    /// [(u8)(u64)(u16)(name)]
+    /// (u8) is the kind of entry (file or directory)
+    /// (u64) is the file size
+    /// (u16) is the length of the name that follows
+    /// Directories are then followed by [(u32)(entries...)]
+    fn decode(bytes: &mut Bytes, depth: usize) -> Result<Self> {
+        if bytes.remaining() < size_of::<u8>() + size_of::<u64>() + size_of::<u16>() {
+            return Err(ProtocolError::MalformedMetadata.into());
+        }
+
+        let kind = bytes.get_u8();
         let file_size = bytes.get_u64();
-        let file_name = String::from_utf8(bytes.to_vec()).unwrap();
+        let name_len = bytes.get_u16() as usize;
+        if bytes.remaining() < name_len {
+            return Err(ProtocolError::MalformedMetadata.into());
+        }
+        let file_name = String::from_utf8(bytes.split_to(name_len).to_vec())
+            .map_err(|_| ProtocolError::MalformedMetadata)?;
 
-        Self {
-            is_file: true,
-            dir_file_entries: None,
-            file_size,
-            file_name,
+        match kind {
+            KIND_FILE => Ok(Self {
+                is_file: true,
+                dir_file_entries: None,
+                file_size,
+                file_name,
+            }),
+            KIND_DIR => {
+                if depth >= MAX_DIR_DEPTH || bytes.remaining() < size_of::<u32>() {
+                    return Err(ProtocolError::MalformedMetadata.into());
+                }
+                let entry_count = bytes.get_u32();
+
+                let mut entries = Vec::new();
+                for _ in 0..entry_count {
+                    entries.push(Self::decode(bytes, depth + 1)?);
+                }
+
+                Ok(Self {
+                    is_file: false,
+                    dir_file_entries: Some(entries),
+                    file_size,
+                    file_name,
+                })
+            }
+            _ => Err(ProtocolError::MalformedMetadata.into()),
         }
     }
 
     pub fn to_bytes(&self) -> Bytes {
         let mut metadata_bytes = BytesMut::new();
+        self.encode(&mut metadata_bytes);
+
+        metadata_bytes.into()
+    }
+
+    fn encode(&self, metadata_bytes: &mut BytesMut) {
+        let kind = if self.is_file { KIND_FILE } else { KIND_DIR };
+
+        metadata_bytes.put_u8(kind);
         metadata_bytes.put_u64(self.file_size);
+        metadata_bytes.put_u16(self.file_name.len() as u16);
         metadata_bytes.put_slice(self.file_name.as_bytes());
 
-        metadata_bytes.into()
+        if let Some(entries) = &self.dir_file_entries {
+            metadata_bytes.put_u32(entries.len() as u32);
+            for entry in entries {
+                entry.encode(metadata_bytes);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn file(name: &str, file_size: u64) -> FlapFileMetadata {
+        FlapFileMetadata {
+            is_file: true,
+            dir_file_entries: None,
+            file_size,
+            file_name: name.to_string(),
+        }
+    }
+
+    fn dir(name: &str, entries: Vec<FlapFileMetadata>) -> FlapFileMetadata {
+        FlapFileMetadata {
+            is_file: false,
+            file_size: entries.iter().map(|entry| entry.file_size).sum(),
+            dir_file_entries: Some(entries),
+            file_name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn nested_dir_roundtrip() {
+        let metadata = dir(
+            "album",
+            vec![
+                file("a.jpg", 10),
+                dir("empty", vec![]),
+                dir("sub", vec![file("b.jpg", 20), file("c.jpg", 0)]),
+            ],
+        );
+
+        let roundtrip = FlapFileMetadata::from_bytes(metadata.to_bytes()).unwrap();
+        assert_eq!(roundtrip, metadata);
+        assert_eq!(roundtrip.file_size, 30);
+
+        let files: Vec<PathBuf> = roundtrip.files().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("album/a.jpg"),
+                PathBuf::from("album/sub/b.jpg"),
+                PathBuf::from("album/sub/c.jpg"),
+            ]
+        );
+    }
+
+    /// Arbitrary, possibly nested, metadata that fits in a single frame.
+    pub(crate) fn arb_metadata() -> impl Strategy<Value = FlapFileMetadata> {
+        let leaf = ("\\PC{1,32}", any::<u64>()).prop_map(|(name, size)| file(&name, size));
+
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            ("\\PC{1,32}", prop::collection::vec(inner, 0..8)).prop_map(|(name, entries)| {
+                FlapFileMetadata {
+                    is_file: false,
+                    dir_file_entries: Some(entries),
+                    file_size: 0,
+                    file_name: name,
+                }
+            })
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn metadata_roundtrip(metadata in arb_metadata()) {
+            let roundtrip = FlapFileMetadata::from_bytes(metadata.to_bytes()).unwrap();
+            prop_assert_eq!(roundtrip, metadata);
+        }
+
+        #[test]
+        fn decoding_arbitrary_metadata_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+            let _ = FlapFileMetadata::from_bytes(bytes.into());
+        }
     }
 }
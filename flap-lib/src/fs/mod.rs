@@ -1,2 +1,4 @@
 pub mod metadata;
+pub mod partial;
+pub mod sanitize;
 pub mod save;
@@ -0,0 +1,98 @@
+//! State kept next to each partial `.flap` file.
+//!
+//! A partial file only tells how many bytes were written, not which file
+//! they belong to. The state records which file is being received, so that
+//! a transfer only resumes into the partial file of the same file.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::crypto::{blake3::FileHash, transfer_id::TransferId};
+
+/// Version of the encoding below, bumped when it changes.
+const PARTIAL_STATE_VERSION: u8 = 2;
+
+/// What is known of the file a partial `.flap` file belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialState {
+    /// The transfer that last wrote to the partial file.
+    pub transfer_id: TransferId,
+    pub file_size: u64,
+    /// The hash announced by the sender, see [`crate::crypto::blake3`].
+    pub root_hash: FileHash,
+    /// How many bytes at the start of the partial file were checked against `root_hash`.
+    /// Bytes past this may have been written, but are received again.
+    pub bytes_verified: u64,
+}
+
+impl PartialState {
+    /// Whether both states are about the same file, no matter which sender,
+    /// which transfer or how far along.
+    pub fn is_same_file(&self, other: &Self) -> bool {
+        self.file_size == other.file_size && self.root_hash == other.root_hash
+    }
+
+    /// [(u8)(transfer id)(u64)(root hash)(u64)]
+    /// (u8) is the version of the encoding
+    /// The first (u64) is the file size, the second is how many bytes were verified
+    pub fn to_bytes(&self) -> Bytes {
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(PARTIAL_STATE_VERSION);
+        bytes.put_slice(&self.transfer_id.0);
+        bytes.put_u64(self.file_size);
+        bytes.put_slice(&self.root_hash);
+        bytes.put_u64(self.bytes_verified);
+
+        bytes.into()
+    }
+
+    /// Decodes a state written by [`Self::to_bytes`]. Anything else, such as a
+    /// state left by another version of Flap, is `None`.
+    pub fn from_bytes(mut bytes: Bytes) -> Option<Self> {
+        const LEN: usize = size_of::<u8>() + 32 + size_of::<u64>() + 32 + size_of::<u64>();
+        if bytes.len() != LEN || bytes.get_u8() != PARTIAL_STATE_VERSION {
+            return None;
+        }
+
+        let transfer_id = TransferId(bytes.split_to(32).as_ref().try_into().ok()?);
+        let file_size = bytes.get_u64();
+        let root_hash = bytes.split_to(32).as_ref().try_into().ok()?;
+        let bytes_verified = bytes.get_u64();
+
+        Some(Self {
+            transfer_id,
+            file_size,
+            root_hash,
+            bytes_verified,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_state_roundtrip() {
+        let state = PartialState {
+            transfer_id: TransferId([1; 32]),
+            file_size: 1 << 40,
+            root_hash: [2; 32],
+            bytes_verified: 1 << 20,
+        };
+
+        let roundtrip = PartialState::from_bytes(state.to_bytes()).unwrap();
+        assert_eq!(roundtrip, state);
+        assert!(roundtrip.is_same_file(&PartialState {
+            transfer_id: TransferId([3; 32]),
+            bytes_verified: 0,
+            ..state.clone()
+        }));
+        assert!(!roundtrip.is_same_file(&PartialState {
+            root_hash: [3; 32],
+            ..state.clone()
+        }));
+
+        assert!(PartialState::from_bytes(Bytes::new()).is_none());
+        assert!(PartialState::from_bytes(state.to_bytes().slice(1..)).is_none());
+    }
+}
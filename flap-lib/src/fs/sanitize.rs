@@ -0,0 +1,219 @@
+//! Validation of file names received from a peer.
+//!
+//! Every name inside a [`FlapFileMetadata`] comes from the sender and must be
+//! treated as hostile: it is only ever allowed to name a single entry inside
+//! of the download directory.
+
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    error::{Error, Result},
+    fs::metadata::FlapFileMetadata,
+};
+
+/// Most filesystems limit a single path component to 255 bytes.
+pub const MAX_FILE_NAME_LENGTH: usize = 255;
+
+/// Names that Windows refuses to create, regardless of their extension.
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters that are either path separators or forbidden on Windows.
+const FORBIDDEN_CHARACTERS: &[char] = &['/', '\\', '<', '>', ':', '"', '|', '?', '*'];
+
+/// Suffixes of the files kept next to a file while it is received, see
+/// [`crate::fs::save::FileSaver`].
+const RESERVED_SUFFIXES: &[&str] = &[".flap", ".flap.state"];
+
+/// Checks that `file_name` is a single, plain path component that is safe
+/// to create on every platform Flap supports.
+pub fn validate_file_name(file_name: &str) -> Result<()> {
+    let invalid = || Error::InvalidFileName(file_name.to_string());
+
+    if file_name.is_empty() || file_name == "." || file_name == ".." {
+        return Err(invalid());
+    }
+
+    if file_name.len() > MAX_FILE_NAME_LENGTH {
+        return Err(invalid());
+    }
+
+    if file_name
+        .chars()
+        .any(|c| c.is_control() || FORBIDDEN_CHARACTERS.contains(&c))
+    {
+        return Err(invalid());
+    }
+
+    // Windows silently strips these, so `a.txt.` would become `a.txt`
+    if file_name.ends_with('.') || file_name.ends_with(' ') {
+        return Err(invalid());
+    }
+
+    // Would be mistaken for the partial file of another file, or for its state
+    let lowercase = file_name.to_lowercase();
+    if RESERVED_SUFFIXES
+        .iter()
+        .any(|suffix| lowercase.ends_with(suffix))
+    {
+        return Err(invalid());
+    }
+
+    let stem = file_name.split('.').next().unwrap_or(file_name);
+    if WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        return Err(invalid());
+    }
+
+    Ok(())
+}
+
+/// Validates every name in `metadata`, including all nested directory entries.
+///
+/// Entries of a directory must also have unique names, otherwise two
+/// entries would be written to the same path.
+pub fn validate_metadata(metadata: &FlapFileMetadata) -> Result<()> {
+    validate_file_name(&metadata.file_name)?;
+
+    if let Some(entries) = &metadata.dir_file_entries {
+        let mut names = HashSet::with_capacity(entries.len());
+        for entry in entries {
+            if !names.insert(entry.file_name.to_lowercase()) {
+                return Err(Error::InvalidFileName(entry.file_name.clone()));
+            }
+            validate_metadata(entry)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Joins `relative_path` onto `base`, making sure that the resulting path
+/// stays inside of `base`.
+pub fn safe_join(base: &Path, relative_path: &Path) -> Result<PathBuf> {
+    let invalid = || Error::InvalidFileName(relative_path.to_string_lossy().into_owned());
+
+    let mut path = base.to_path_buf();
+    let mut components = 0;
+    for component in relative_path.components() {
+        match component {
+            Component::Normal(name) => {
+                validate_file_name(name.to_str().ok_or_else(invalid)?)?;
+                path.push(name);
+                components += 1;
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    if components == 0 {
+        return Err(invalid());
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTILE_NAMES: &[&str] = &[
+        "",
+        ".",
+        "..",
+        "../../.bashrc",
+        "..\\..\\evil.exe",
+        "/etc/passwd",
+        "C:\\Windows\\System32",
+        "C:evil",
+        "a/b",
+        "nul\0byte",
+        "new\nline",
+        "CON",
+        "con.txt",
+        "LPT1.log",
+        "Aux",
+        "trailing.",
+        "trailing ",
+        "file.txt:stream",
+        "what?",
+        "star*",
+        "pipe|",
+        "movie.mkv.flap",
+        "movie.mkv.FLAP.state",
+    ];
+
+    #[test]
+    fn rejects_hostile_names() {
+        for name in HOSTILE_NAMES {
+            assert!(
+                matches!(validate_file_name(name), Err(Error::InvalidFileName(_))),
+                "{name:?} should be rejected"
+            );
+        }
+
+        assert!(validate_file_name(&"a".repeat(MAX_FILE_NAME_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn accepts_regular_names() {
+        for name in [
+            "a",
+            "photo.jpg",
+            ".bashrc",
+            "archive.tar.gz",
+            "My Document (1).pdf",
+            "console.log",
+            "flap.txt",
+            "日本語.txt",
+        ] {
+            assert!(validate_file_name(name).is_ok(), "{name:?} should be valid");
+        }
+    }
+
+    #[test]
+    fn rejects_hostile_nested_entries() {
+        let file = |name: &str| FlapFileMetadata {
+            is_file: true,
+            dir_file_entries: None,
+            file_size: 0,
+            file_name: name.to_string(),
+        };
+        let dir = |name: &str, entries| FlapFileMetadata {
+            is_file: false,
+            dir_file_entries: Some(entries),
+            file_size: 0,
+            file_name: name.to_string(),
+        };
+
+        assert!(validate_metadata(&dir("album", vec![file("a.jpg"), file("b.jpg")])).is_ok());
+        assert!(validate_metadata(&dir("album", vec![file("..")])).is_err());
+        assert!(validate_metadata(&dir("album", vec![dir("sub", vec![file("../x")])])).is_err());
+        assert!(
+            validate_metadata(&dir("album", vec![file("a.jpg"), dir("A.JPG", vec![])])).is_err()
+        );
+        assert!(validate_metadata(&dir("album", vec![file("a.jpg"), file("a.jpg.flap")])).is_err());
+        assert!(validate_metadata(&dir("..", vec![])).is_err());
+    }
+
+    #[test]
+    fn safe_join_stays_inside_base() {
+        let base = Path::new("/downloads");
+
+        assert_eq!(
+            safe_join(base, Path::new("album/a.jpg")).unwrap(),
+            PathBuf::from("/downloads/album/a.jpg")
+        );
+        assert!(safe_join(base, Path::new("")).is_err());
+        assert!(safe_join(base, Path::new("../a.jpg")).is_err());
+        assert!(safe_join(base, Path::new("album/../../a.jpg")).is_err());
+        assert!(safe_join(base, Path::new("/etc/passwd")).is_err());
+    }
+}
@@ -1,19 +1,41 @@
 use std::{
-    io::{ErrorKind, SeekFrom},
-    path::PathBuf,
+    io::ErrorKind,
+    path::{Path, PathBuf},
 };
 
-use tokio::{
-    fs::{self, DirBuilder, File},
-    io::AsyncSeekExt,
-};
+use tokio::fs::{self, DirBuilder, File};
 
 use crate::{
-    crypto::blake3::Blake3,
+    crypto::encryption_stream::FILE_BLOCK_SIZE,
     error::{Error, Result},
-    fs::metadata::FlapFileMetadata,
+    fs::{metadata::FlapFileMetadata, partial::PartialState, sanitize::safe_join},
 };
 
+#[cfg(feature = "tracing")]
+use tracing::info;
+
+/// What happens to the partial `.flap` file of a cancelled transfer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PartialFilePolicy {
+    /// Keep it, so that the transfer can resume later.
+    #[default]
+    Keep,
+    Delete,
+}
+
+/// What happens to a received file whose name is taken by another file,
+/// either a finished one or the partial file of another file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Replace the other file.
+    Overwrite,
+    /// Save it as `name (1).ext`, or the first number that is free.
+    #[default]
+    Rename,
+    /// Keep the other file, and do not receive this one.
+    Skip,
+}
+
 #[derive(Debug, Clone)]
 pub struct FileSaver {
     /// The directory in which received files are written to.
@@ -21,67 +43,217 @@ pub struct FileSaver {
 }
 
 impl FileSaver {
-    pub async fn new() -> Self {
-        let download_dir = dirs::download_dir()
-            .expect("download exists because Flap is used on a supported OS")
-            .join("Flap Downloads");
-
-        match DirBuilder::new().create(&download_dir).await {
-            Ok(_) => {}
-            Err(err) => match err.kind() {
-                ErrorKind::AlreadyExists => {}
-                _ => {
-                    panic!(
-                        "Flap does not have filesystem permissions, or there is a file at the path, or the filesystem returned a critical error"
-                    )
-                }
-            },
+    /// Saves files in [`Self::default_download_dir`].
+    pub async fn new() -> Result<Self> {
+        Self::with_download_dir(Self::default_download_dir()?).await
+    }
+
+    /// Saves files in `download_dir`, creating it if needed.
+    pub async fn with_download_dir(download_dir: PathBuf) -> Result<Self> {
+        DirBuilder::new()
+            .recursive(true)
+            .create(&download_dir)
+            .await
+            .map_err(|err| Error::DownloadDirError(download_dir.clone(), err))?;
+
+        Ok(Self { download_dir })
+    }
+
+    /// How many bytes can still be written to the disk of the download directory.
+    pub async fn available_space(&self) -> Result<u64> {
+        let download_dir = self.download_dir.clone();
+        let available_space =
+            tokio::task::spawn_blocking(move || fs4::available_space(download_dir))
+                .await
+                .map_err(|_| Error::FileReadError)??;
+
+        Ok(available_space)
+    }
+
+    /// `Flap Downloads`, in the user's download directory.
+    pub fn default_download_dir() -> Result<PathBuf> {
+        let download_dir = dirs::download_dir().ok_or(Error::NoDownloadDir)?;
+
+        Ok(download_dir.join("Flap Downloads"))
+    }
+
+    /// Creates the directory described by `metadata` and every directory
+    /// nested inside of it. Files are created later by [`Self::prepare_file`].
+    pub async fn prepare_dir(&self, metadata: &FlapFileMetadata) -> Result<()> {
+        self.create_dir_tree(self.download_dir.clone(), metadata)
+            .await
+    }
+
+    async fn create_dir_tree(&self, parent: PathBuf, metadata: &FlapFileMetadata) -> Result<()> {
+        if let Some(entries) = &metadata.dir_file_entries {
+            let dir_path = safe_join(&parent, Path::new(&metadata.file_name))?;
+            DirBuilder::new().recursive(true).create(&dir_path).await?;
+
+            for entry in entries {
+                Box::pin(self.create_dir_tree(dir_path.clone(), entry)).await?;
+            }
         }
 
-        Self { download_dir }
+        Ok(())
     }
 
+    /// Opens the partial `.flap` file for the file at `relative_path`
+    /// (as given by [`FlapFileMetadata::files`]), described by `state`.
+    ///
+    /// If a partial file of the same file already exists, returns the state
+    /// with how many bytes of it are kept so that the transfer can resume.
+    /// Only whole blocks that were verified are kept, since each block is
+    /// checked against the file's hash on its own.
+    ///
+    /// A partial file of another file, or one that is not known, is started
+    /// over. See [`Self::is_taken`] to keep it instead.
     pub async fn prepare_file(
         &self,
-        metadata: &FlapFileMetadata,
-    ) -> Result<(File, u64, Option<Blake3>)> {
-        let mut file_name = metadata.file_name.clone();
-        file_name.push_str(".flap");
-
-        let file_path = self.download_dir.join(file_name);
-        match File::create_new(&file_path).await {
-            Ok(file) => Ok((file, 0, None)),
-            Err(e) => {
-                if matches!(e.kind(), ErrorKind::AlreadyExists) {
-                    // Open with seek
-                    let mut file = File::options()
-                        .append(true)
-                        .read(true)
-                        .open(file_path)
-                        .await?;
-                    let file_len = file.metadata().await?.len();
-                    let hasher = Blake3::partial_hash(&mut file, None).await?;
-
-                    file.seek(SeekFrom::Start(file_len)).await?;
-
-                    Ok((file, file_len, Some(hasher)))
-                } else {
-                    Err(Error::FileIoError(e))
-                }
+        relative_path: &Path,
+        mut state: PartialState,
+    ) -> Result<(File, PartialState)> {
+        let file_path = self.partial_file_path(relative_path)?;
+        state.bytes_verified = 0;
+
+        let file = match File::create_new(&file_path).await {
+            Ok(file) => file,
+            Err(e) if matches!(e.kind(), ErrorKind::AlreadyExists) => {
+                // Blocks are appended, whichever part of the file was read last
+                let file = File::options()
+                    .append(true)
+                    .read(true)
+                    .open(file_path)
+                    .await?;
+
+                let file_len = file.metadata().await?.len();
+                state.bytes_verified = self.kept_len(relative_path, &state, file_len).await;
+                file.set_len(state.bytes_verified).await?;
+
+                file
+            }
+            Err(e) => return Err(Error::FileIoError(e)),
+        };
+        self.save_state(relative_path, &state).await?;
+
+        Ok((file, state))
+    }
+
+    /// How many bytes [`Self::prepare_file`] would keep from the partial file
+    /// of the file described by `state`, without creating or changing anything.
+    pub async fn resumable_len(&self, relative_path: &Path, state: &PartialState) -> Result<u64> {
+        let file_len = match fs::metadata(self.partial_file_path(relative_path)?).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound) => return Ok(0),
+            Err(e) => return Err(Error::FileIoError(e)),
+        };
+
+        Ok(self.kept_len(relative_path, state, file_len).await)
+    }
+
+    /// How many of the `file_len` bytes of a partial file are kept to resume
+    /// the file described by `state`.
+    async fn kept_len(&self, relative_path: &Path, state: &PartialState, file_len: u64) -> u64 {
+        match self.load_state(relative_path).await {
+            Some(saved) if saved.is_same_file(state) => {
+                let kept_len = file_len.min(saved.bytes_verified);
+                kept_len - kept_len % FILE_BLOCK_SIZE as u64
+            }
+            _ => {
+                #[cfg(feature = "tracing")]
+                info!("Partial file is from another file, starting over");
+
+                0
             }
         }
     }
 
-    pub async fn finish_file(&self, metadata: &FlapFileMetadata) -> Result<()> {
-        let file_name = metadata.file_name.clone();
-        let mut file_name_with_flap_ext = metadata.file_name.clone();
-        file_name_with_flap_ext.push_str(".flap");
+    /// Whether the file described by `state` would replace another file if
+    /// saved at `relative_path`: a finished file, or the partial file of another file.
+    pub async fn is_taken(&self, relative_path: &Path, state: &PartialState) -> Result<bool> {
+        if fs::try_exists(safe_join(&self.download_dir, relative_path)?).await? {
+            return Ok(true);
+        }
+        if !fs::try_exists(self.partial_file_path(relative_path)?).await? {
+            return Ok(false);
+        }
 
-        let file_path = self.download_dir.join(file_name);
-        let file_path_with_ext = self.download_dir.join(file_name_with_flap_ext);
+        Ok(!self
+            .load_state(relative_path)
+            .await
+            .is_some_and(|saved| saved.is_same_file(state)))
+    }
 
-        fs::rename(file_path_with_ext, file_path).await?;
+    /// The first `name (n).ext` next to `relative_path` where the file described
+    /// by `state` can be saved, see [`Self::is_taken`].
+    pub async fn free_path(&self, relative_path: &Path, state: &PartialState) -> Result<PathBuf> {
+        let file_name = Path::new(relative_path.file_name().ok_or(Error::FileReadError)?);
+        let stem = file_name.file_stem().unwrap_or_default().to_string_lossy();
+
+        let mut n = 1u64;
+        loop {
+            let numbered = match file_name.extension() {
+                Some(extension) => format!("{stem} ({n}).{}", extension.to_string_lossy()),
+                None => format!("{stem} ({n})"),
+            };
+            let candidate = relative_path.with_file_name(numbered);
+            if !self.is_taken(&candidate, state).await? {
+                return Ok(candidate);
+            }
+            n += 1;
+        }
+    }
+
+    /// Records how far along the partial file for the file at `relative_path` is.
+    pub async fn save_state(&self, relative_path: &Path, state: &PartialState) -> Result<()> {
+        fs::write(self.state_file_path(relative_path)?, state.to_bytes()).await?;
 
         Ok(())
     }
+
+    /// The state of the partial file for the file at `relative_path`, if it has a valid one.
+    async fn load_state(&self, relative_path: &Path) -> Option<PartialState> {
+        let bytes = fs::read(self.state_file_path(relative_path).ok()?)
+            .await
+            .ok()?;
+
+        PartialState::from_bytes(bytes.into())
+    }
+
+    /// Moves the partial file for the file at `relative_path` to `saved_as`,
+    /// replacing whatever is there.
+    pub async fn finish_file(&self, relative_path: &Path, saved_as: &Path) -> Result<()> {
+        let file_path = safe_join(&self.download_dir, saved_as)?;
+        let file_path_with_ext = self.partial_file_path(relative_path)?;
+
+        fs::rename(file_path_with_ext, file_path).await?;
+        Self::remove_if_exists(&self.state_file_path(relative_path)?).await
+    }
+
+    /// Removes the partial file for the file at `relative_path`, if there is one.
+    pub async fn discard_partial(&self, relative_path: &Path) -> Result<()> {
+        Self::remove_if_exists(&self.partial_file_path(relative_path)?).await?;
+        Self::remove_if_exists(&self.state_file_path(relative_path)?).await
+    }
+
+    async fn remove_if_exists(path: &Path) -> Result<()> {
+        match fs::remove_file(path).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(Error::FileIoError(err)),
+            _ => Ok(()),
+        }
+    }
+
+    fn partial_file_path(&self, relative_path: &Path) -> Result<PathBuf> {
+        let mut file_path = safe_join(&self.download_dir, relative_path)?.into_os_string();
+        file_path.push(".flap");
+
+        Ok(file_path.into())
+    }
+
+    /// Where the [`PartialState`] of the partial file is kept, right next to it.
+    fn state_file_path(&self, relative_path: &Path) -> Result<PathBuf> {
+        let mut file_path = self.partial_file_path(relative_path)?.into_os_string();
+        file_path.push(".state");
+
+        Ok(file_path.into())
+    }
 }
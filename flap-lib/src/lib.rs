@@ -1,3 +1,4 @@
+pub mod code;
 pub mod crypto;
 pub mod error;
 pub mod event;
@@ -0,0 +1,119 @@
+//! Cancelling transfers from either side.
+//!
+//! The side that cancels sends a [`crate::p2p::frame::Frame::Cancel`] with the
+//! reason, if the peer supports it, and closes the stream with
+//! [`crate::error::CloseReason::Cancelled`]. A peer that does not read the frame still
+//! learns of the cancellation from the stream being closed.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::watch;
+
+use crate::{
+    crypto::transfer_id::TransferId,
+    error::{Error, Result},
+};
+
+/// Why a transfer was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelReason {
+    /// The peer did not say why, or gave a reason we do not know of.
+    Unknown,
+    /// The user does not want the file anymore.
+    UserRequested,
+    /// The app is closing. The transfer may be resumed later.
+    ShuttingDown,
+}
+
+impl CancelReason {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            CancelReason::Unknown => 0,
+            CancelReason::UserRequested => 1,
+            CancelReason::ShuttingDown => 2,
+        }
+    }
+
+    pub fn from_u8(reason: u8) -> Self {
+        match reason {
+            1 => CancelReason::UserRequested,
+            2 => CancelReason::ShuttingDown,
+            _ => CancelReason::Unknown,
+        }
+    }
+}
+
+type Transfers = Arc<Mutex<HashMap<TransferId, watch::Sender<Option<CancelReason>>>>>;
+
+/// The running transfers of a sender or a receiver, which can be cancelled.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Cancellations {
+    transfers: Transfers,
+}
+
+impl Cancellations {
+    /// The transfer can be cancelled until the returned token is dropped.
+    pub fn register(&self, transfer_id: TransferId) -> CancelToken {
+        let (cancel_tx, cancel_rx) = watch::channel(None);
+        self.transfers
+            .lock()
+            .expect("lock is not poisoned")
+            .insert(transfer_id, cancel_tx);
+
+        CancelToken {
+            transfer_id,
+            cancel_rx,
+            transfers: self.transfers.clone(),
+        }
+    }
+
+    pub fn cancel(&self, transfer_id: TransferId, reason: CancelReason) -> Result<()> {
+        let transfers = self.transfers.lock().expect("lock is not poisoned");
+        let cancel_tx = transfers.get(&transfer_id).ok_or(Error::UnknownTransfer)?;
+        cancel_tx.send_replace(Some(reason));
+
+        Ok(())
+    }
+}
+
+/// Lets a running transfer know it was cancelled.
+#[derive(Debug)]
+pub(crate) struct CancelToken {
+    transfer_id: TransferId,
+    cancel_rx: watch::Receiver<Option<CancelReason>>,
+    transfers: Transfers,
+}
+
+impl CancelToken {
+    /// Why the transfer was cancelled, if it was.
+    pub fn reason(&self) -> Option<CancelReason> {
+        *self.cancel_rx.borrow()
+    }
+
+    /// Resolves once the transfer is cancelled.
+    pub async fn cancelled(&mut self) -> CancelReason {
+        let reason = self
+            .cancel_rx
+            .wait_for(Option::is_some)
+            .await
+            .map(|reason| reason.expect("waited for a reason"));
+
+        match reason {
+            Ok(reason) => reason,
+            // The transfer can no longer be cancelled
+            Err(_) => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for CancelToken {
+    fn drop(&mut self) {
+        self.transfers
+            .lock()
+            .expect("lock is not poisoned")
+            .remove(&self.transfer_id);
+    }
+}
@@ -1,20 +1,94 @@
-use std::ops::Deref;
+use std::{net::SocketAddr, ops::Deref};
 
+pub use iroh::RelayUrl;
+use iroh::{RelayMap, RelayMode};
 #[cfg(feature = "tracing")]
 use tracing::warn;
 
 use crate::{error::Result, p2p::ALPN};
 
+/// Published by senders along with their addresses, so that receivers can tell
+/// them apart from the other iroh nodes found on the local network.
+pub(crate) const SENDER_USER_DATA: &str = "flap-sender";
+
+/// How peers find each other's addresses from their node id.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DiscoveryMode {
+    /// Publish and resolve addresses through the n0 DNS service.
+    #[default]
+    N0,
+    /// Find peers on the local network through multicast DNS.
+    /// Nothing is published outside of the local network.
+    LocalNetwork,
+    /// No discovery at all. The receiver must already know the sender's
+    /// addresses, or reach it through a relay.
+    Disabled,
+}
+
+/// Which relay servers are used when a direct connection cannot be made.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum RelayConfig {
+    /// The relays run by n0.
+    #[default]
+    Default,
+    /// A self-hosted relay.
+    Custom(RelayUrl),
+    /// Direct connections only.
+    Disabled,
+}
+
+/// Configuration of a [`P2pEndpoint`].
+///
+/// The default talks to the n0 discovery service and relays, like Flap always has.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct P2pEndpointConfig {
+    pub discovery: DiscoveryMode,
+    pub relay: RelayConfig,
+    /// Address to listen on. A random port on every interface if unset.
+    pub bind_addr: Option<SocketAddr>,
+}
+
+impl P2pEndpointConfig {
+    /// Only listens on localhost, without relays or discovery.
+    ///
+    /// Peers are only reachable through the direct addresses of their ticket.
+    #[cfg(test)]
+    pub(crate) fn loopback() -> Self {
+        Self {
+            discovery: DiscoveryMode::Disabled,
+            relay: RelayConfig::Disabled,
+            bind_addr: Some(SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, 0))),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct P2pEndpoint(iroh::Endpoint);
 
 impl P2pEndpoint {
-    pub async fn start() -> Result<Self> {
-        let endpoint = iroh::Endpoint::builder()
-            .alpns(vec![ALPN.to_vec()])
-            .discovery_n0()
-            .bind()
-            .await?;
+    pub async fn start(config: &P2pEndpointConfig) -> Result<Self> {
+        let mut builder = iroh::Endpoint::builder().alpns(vec![ALPN.to_vec()]);
+
+        builder = match config.discovery {
+            DiscoveryMode::N0 => builder.discovery_n0(),
+            DiscoveryMode::LocalNetwork => builder.discovery_local_network(),
+            DiscoveryMode::Disabled => builder.clear_discovery(),
+        };
+
+        builder = builder.relay_mode(match &config.relay {
+            RelayConfig::Default => RelayMode::Default,
+            RelayConfig::Custom(relay_url) => RelayMode::Custom(RelayMap::from(relay_url.clone())),
+            RelayConfig::Disabled => RelayMode::Disabled,
+        });
+
+        // The other address family keeps listening on a random port
+        builder = match config.bind_addr {
+            Some(SocketAddr::V4(addr)) => builder.bind_addr_v4(addr),
+            Some(SocketAddr::V6(addr)) => builder.bind_addr_v6(addr),
+            None => builder,
+        };
+
+        let endpoint = builder.bind().await?;
 
         Ok(Self(endpoint))
     }
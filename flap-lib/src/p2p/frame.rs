@@ -2,42 +2,104 @@ use bytes::{Buf, BufMut, Bytes};
 
 use crate::{
     crypto::{blake3::FileHash, encryption_stream::MAX_NOISE_MESSAGE_LENGTH},
-    error::{Error, Result},
+    error::{ProtocolError, Result},
     fs::metadata::FlapFileMetadata,
+    p2p::{cancel::CancelReason, limits::RefuseReason, manifest::ManifestEntry},
 };
 
+/// Frames exchanged once the Noise handshake is done.
+///
+/// Which frames a peer understands is defined by the protocol version
+/// negotiated during the handshake (see [`crate::p2p::version`]). New frames
+/// must only be sent when the matching capability was negotiated.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Frame {
     // msg = 0x01
-    FileData(Bytes),
+    FileData(Bytes, Vec<FileHash> /* proof of the block */),
     // msg = 0x02
-    PleaseSendFile(u64 /* seek amount */),
+    PleaseSendFile(
+        u64,      /* seek amount */
+        FileHash, /* hash of the last block of the partial file */
+    ),
     // msg = 0x03
     IWillSendThisFile(FlapFileMetadata),
     // msg = 0x04
     TransferComplete(FileHash),
+    // msg = 0x05
+    FileMetadataPart(
+        Bytes, /* part of the serialized file metadata */
+        bool,  /* last part of the metadata */
+    ),
+    // msg = 0x06
+    DeclineFile,
+    // msg = 0x07
+    Cancel(CancelReason),
+    // msg = 0x08
+    Pause,
+    // msg = 0x09
+    Resume,
+    // msg = 0x0a
+    SendingFileFrom(u64 /* seek amount */),
+    // msg = 0x0b
+    SkipFile,
+    // msg = 0x0c
+    RefuseFile(RefuseReason),
+    // msg = 0x0d
+    Manifest(
+        Vec<ManifestEntry>,
+        bool, /* last part of the manifest */
+    ),
+    // msg = 0x0e
+    RequestFiles(
+        Vec<u32>, /* indices of the requested files in the manifest */
+        bool,     /* last part of the request */
+    ),
+    // msg = 0x0f
+    NextFileHash(FileHash /* root hash of the next file */),
 }
 
-pub(crate) const MAX_FRAME_OPTIONAL_DATA_SIZE: usize = MAX_NOISE_MESSAGE_LENGTH - size_of::<u8>();
+/// Noise appends an authentication tag to every encrypted message.
+pub(crate) const NOISE_TAG_LENGTH: usize = 16;
+
+pub(crate) const MAX_FRAME_OPTIONAL_DATA_SIZE: usize =
+    MAX_NOISE_MESSAGE_LENGTH - NOISE_TAG_LENGTH - size_of::<u8>();
 
 /// [(u8)(data)]
 /// (u8) is [`Message`]
 /// (data) is optional data according to u8
-/// Note: Size of `SerializedFrame` is always equal or less than 65535 (fits in u16)
+/// [`Frame::FileData`] data is [(u8)(hashes)(block)], (u8) being how many
+/// 32 bytes hashes prove the block that follows
+/// [`Frame::FileMetadataPart`] data is [(u8)(part)], (u8) being 1 for the
+/// last part of the metadata
+/// [`Frame::Manifest`] data is [(u8)(u16)(entries)], (u8) being 1 for the
+/// last part of the manifest and (u16) how many entries follow
+/// [`Frame::RequestFiles`] data is [(u8)(u16)(indices)], the same way with
+/// (u32) indices
+/// Note: Size of `SerializedFrame` is always small enough to fit in a single
+/// Noise message (65535 bytes) once encrypted
 pub type SerializedFrame = Vec<u8>;
 
 impl Frame {
     pub fn to_bytes(&self) -> SerializedFrame {
         let mut vec: Vec<u8> = Vec::with_capacity(MAX_NOISE_MESSAGE_LENGTH);
         match self {
-            Frame::FileData(bytes) => {
-                debug_assert!(bytes.len() <= MAX_FRAME_OPTIONAL_DATA_SIZE);
+            Frame::FileData(bytes, proof) => {
+                debug_assert!(proof.len() <= u8::MAX as usize);
+                debug_assert!(
+                    bytes.len() + size_of::<u8>() + proof.len() * size_of::<FileHash>()
+                        <= MAX_FRAME_OPTIONAL_DATA_SIZE
+                );
                 vec.put_u8(0x01);
+                vec.put_u8(proof.len() as u8);
+                for hash in proof {
+                    vec.put_slice(hash);
+                }
                 vec.put_slice(bytes.as_ref());
             }
-            Frame::PleaseSendFile(seek) => {
+            Frame::PleaseSendFile(seek, boundary_hash) => {
                 vec.put_u8(0x02);
                 vec.put_u64(*seek);
+                vec.put_slice(boundary_hash);
             }
             Frame::IWillSendThisFile(flap_file_metadata) => {
                 let bytes = flap_file_metadata.to_bytes();
@@ -49,48 +111,232 @@ impl Frame {
                 vec.put_u8(0x04);
                 vec.put_slice(file_hash);
             }
+            Frame::FileMetadataPart(part, last) => {
+                debug_assert!(part.len() + size_of::<u8>() <= MAX_FRAME_OPTIONAL_DATA_SIZE);
+                vec.put_u8(0x05);
+                vec.put_u8(*last as u8);
+                vec.put_slice(part);
+            }
+            Frame::DeclineFile => {
+                vec.put_u8(0x06);
+            }
+            Frame::Cancel(reason) => {
+                vec.put_u8(0x07);
+                vec.put_u8(reason.to_u8());
+            }
+            Frame::Pause => {
+                vec.put_u8(0x08);
+            }
+            Frame::Resume => {
+                vec.put_u8(0x09);
+            }
+            Frame::SendingFileFrom(seek) => {
+                vec.put_u8(0x0a);
+                vec.put_u64(*seek);
+            }
+            Frame::SkipFile => {
+                vec.put_u8(0x0b);
+            }
+            Frame::RefuseFile(reason) => {
+                vec.put_u8(0x0c);
+                vec.put_u8(reason.to_u8());
+            }
+            Frame::Manifest(entries, last) => {
+                debug_assert!(entries.len() <= u16::MAX as usize);
+                vec.put_u8(0x0d);
+                vec.put_u8(*last as u8);
+                vec.put_u16(entries.len() as u16);
+                for entry in entries {
+                    entry.encode(&mut vec);
+                }
+            }
+            Frame::RequestFiles(indices, last) => {
+                debug_assert!(indices.len() <= u16::MAX as usize);
+                vec.put_u8(0x0e);
+                vec.put_u8(*last as u8);
+                vec.put_u16(indices.len() as u16);
+                for index in indices {
+                    vec.put_u32(*index);
+                }
+            }
+            Frame::NextFileHash(root_hash) => {
+                vec.put_u8(0x0f);
+                vec.put_slice(root_hash);
+            }
         }
 
-        debug_assert!(vec.len() <= MAX_NOISE_MESSAGE_LENGTH);
+        debug_assert!(vec.len() <= MAX_NOISE_MESSAGE_LENGTH - NOISE_TAG_LENGTH);
         vec
     }
 
-    pub async fn read_from_frame(mut frame: Bytes) -> Result<Self> {
-        debug_assert!(frame.len() >= size_of::<u8>());
+    /// Decodes a frame sent by the peer.
+    ///
+    /// The frame is untrusted input, so this must never panic.
+    pub fn read_from_frame(mut frame: Bytes) -> Result<Self> {
         debug_assert!(frame.len() <= MAX_NOISE_MESSAGE_LENGTH);
 
+        if frame.is_empty() {
+            return Err(ProtocolError::TruncatedPayload.into());
+        }
+
         let msg = frame.get_u8();
 
         match msg {
-            0x01 => Ok(Self::FileData(frame)),
-            0x02 => Ok(Self::PleaseSendFile(u64::from_be_bytes(
+            0x01 => {
+                if frame.is_empty() {
+                    return Err(ProtocolError::TruncatedPayload.into());
+                }
+                let proof_len = frame.get_u8() as usize;
+                if frame.remaining() < proof_len * size_of::<FileHash>() {
+                    return Err(ProtocolError::TruncatedPayload.into());
+                }
+                let proof = (0..proof_len)
+                    .map(|_| {
+                        let mut hash = FileHash::default();
+                        frame.copy_to_slice(&mut hash);
+                        hash
+                    })
+                    .collect();
+
+                Ok(Self::FileData(frame, proof))
+            }
+            0x02 => {
+                if frame.len() != size_of::<u64>() + size_of::<FileHash>() {
+                    return Err(ProtocolError::TruncatedPayload.into());
+                }
+                let seek = frame.get_u64();
+                let mut boundary_hash = FileHash::default();
+                frame.copy_to_slice(&mut boundary_hash);
+
+                Ok(Self::PleaseSendFile(seek, boundary_hash))
+            }
+            0x03 => {
+                let metadata = FlapFileMetadata::from_bytes(frame)?;
+                Ok(Self::IWillSendThisFile(metadata))
+            }
+            0x04 => Ok(Self::TransferComplete(
+                frame
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| ProtocolError::TruncatedPayload)?,
+            )),
+            0x05 => {
+                if !frame.has_remaining() {
+                    return Err(ProtocolError::TruncatedPayload.into());
+                }
+                let last = match frame.get_u8() {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(ProtocolError::MalformedMetadata.into()),
+                };
+
+                Ok(Self::FileMetadataPart(frame, last))
+            }
+            0x06 => Ok(Self::DeclineFile),
+            0x07 => {
+                let [reason] = frame
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| ProtocolError::TruncatedPayload)?;
+                Ok(Self::Cancel(CancelReason::from_u8(reason)))
+            }
+            0x08 => Ok(Self::Pause),
+            0x09 => Ok(Self::Resume),
+            0x0a => Ok(Self::SendingFileFrom(u64::from_be_bytes(
                 frame
                     .as_ref()
                     .try_into()
-                    .map_err(|_| Error::SerializationError)?,
+                    .map_err(|_| ProtocolError::TruncatedPayload)?,
             ))),
-            0x03 => {
-                let metadata = FlapFileMetadata::from_bytes(frame).await;
-                Ok(Self::IWillSendThisFile(metadata))
+            0x0b => Ok(Self::SkipFile),
+            0x0c => {
+                let [reason] = frame
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| ProtocolError::TruncatedPayload)?;
+                Ok(Self::RefuseFile(RefuseReason::from_u8(reason)))
             }
-            0x04 => Ok(Self::TransferComplete(
+            0x0d => {
+                if frame.remaining() < size_of::<u8>() + size_of::<u16>() {
+                    return Err(ProtocolError::TruncatedPayload.into());
+                }
+                let last = match frame.get_u8() {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(ProtocolError::MalformedManifest.into()),
+                };
+                let entry_count = frame.get_u16();
+                let entries = (0..entry_count)
+                    .map(|_| ManifestEntry::decode(&mut frame))
+                    .collect::<Result<_>>()?;
+                if frame.has_remaining() {
+                    return Err(ProtocolError::MalformedManifest.into());
+                }
+
+                Ok(Self::Manifest(entries, last))
+            }
+            0x0e => {
+                if frame.remaining() < size_of::<u8>() + size_of::<u16>() {
+                    return Err(ProtocolError::TruncatedPayload.into());
+                }
+                let last = match frame.get_u8() {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(ProtocolError::MalformedFileRequest.into()),
+                };
+                let index_count = frame.get_u16() as usize;
+                if frame.remaining() != index_count * size_of::<u32>() {
+                    return Err(ProtocolError::MalformedFileRequest.into());
+                }
+                let indices = (0..index_count).map(|_| frame.get_u32()).collect();
+
+                Ok(Self::RequestFiles(indices, last))
+            }
+            0x0f => Ok(Self::NextFileHash(
                 frame
                     .as_ref()
                     .try_into()
-                    .map_err(|_| Error::SerializationError)?,
+                    .map_err(|_| ProtocolError::TruncatedPayload)?,
             )),
-            _ => panic!("Invalid message header"),
+            header => Err(ProtocolError::UnknownFrame(header).into()),
+        }
+    }
+
+    /// Name of the frame, used when reporting protocol errors.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Frame::FileData(..) => "FileData",
+            Frame::PleaseSendFile(..) => "PleaseSendFile",
+            Frame::IWillSendThisFile(_) => "IWillSendThisFile",
+            Frame::TransferComplete(_) => "TransferComplete",
+            Frame::FileMetadataPart(..) => "FileMetadataPart",
+            Frame::DeclineFile => "DeclineFile",
+            Frame::Cancel(_) => "Cancel",
+            Frame::Pause => "Pause",
+            Frame::Resume => "Resume",
+            Frame::SendingFileFrom(_) => "SendingFileFrom",
+            Frame::SkipFile => "SkipFile",
+            Frame::RefuseFile(_) => "RefuseFile",
+            Frame::Manifest(..) => "Manifest",
+            Frame::RequestFiles(..) => "RequestFiles",
+            Frame::NextFileHash(_) => "NextFileHash",
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
+    use crate::{
+        error::Error,
+        fs::{metadata::tests::arb_metadata, sanitize::validate_file_name},
+    };
 
-    #[tokio::test]
-    pub async fn basic_frame_roundtrip() {
-        let frame1 = Frame::PleaseSendFile(0);
+    #[test]
+    pub fn basic_frame_roundtrip() {
+        let frame1 = Frame::PleaseSendFile(0, [0; 32]);
 
         let frame2 = Frame::IWillSendThisFile(FlapFileMetadata {
             is_file: true,
@@ -99,21 +345,139 @@ mod tests {
             file_name: "a".to_string(),
         });
 
-        let frame3 = Frame::PleaseSendFile(100);
+        let frame3 = Frame::PleaseSendFile(100, [2; 32]);
 
         /* roundtrip */
-        let frame1_roundtrip = Frame::read_from_frame(frame1.to_bytes().into())
-            .await
-            .unwrap();
-        let frame2_roundtrip = Frame::read_from_frame(frame2.to_bytes().into())
-            .await
-            .unwrap();
-        let frame3_roundtrip = Frame::read_from_frame(frame3.to_bytes().into())
-            .await
-            .unwrap();
+        let frame1_roundtrip = Frame::read_from_frame(frame1.to_bytes().into()).unwrap();
+        let frame2_roundtrip = Frame::read_from_frame(frame2.to_bytes().into()).unwrap();
+        let frame3_roundtrip = Frame::read_from_frame(frame3.to_bytes().into()).unwrap();
 
         assert_eq!(frame1_roundtrip, frame1);
         assert_eq!(frame2_roundtrip, frame2);
         assert_eq!(frame3_roundtrip, frame3);
     }
+
+    #[test]
+    pub fn invalid_frames_are_errors() {
+        let invalid_frames: &[&[u8]] = &[
+            &[],
+            &[0x00],
+            &[0x01],
+            &[0x01, 1, 0, 0],
+            &[0xff, 1, 2, 3],
+            &[0x02, 0, 0, 0],
+            &[0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[0x0a, 0, 0, 0],
+            &[0x03, 0x00, 0, 0],
+            &[0x03, 0x00, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0xff],
+            &[0x04, 0, 0],
+            &[0x05],
+            &[0x05, 2, 0],
+            &[0x07],
+            &[0x07, 1, 1],
+            &[0x0c],
+            &[0x0d, 1],
+            &[0x0d, 2, 0, 0],
+            &[0x0d, 1, 0, 1],
+            &[0x0d, 1, 0, 0, 0xff],
+            &[0x0e, 1, 0, 1, 0, 0],
+            &[0x0e, 2, 0, 0],
+            &[0x0f, 0, 0],
+        ];
+
+        for frame in invalid_frames {
+            assert!(
+                matches!(
+                    Frame::read_from_frame(Bytes::copy_from_slice(frame)),
+                    Err(Error::Protocol(_))
+                ),
+                "{frame:?} should be rejected"
+            );
+        }
+    }
+
+    fn file_data_len(proof_len: usize) -> impl Strategy<Value = usize> {
+        let max =
+            MAX_FRAME_OPTIONAL_DATA_SIZE - size_of::<u8>() - proof_len * size_of::<FileHash>();
+        prop_oneof![Just(0), Just(1), Just(max - 1), Just(max), 0..=max]
+    }
+
+    fn arb_file_data() -> impl Strategy<Value = Frame> {
+        prop::collection::vec(any::<FileHash>(), 0..=64).prop_flat_map(|proof| {
+            file_data_len(proof.len())
+                .prop_flat_map(|len| prop::collection::vec(any::<u8>(), len))
+                .prop_map(move |data| Frame::FileData(data.into(), proof.clone()))
+        })
+    }
+
+    fn arb_manifest_entries() -> impl Strategy<Value = Vec<ManifestEntry>> {
+        let entry = (
+            prop::collection::vec("[a-z0-9 _-]{1,20}", 1..4),
+            any::<u64>(),
+        )
+            .prop_map(|(components, file_size)| ManifestEntry {
+                relative_path: components.iter().collect(),
+                file_size,
+            })
+            // Windows strips trailing spaces
+            .prop_filter("valid names", |entry| {
+                entry
+                    .relative_path
+                    .iter()
+                    .all(|component| validate_file_name(&component.to_string_lossy()).is_ok())
+            });
+
+        prop::collection::vec(entry, 0..64)
+    }
+
+    fn arb_frame() -> impl Strategy<Value = Frame> {
+        prop_oneof![
+            arb_file_data(),
+            (any::<u64>(), any::<FileHash>())
+                .prop_map(|(seek, hash)| Frame::PleaseSendFile(seek, hash)),
+            arb_metadata().prop_map(Frame::IWillSendThisFile),
+            any::<FileHash>().prop_map(Frame::TransferComplete),
+            (prop::collection::vec(any::<u8>(), 0..1024), any::<bool>())
+                .prop_map(|(part, last)| Frame::FileMetadataPart(part.into(), last)),
+            Just(Frame::DeclineFile),
+            prop_oneof![
+                Just(CancelReason::Unknown),
+                Just(CancelReason::UserRequested),
+                Just(CancelReason::ShuttingDown),
+            ]
+            .prop_map(Frame::Cancel),
+            Just(Frame::Pause),
+            Just(Frame::Resume),
+            any::<u64>().prop_map(Frame::SendingFileFrom),
+            Just(Frame::SkipFile),
+            prop_oneof![
+                Just(RefuseReason::Unknown),
+                Just(RefuseReason::NotEnoughSpace),
+                Just(RefuseReason::FileTooLarge),
+                Just(RefuseReason::SessionTooLarge),
+            ]
+            .prop_map(Frame::RefuseFile),
+            (arb_manifest_entries(), any::<bool>())
+                .prop_map(|(entries, last)| Frame::Manifest(entries, last)),
+            (prop::collection::vec(any::<u32>(), 0..1024), any::<bool>())
+                .prop_map(|(indices, last)| Frame::RequestFiles(indices, last)),
+            any::<FileHash>().prop_map(Frame::NextFileHash),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn frame_roundtrip(frame in arb_frame()) {
+            let bytes = frame.to_bytes();
+            prop_assert!(bytes.len() <= MAX_NOISE_MESSAGE_LENGTH - NOISE_TAG_LENGTH);
+
+            let roundtrip = Frame::read_from_frame(bytes.into()).unwrap();
+            prop_assert_eq!(roundtrip, frame);
+        }
+
+        #[test]
+        fn decoding_arbitrary_frames_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..1024)) {
+            let _ = Frame::read_from_frame(bytes.into());
+        }
+    }
 }
@@ -0,0 +1,216 @@
+//! Limits on how much a receiver accepts.
+//!
+//! Before asking for a file, the receiver checks it against its
+//! [`SizeLimits`] and the free space of the download directory. A file that
+//! does not fit is refused with a [`crate::p2p::frame::Frame::RefuseFile`]
+//! telling the sender why, and the transfer stops there.
+//!
+//! Files announced in a [`crate::p2p::manifest::Manifest`] are checked
+//! together as soon as they are announced. If they do not fit, none of them
+//! is asked for, and the sender keeps them for other receivers.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+
+/// Why the receiver refused a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefuseReason {
+    /// The peer did not say why, or gave a reason we do not know of.
+    Unknown,
+    /// The receiver's disk does not have enough free space left.
+    NotEnoughSpace,
+    /// The file is larger than [`SizeLimits::max_file_size`].
+    FileTooLarge,
+    /// The file would go over [`SizeLimits::max_session_size`].
+    SessionTooLarge,
+}
+
+impl RefuseReason {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            RefuseReason::Unknown => 0,
+            RefuseReason::NotEnoughSpace => 1,
+            RefuseReason::FileTooLarge => 2,
+            RefuseReason::SessionTooLarge => 3,
+        }
+    }
+
+    pub fn from_u8(reason: u8) -> Self {
+        match reason {
+            1 => RefuseReason::NotEnoughSpace,
+            2 => RefuseReason::FileTooLarge,
+            3 => RefuseReason::SessionTooLarge,
+            _ => RefuseReason::Unknown,
+        }
+    }
+}
+
+/// The largest files a receiver accepts. Nothing is limited by default,
+/// other than by the free space of the download directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeLimits {
+    /// The largest file accepted, in bytes.
+    pub max_file_size: Option<u64>,
+    /// How many bytes are accepted in total by a single call to
+    /// [`crate::p2p::receiver::P2pReceiver::retrieve`].
+    pub max_session_size: Option<u64>,
+}
+
+impl SizeLimits {
+    /// Whether a file of `file_size` bytes, of which `needed` bytes are still
+    /// to be received, is accepted. The bytes needed by accepted files are
+    /// reserved in the session, see [`SessionReservation`].
+    pub(crate) fn check(
+        &self,
+        file_size: u64,
+        needed: u64,
+        available_space: u64,
+        session: &SessionUsage,
+    ) -> Result<SessionReservation, RefuseReason> {
+        self.fits(file_size, needed, available_space, session)?;
+
+        session
+            .reserve(needed, self.max_session_size)
+            .ok_or(RefuseReason::SessionTooLarge)
+    }
+
+    /// Like [`Self::check`], without reserving anything. Used for files that
+    /// are announced, whose largest file is `file_size` bytes.
+    pub(crate) fn fits(
+        &self,
+        file_size: u64,
+        needed: u64,
+        available_space: u64,
+        session: &SessionUsage,
+    ) -> Result<(), RefuseReason> {
+        if self.max_file_size.is_some_and(|max| file_size > max) {
+            return Err(RefuseReason::FileTooLarge);
+        }
+        if needed > available_space {
+            return Err(RefuseReason::NotEnoughSpace);
+        }
+        let total = session.bytes.load(Ordering::SeqCst).checked_add(needed);
+        if total.is_none_or(|total| self.max_session_size.is_some_and(|max| total > max)) {
+            return Err(RefuseReason::SessionTooLarge);
+        }
+
+        Ok(())
+    }
+}
+
+/// How many bytes were accepted during a session, shared by all its streams.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SessionUsage {
+    bytes: Arc<AtomicU64>,
+}
+
+impl SessionUsage {
+    /// Counts `bytes` more, unless the total would go over `max`.
+    fn reserve(&self, bytes: u64, max: Option<u64>) -> Option<SessionReservation> {
+        self.bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| {
+                let total = total.checked_add(bytes)?;
+                max.is_none_or(|max| total <= max).then_some(total)
+            })
+            .ok()?;
+
+        Some(SessionReservation {
+            bytes: self.bytes.clone(),
+            unused: bytes,
+        })
+    }
+}
+
+/// Bytes of a file counted in a [`SessionUsage`] before receiving it.
+///
+/// Those that were not received are given back once dropped, whether the
+/// file failed, was cancelled or is retried later.
+#[derive(Debug)]
+pub(crate) struct SessionReservation {
+    bytes: Arc<AtomicU64>,
+    unused: u64,
+}
+
+impl SessionReservation {
+    /// Counts `bytes` of the file as received, for good.
+    pub fn use_bytes(&mut self, bytes: u64) {
+        self.unused = self.unused.saturating_sub(bytes);
+    }
+}
+
+impl Drop for SessionReservation {
+    fn drop(&mut self) {
+        self.bytes.fetch_sub(self.unused, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_limits_refuse_what_does_not_fit() {
+        let limits = SizeLimits {
+            max_file_size: Some(100),
+            max_session_size: Some(150),
+        };
+        let session = SessionUsage::default();
+        let check = |file_size, needed, available_space| {
+            limits.check(file_size, needed, available_space, &session)
+        };
+
+        assert_eq!(
+            check(101, 101, u64::MAX).err(),
+            Some(RefuseReason::FileTooLarge)
+        );
+        assert_eq!(
+            check(100, 100, 99).err(),
+            Some(RefuseReason::NotEnoughSpace)
+        );
+        // Only what is left of a partial file counts
+        let mut partial = check(100, 10, 10).unwrap();
+        partial.use_bytes(10);
+        let full = check(100, 100, u64::MAX).unwrap();
+        assert_eq!(
+            check(100, 41, u64::MAX).err(),
+            Some(RefuseReason::SessionTooLarge)
+        );
+        // Refused files do not count
+        let _small = check(40, 40, u64::MAX).unwrap();
+
+        // Bytes that were not received are given back, the others are kept
+        drop(partial);
+        drop(full);
+        assert!(check(100, 100, u64::MAX).is_ok());
+        assert!(check(100, 101, u64::MAX).is_err());
+
+        // Announced files are only checked
+        assert_eq!(limits.fits(100, 100, u64::MAX, &session), Ok(()));
+        assert_eq!(
+            limits.fits(100, 101, u64::MAX, &session),
+            Err(RefuseReason::SessionTooLarge)
+        );
+        assert!(check(100, 100, u64::MAX).is_ok());
+
+        assert!(
+            SizeLimits::default()
+                .check(u64::MAX, u64::MAX, u64::MAX, &SessionUsage::default())
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn refuse_reason_roundtrip() {
+        for reason in [
+            RefuseReason::Unknown,
+            RefuseReason::NotEnoughSpace,
+            RefuseReason::FileTooLarge,
+            RefuseReason::SessionTooLarge,
+        ] {
+            assert_eq!(RefuseReason::from_u8(reason.to_u8()), reason);
+        }
+        assert_eq!(RefuseReason::from_u8(u8::MAX), RefuseReason::Unknown);
+    }
+}
@@ -0,0 +1,265 @@
+//! Manifests, announcing the queued files to the receiver up front.
+//!
+//! Each file is sent over its own stream, so without a manifest the receiver
+//! only learns of a file once its stream is opened. Instead, the sender opens
+//! a control stream once connected, and sends a [`Manifest`] of every file it
+//! is about to send before opening any of their streams. Files queued later
+//! are announced the same way.
+
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::{
+    error::{Error, ProtocolError, Result},
+    fs::{metadata::FlapFileMetadata, sanitize::validate_file_name},
+    p2p::frame::{Frame, MAX_FRAME_OPTIONAL_DATA_SIZE},
+};
+
+/// A file the sender is about to send.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ManifestEntry {
+    /// Where the file is saved, relative to the download directory.
+    /// Files of a directory start with the name of the directory.
+    pub relative_path: PathBuf,
+    pub file_size: u64,
+}
+
+impl ManifestEntry {
+    /// The name of the file, without the directories it is in.
+    pub fn file_name(&self) -> String {
+        self.relative_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// The relative path with `/` between its components, whatever the platform.
+    fn path_string(&self) -> String {
+        let components: Vec<_> = self
+            .relative_path
+            .iter()
+            .map(|component| component.to_string_lossy())
+            .collect();
+
+        components.join("/")
+    }
+
+    fn encoded_len(&self) -> usize {
+        size_of::<u64>() + size_of::<u16>() + self.path_string().len()
+    }
+
+    /// [(u64)(u16)(path)]
+    /// (u64) is the file size
+    /// (u16) is the length of the path that follows, its components separated by `/`
+    pub(crate) fn encode(&self, bytes: &mut Vec<u8>) {
+        let path = self.path_string();
+
+        bytes.put_u64(self.file_size);
+        bytes.put_u16(path.len() as u16);
+        bytes.put_slice(path.as_bytes());
+    }
+
+    /// Decodes an entry written by [`Self::encode`]. Its path comes from the
+    /// sender, so each of its components is validated.
+    pub(crate) fn decode(bytes: &mut Bytes) -> Result<Self> {
+        if bytes.remaining() < size_of::<u64>() + size_of::<u16>() {
+            return Err(ProtocolError::MalformedManifest.into());
+        }
+        let file_size = bytes.get_u64();
+        let path_len = bytes.get_u16() as usize;
+        if bytes.remaining() < path_len {
+            return Err(ProtocolError::MalformedManifest.into());
+        }
+        let path = String::from_utf8(bytes.split_to(path_len).to_vec())
+            .map_err(|_| ProtocolError::MalformedManifest)?;
+
+        let mut relative_path = PathBuf::new();
+        for component in path.split('/') {
+            validate_file_name(component)?;
+            relative_path.push(component);
+        }
+
+        Ok(Self {
+            relative_path,
+            file_size,
+        })
+    }
+}
+
+/// Files the sender is about to send.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Adds every file of a queued file or directory.
+    pub(crate) fn add(&mut self, metadata: &FlapFileMetadata) {
+        self.entries.extend(
+            metadata
+                .files()
+                .into_iter()
+                .map(|(relative_path, metadata)| ManifestEntry {
+                    relative_path,
+                    file_size: metadata.file_size,
+                }),
+        );
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The size of every file, in bytes.
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.file_size).sum()
+    }
+
+    /// Splits the manifest into [`Frame::Manifest`] frames that each fit in a
+    /// Noise message. Only the last of them is marked as such.
+    pub(crate) fn to_frames(&self) -> Result<Vec<Frame>> {
+        // The flag and the entry count come first
+        const CAPACITY: usize = MAX_FRAME_OPTIONAL_DATA_SIZE - size_of::<u8>() - size_of::<u16>();
+
+        let mut frames = Vec::new();
+        let mut entries = Vec::new();
+        let mut len = 0;
+        for entry in &self.entries {
+            let entry_len = entry.encoded_len();
+            if entry_len > CAPACITY || entry.path_string().len() > u16::MAX as usize {
+                return Err(Error::MetadataTooLarge);
+            }
+            if len + entry_len > CAPACITY || entries.len() == u16::MAX as usize {
+                frames.push(Frame::Manifest(std::mem::take(&mut entries), false));
+                len = 0;
+            }
+            entries.push(entry.clone());
+            len += entry_len;
+        }
+        frames.push(Frame::Manifest(entries, true));
+
+        Ok(frames)
+    }
+}
+
+/// Every file announced to the receiver during a session and selected by it,
+/// and how much of them was received.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BatchProgress {
+    state: Arc<Mutex<BatchState>>,
+}
+
+#[derive(Debug, Default)]
+struct BatchState {
+    manifest: Manifest,
+    /// Files announced again after reconnecting are only counted once.
+    announced: HashSet<(PathBuf, u64)>,
+    bytes_received: u64,
+}
+
+impl BatchProgress {
+    /// Adds the files of a manifest, and returns every file announced so far.
+    pub fn announce(&self, entries: Vec<ManifestEntry>) -> Manifest {
+        let mut state = self.state.lock().expect("lock is not poisoned");
+        for entry in entries {
+            if state
+                .announced
+                .insert((entry.relative_path.clone(), entry.file_size))
+            {
+                state.manifest.entries.push(entry);
+            }
+        }
+
+        state.manifest.clone()
+    }
+
+    /// Counts `bytes` more as received, and returns how many bytes were
+    /// received out of the total. `None` until a manifest is announced.
+    pub fn add_received(&self, bytes: u64) -> Option<(u64, u64)> {
+        let mut state = self.state.lock().expect("lock is not poisoned");
+        let total_size = state.manifest.total_size();
+        if state.manifest.entries.is_empty() {
+            return None;
+        }
+        // Files received again from their start would count twice
+        state.bytes_received = (state.bytes_received + bytes).min(total_size);
+
+        Some((state.bytes_received, total_size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(relative_path: &str, file_size: u64) -> ManifestEntry {
+        ManifestEntry {
+            relative_path: PathBuf::from_iter(relative_path.split('/')),
+            file_size,
+        }
+    }
+
+    #[test]
+    fn large_manifests_are_split() {
+        let manifest = Manifest {
+            entries: (0..10_000)
+                .map(|i| entry(&format!("directory/file {i}.txt"), i))
+                .collect(),
+        };
+
+        let frames = manifest.to_frames().unwrap();
+        assert!(frames.len() > 1);
+
+        let mut entries = Vec::new();
+        for (i, frame) in frames.iter().enumerate() {
+            let bytes = frame.to_bytes();
+            let Frame::Manifest(part, last) = Frame::read_from_frame(bytes.into()).unwrap() else {
+                panic!("manifests are split into manifest frames");
+            };
+            assert_eq!(last, i == frames.len() - 1);
+            entries.extend(part);
+        }
+        assert_eq!(entries, manifest.entries);
+    }
+
+    #[test]
+    fn entries_with_unsafe_paths_are_rejected() {
+        for path in ["", "a//b", "../a", "a/..", "a\\b", "/a"] {
+            let mut bytes = Vec::new();
+            ManifestEntry {
+                relative_path: PathBuf::new(),
+                file_size: 1,
+            }
+            .encode(&mut bytes);
+            // Replaces the empty path with `path`
+            bytes.truncate(bytes.len() - size_of::<u16>());
+            bytes.put_u16(path.len() as u16);
+            bytes.put_slice(path.as_bytes());
+
+            assert!(
+                ManifestEntry::decode(&mut Bytes::from(bytes)).is_err(),
+                "{path:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn batch_progress_counts_files_once() {
+        let batch = BatchProgress::default();
+        assert_eq!(batch.add_received(10), None);
+
+        let manifest = batch.announce(vec![entry("a", 100), entry("b", 50)]);
+        assert_eq!(manifest.file_count(), 2);
+        // Announced again after reconnecting
+        let manifest = batch.announce(vec![entry("b", 50), entry("c", 10)]);
+        assert_eq!((manifest.file_count(), manifest.total_size()), (3, 160));
+
+        assert_eq!(batch.add_received(100), Some((100, 160)));
+        assert_eq!(batch.add_received(100), Some((160, 160)));
+    }
+}
@@ -1,6 +1,17 @@
 pub const ALPN: &[u8] = b"flap-p2p-transfer";
 
+pub mod cancel;
 pub mod endpoint;
 pub mod frame;
+pub mod limits;
+pub mod manifest;
+pub mod pause;
+pub mod policy;
 pub mod receiver;
+pub mod reconnect;
+pub mod scheduler;
+pub mod selection;
 pub mod sender;
+#[cfg(test)]
+mod tests;
+pub mod version;
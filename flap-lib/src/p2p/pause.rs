@@ -0,0 +1,83 @@
+//! Pausing transfers from either side, without closing their stream.
+//!
+//! The side that pauses stops sending file blocks if it is the sender, and
+//! sends a [`crate::p2p::frame::Frame::Pause`] to the peer. A sender told to
+//! pause stops before its next block, until the peer sends
+//! [`crate::p2p::frame::Frame::Resume`]. A transfer paused by both sides
+//! continues once both resumed it.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::watch;
+
+use crate::{
+    crypto::transfer_id::TransferId,
+    error::{Error, Result},
+};
+
+type Transfers = Arc<Mutex<HashMap<TransferId, watch::Sender<bool>>>>;
+
+/// The running transfers of a sender or a receiver, which can be paused.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pauses {
+    transfers: Transfers,
+}
+
+impl Pauses {
+    /// The transfer can be paused until the returned token is dropped.
+    pub fn register(&self, transfer_id: TransferId) -> PauseToken {
+        let (paused_tx, paused_rx) = watch::channel(false);
+        self.transfers
+            .lock()
+            .expect("lock is not poisoned")
+            .insert(transfer_id, paused_tx);
+
+        PauseToken {
+            transfer_id,
+            paused_rx,
+            transfers: self.transfers.clone(),
+        }
+    }
+
+    pub fn set_paused(&self, transfer_id: TransferId, paused: bool) -> Result<()> {
+        let transfers = self.transfers.lock().expect("lock is not poisoned");
+        let paused_tx = transfers.get(&transfer_id).ok_or(Error::UnknownTransfer)?;
+        paused_tx.send_replace(paused);
+
+        Ok(())
+    }
+}
+
+/// Lets a running transfer know it was paused or resumed on our side.
+#[derive(Debug)]
+pub(crate) struct PauseToken {
+    transfer_id: TransferId,
+    paused_rx: watch::Receiver<bool>,
+    transfers: Transfers,
+}
+
+impl PauseToken {
+    pub fn is_paused(&self) -> bool {
+        *self.paused_rx.borrow()
+    }
+
+    /// Resolves once the transfer is paused or resumed.
+    pub async fn changed(&mut self) {
+        if self.paused_rx.changed().await.is_err() {
+            // The transfer can no longer be paused
+            std::future::pending().await
+        }
+    }
+}
+
+impl Drop for PauseToken {
+    fn drop(&mut self) {
+        self.transfers
+            .lock()
+            .expect("lock is not poisoned")
+            .remove(&self.transfer_id);
+    }
+}
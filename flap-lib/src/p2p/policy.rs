@@ -0,0 +1,315 @@
+//! Who may redeem the tickets of a [`crate::p2p::sender::P2pSender`].
+//!
+//! A ticket is bound to the first receivers that complete the handshake with
+//! it, up to [`TicketPolicy::max_uses`]. Bound receivers may connect
+//! again, other receivers are turned away. Once a ticket is used up or expired,
+//! a fresh one, with a new [`MasterKey`], is issued the next time the sender
+//! asks for its ticket.
+//!
+//! Receivers bound to a ticket that was replaced keep it for as long as the
+//! ticket could be redeemed, counted from when it was replaced. They are only
+//! sent the files queued before then.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use iroh::{NodeAddr, NodeId};
+
+use crate::{
+    crypto::master_key::MasterKey,
+    error::{Error, Result},
+    ticket::Ticket,
+};
+
+/// How many times and for how long a ticket can be redeemed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TicketPolicy {
+    max_uses: u32,
+    ttl: Option<Duration>,
+}
+
+impl TicketPolicy {
+    /// Fails if `max_uses` is 0, as nobody could ever redeem the ticket.
+    pub fn new(max_uses: u32, ttl: Option<Duration>) -> Result<Self> {
+        if max_uses == 0 {
+            return Err(Error::TicketNeverUsable);
+        }
+
+        Ok(Self { max_uses, ttl })
+    }
+
+    /// How many receivers can redeem the same ticket.
+    pub fn max_uses(&self) -> u32 {
+        self.max_uses
+    }
+
+    /// How long after being issued a ticket can still be redeemed by new receivers.
+    /// Transfers of receivers that already redeemed it are not interrupted.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+}
+
+impl Default for TicketPolicy {
+    /// Tickets are single-use, and never expire.
+    fn default() -> Self {
+        Self {
+            max_uses: 1,
+            ttl: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct IssuedTicket {
+    ticket: Ticket,
+    /// Increases with every ticket issued.
+    generation: u64,
+    issued_at: Instant,
+    receivers: HashSet<NodeId>,
+}
+
+/// A receiver bound to a ticket that was since replaced.
+#[derive(Debug)]
+struct RetiredBinding {
+    generation: u64,
+    ticket: Ticket,
+    retired_at: Instant,
+}
+
+/// Issues tickets and keeps track of who redeemed them.
+#[derive(Debug)]
+pub(crate) struct TicketIssuer {
+    node_addr: NodeAddr,
+    policy: TicketPolicy,
+    current: IssuedTicket,
+    /// Receivers bound to tickets that were since replaced, with the ticket they know.
+    retired: HashMap<NodeId, RetiredBinding>,
+}
+
+impl TicketIssuer {
+    pub fn new(node_addr: NodeAddr, policy: TicketPolicy) -> Self {
+        let current = IssuedTicket {
+            ticket: Ticket::make(node_addr.clone(), MasterKey::generate()),
+            generation: 0,
+            issued_at: Instant::now(),
+            receivers: HashSet::new(),
+        };
+
+        Self {
+            node_addr,
+            policy,
+            current,
+            retired: HashMap::new(),
+        }
+    }
+
+    /// The ticket to hand out to new receivers.
+    pub fn ticket(&mut self) -> Ticket {
+        if self.check_current().is_err() {
+            self.rotate(MasterKey::generate());
+        }
+
+        self.current.ticket.clone()
+    }
+
+    /// The generation of the current ticket. Files queued now are for its
+    /// receivers, and not for the receivers of the tickets it replaced.
+    pub fn generation(&self) -> u64 {
+        self.current.generation
+    }
+
+    /// Replaces the current ticket with a fresh one using `master_key`, such
+    /// as one derived from a short code.
+    pub fn issue(&mut self, master_key: MasterKey) -> Ticket {
+        self.rotate(master_key);
+
+        self.current.ticket.clone()
+    }
+
+    /// Fails if a new receiver cannot redeem the current ticket.
+    fn check_current(&self) -> Result<()> {
+        if self.current.receivers.len() >= self.policy.max_uses as usize {
+            return Err(Error::TicketAlreadyUsed);
+        }
+
+        match self.policy.ttl {
+            Some(ttl) if self.current.issued_at.elapsed() > ttl => Err(Error::TicketExpired),
+            _ => Ok(()),
+        }
+    }
+
+    fn rotate(&mut self, master_key: MasterKey) {
+        let generation = self.current.generation + 1;
+        let previous = std::mem::replace(
+            &mut self.current,
+            IssuedTicket {
+                ticket: Ticket::make(self.node_addr.clone(), master_key),
+                generation,
+                issued_at: Instant::now(),
+                receivers: HashSet::new(),
+            },
+        );
+
+        self.prune();
+        let retired_at = Instant::now();
+        for receiver in previous.receivers {
+            let binding = RetiredBinding {
+                generation: previous.generation,
+                ticket: previous.ticket.clone(),
+                retired_at,
+            };
+            self.retired.insert(receiver, binding);
+        }
+    }
+
+    /// Forgets the receivers bound to a ticket replaced longer than the TTL ago.
+    fn prune(&mut self) {
+        if let Some(ttl) = self.policy.ttl {
+            self.retired
+                .retain(|_, binding| binding.retired_at.elapsed() <= ttl);
+        }
+    }
+
+    /// The ticket `receiver` is expected to know, along with its generation.
+    ///
+    /// Fails if `receiver` is not bound to any ticket, and can no longer
+    /// redeem the current one.
+    pub fn ticket_for(&mut self, receiver: &NodeId) -> Result<(u64, Ticket)> {
+        self.prune();
+        if let Some(binding) = self.retired.get(receiver) {
+            return Ok((binding.generation, binding.ticket.clone()));
+        }
+
+        if !self.current.receivers.contains(receiver) {
+            self.check_current()?;
+        }
+
+        Ok((self.current.generation, self.current.ticket.clone()))
+    }
+
+    /// Binds `receiver` to the ticket of `generation`, once it proved it knows it.
+    pub fn bind(&mut self, receiver: NodeId, generation: u64) -> Result<()> {
+        self.prune();
+        let bound = match self.retired.get(&receiver) {
+            Some(binding) => binding.generation == generation,
+            None => self.current.receivers.contains(&receiver),
+        };
+        if bound {
+            return Ok(());
+        }
+
+        // The ticket was replaced during the handshake
+        if generation != self.current.generation {
+            return Err(Error::TicketExpired);
+        }
+        self.check_current()?;
+        self.current.receivers.insert(receiver);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::*;
+    use crate::crypto::random_array;
+
+    fn node_id() -> NodeId {
+        SecretKey::from_bytes(&random_array()).public()
+    }
+
+    #[test]
+    fn single_use_ticket_is_bound_to_first_receiver() {
+        let sender = node_id();
+        let mut issuer = TicketIssuer::new(sender.into(), TicketPolicy::default());
+        let first_key = issuer.ticket().master_key().encode_to_string();
+
+        let (receiver, other) = (node_id(), node_id());
+        let (generation, ticket) = issuer.ticket_for(&receiver).unwrap();
+        assert_eq!(ticket.master_key().encode_to_string(), first_key);
+        issuer.bind(receiver, generation).unwrap();
+
+        // The receiver can connect again, nobody else can
+        assert!(issuer.ticket_for(&receiver).is_ok());
+        issuer.bind(receiver, generation).unwrap();
+        assert!(matches!(
+            issuer.ticket_for(&other),
+            Err(Error::TicketAlreadyUsed)
+        ));
+        assert!(matches!(
+            issuer.bind(other, generation),
+            Err(Error::TicketAlreadyUsed)
+        ));
+
+        // A fresh ticket is issued for the next receiver
+        let second_key = issuer.ticket().master_key().encode_to_string();
+        assert_ne!(second_key, first_key);
+        let (generation, ticket) = issuer.ticket_for(&other).unwrap();
+        assert_eq!(ticket.master_key().encode_to_string(), second_key);
+        issuer.bind(other, generation).unwrap();
+
+        // The first receiver still uses the first ticket
+        let (_, ticket) = issuer.ticket_for(&receiver).unwrap();
+        assert_eq!(ticket.master_key().encode_to_string(), first_key);
+    }
+
+    #[test]
+    fn expired_ticket_is_rejected() {
+        let policy = TicketPolicy::new(2, Some(Duration::ZERO)).unwrap();
+        let mut issuer = TicketIssuer::new(node_id().into(), policy);
+        std::thread::sleep(Duration::from_millis(1));
+
+        let receiver = node_id();
+        assert!(matches!(
+            issuer.ticket_for(&receiver),
+            Err(Error::TicketExpired)
+        ));
+        assert!(matches!(
+            issuer.bind(receiver, 0),
+            Err(Error::TicketExpired)
+        ));
+
+        // Replaced by a ticket of the next generation
+        issuer.ticket();
+        assert!(matches!(
+            issuer.bind(receiver, 0),
+            Err(Error::TicketExpired)
+        ));
+    }
+
+    #[test]
+    fn retired_bindings_expire() {
+        let policy = TicketPolicy::new(1, Some(Duration::from_millis(50))).unwrap();
+        let mut issuer = TicketIssuer::new(node_id().into(), policy);
+        let receiver = node_id();
+        let (generation, _) = issuer.ticket_for(&receiver).unwrap();
+        issuer.bind(receiver, generation).unwrap();
+
+        // Files queued from now on are for the next receiver
+        issuer.ticket();
+        assert_eq!(issuer.generation(), generation + 1);
+        assert_eq!(issuer.ticket_for(&receiver).unwrap().0, generation);
+        issuer.bind(receiver, generation).unwrap();
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(matches!(
+            issuer.bind(receiver, generation),
+            Err(Error::TicketExpired)
+        ));
+        assert!(issuer.retired.is_empty());
+    }
+
+    #[test]
+    fn tickets_must_be_usable() {
+        assert!(matches!(
+            TicketPolicy::new(0, None),
+            Err(Error::TicketNeverUsable)
+        ));
+        assert_eq!(TicketPolicy::new(1, None).unwrap(), TicketPolicy::default());
+    }
+}
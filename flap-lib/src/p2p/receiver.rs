@@ -1,40 +1,442 @@
-use iroh::endpoint::ConnectionError;
-use tokio::task::JoinSet;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use iroh::{
+    NodeAddr, SecretKey,
+    discovery::mdns,
+    endpoint::{Connection, ConnectionError, RecvStream, SendStream},
+};
+use tokio::{
+    fs::File,
+    sync::{Semaphore, mpsc, oneshot},
+    task::JoinSet,
+};
+use tokio_stream::StreamExt;
 
 use crate::{
-    crypto::encryption_stream::EncryptionStream,
-    error::Result,
+    code::server::RemoteRendezvous,
+    crypto::{
+        blake3::{FileHash, boundary_hash},
+        encryption_stream::{EncryptionStream, StreamOpening},
+        transfer_id::TransferId,
+    },
+    error::{CloseReason, Error, ProtocolError, Result},
     event::{Event, get_event_handler},
-    fs::save::FileSaver,
-    p2p::{ALPN, endpoint::P2pEndpoint},
+    fs::{
+        metadata::FlapFileMetadata,
+        partial::PartialState,
+        sanitize::{validate_file_name, validate_metadata},
+        save::{ConflictPolicy, FileSaver, PartialFilePolicy},
+    },
+    p2p::{
+        ALPN,
+        cancel::{CancelReason, Cancellations},
+        endpoint::{P2pEndpoint, P2pEndpointConfig, SENDER_USER_DATA},
+        limits::{RefuseReason, SessionUsage, SizeLimits},
+        manifest::{BatchProgress, Manifest, ManifestEntry},
+        pause::Pauses,
+        reconnect::{ReconnectPolicy, is_connection_lost},
+        scheduler::DEFAULT_MAX_STREAMS,
+        selection::FileSelection,
+    },
     ticket::Ticket,
 };
 
 #[cfg(feature = "tracing")]
 use tracing::{error, info};
 
+/// How many bytes are received between two saves of the [`PartialState`] of a file.
+/// If Flap stops without saving it, at most this many bytes are received again.
+const PARTIAL_STATE_SAVE_INTERVAL: u64 = 1 << 21;
+
+/// What to do with a file or directory offered by the sender.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OfferDecision {
+    Accept,
+    /// Accept, but save it under another name.
+    Rename(String),
+    Decline,
+}
+
+/// A file or directory the sender wants to send, waiting for an answer.
+///
+/// Nothing is written to disk before it is accepted. Dropping the offer declines it.
 #[derive(Debug)]
+pub struct TransferOffer {
+    pub transfer_id: TransferId,
+    pub metadata: FlapFileMetadata,
+    decision_tx: oneshot::Sender<OfferDecision>,
+}
+
+impl TransferOffer {
+    /// How many files are offered, 1 unless a directory is offered.
+    pub fn file_count(&self) -> usize {
+        self.metadata.files().len()
+    }
+
+    pub fn decide(self, decision: OfferDecision) {
+        // The transfer may have failed in the meantime
+        let _ = self.decision_tx.send(decision);
+    }
+
+    pub fn accept(self) {
+        self.decide(OfferDecision::Accept);
+    }
+
+    pub fn rename(self, file_name: String) {
+        self.decide(OfferDecision::Rename(file_name));
+    }
+
+    pub fn decline(self) {
+        self.decide(OfferDecision::Decline);
+    }
+}
+
+/// A received file whose name is taken by another file, waiting for an answer.
+///
+/// Dropping the conflict skips the file, keeping the other one.
+#[derive(Debug)]
+pub struct FileConflict {
+    pub transfer_id: TransferId,
+    /// Where the file would be saved, relative to the download directory.
+    pub path: PathBuf,
+    policy_tx: oneshot::Sender<ConflictPolicy>,
+}
+
+impl FileConflict {
+    pub fn resolve(self, policy: ConflictPolicy) {
+        // The transfer may have failed in the meantime
+        let _ = self.policy_tx.send(policy);
+    }
+}
+
+/// Where a received file is saved, once conflicts with other files are resolved.
+enum Destination {
+    /// No other file has this name.
+    Free(PathBuf),
+    Overwrite(PathBuf),
+    Skip,
+}
+
+/// Everything a stream needs to receive a file or directory.
+#[derive(Debug, Clone)]
+struct StreamContext {
+    secret_key: SecretKey,
+    ticket: Ticket,
+    file_saver: FileSaver,
+    offers_tx: Option<mpsc::UnboundedSender<TransferOffer>>,
+    cancellations: Cancellations,
+    pauses: Pauses,
+    partial_policy: PartialFilePolicy,
+    conflict_policy: ConflictPolicy,
+    conflicts_tx: Option<mpsc::UnboundedSender<FileConflict>>,
+    size_limits: SizeLimits,
+    selection: FileSelection,
+    session: SessionUsage,
+    batch: BatchProgress,
+    /// Bounds how many files are received at once.
+    streams: Arc<Semaphore>,
+}
+
+/// Clones share the endpoint and the running transfers, so a transfer
+/// retrieved by a clone can be cancelled or paused from any of them.
+#[derive(Debug, Clone)]
 pub struct P2pReceiver {
     p2p_endpoint: P2pEndpoint,
+    /// Where received files are saved. Uses the default of [`FileSaver`] if unset.
+    download_dir: Option<PathBuf>,
+    /// Where offers are sent. Everything is accepted if unset.
+    offers_tx: Option<mpsc::UnboundedSender<TransferOffer>>,
+    cancellations: Cancellations,
+    pauses: Pauses,
+    partial_policy: PartialFilePolicy,
+    reconnect_policy: ReconnectPolicy,
+    conflict_policy: ConflictPolicy,
+    /// Where conflicts are sent. Resolved with `conflict_policy` if unset.
+    conflicts_tx: Option<mpsc::UnboundedSender<FileConflict>>,
+    size_limits: SizeLimits,
+    selection: FileSelection,
+    max_streams: usize,
 }
 
 impl P2pReceiver {
-    pub async fn new() -> Result<Self> {
-        let p2p_endpoint = P2pEndpoint::start().await?;
+    pub async fn new(config: &P2pEndpointConfig) -> Result<Self> {
+        let p2p_endpoint = P2pEndpoint::start(config).await?;
+
+        Ok(Self::with_endpoint(p2p_endpoint, None))
+    }
+
+    pub(crate) fn with_endpoint(p2p_endpoint: P2pEndpoint, download_dir: Option<PathBuf>) -> Self {
+        Self {
+            p2p_endpoint,
+            download_dir,
+            offers_tx: None,
+            cancellations: Cancellations::default(),
+            pauses: Pauses::default(),
+            partial_policy: PartialFilePolicy::default(),
+            reconnect_policy: ReconnectPolicy::default(),
+            conflict_policy: ConflictPolicy::default(),
+            conflicts_tx: None,
+            size_limits: SizeLimits::default(),
+            selection: FileSelection::default(),
+            max_streams: DEFAULT_MAX_STREAMS,
+        }
+    }
+
+    /// Sets where received files are saved. The directory is created when a
+    /// transfer starts if needed. Defaults to [`FileSaver::default_download_dir`].
+    pub fn set_download_dir(&mut self, download_dir: PathBuf) {
+        self.download_dir = Some(download_dir);
+    }
+
+    /// Sets what happens to the partial file when a transfer is cancelled,
+    /// by either side. Partial files are kept by default.
+    pub fn set_partial_policy(&mut self, partial_policy: PartialFilePolicy) {
+        self.partial_policy = partial_policy;
+    }
+
+    /// Sets what happens to a received file whose name is taken by another file.
+    /// Such files are renamed by default.
+    pub fn set_conflict_policy(&mut self, conflict_policy: ConflictPolicy) {
+        self.conflict_policy = conflict_policy;
+    }
+
+    /// Asks what to do with each received file whose name is taken by another
+    /// file, instead of following the [`ConflictPolicy`].
+    ///
+    /// Every conflict is sent to the returned channel, and is also announced
+    /// with [`Event::FileConflict`].
+    pub fn ask_on_conflict(&mut self) -> mpsc::UnboundedReceiver<FileConflict> {
+        let (conflicts_tx, conflicts_rx) = mpsc::unbounded_channel();
+        self.conflicts_tx = Some(conflicts_tx);
+
+        conflicts_rx
+    }
+
+    /// Sets the largest files accepted. Files that do not fit, in the limits
+    /// or on the disk, are refused and the sender learns why.
+    pub fn set_size_limits(&mut self, size_limits: SizeLimits) {
+        self.size_limits = size_limits;
+    }
+
+    /// Sets which files to receive. The sender only sends those, and keeps the
+    /// others for the next receiver. Everything is received by default.
+    ///
+    /// Files of senders that do not announce them are received anyway, unless
+    /// they are part of a directory.
+    pub fn set_selection(&mut self, selection: FileSelection) {
+        self.selection = selection;
+    }
+
+    /// Sets how many files are received at once. Files the sender opens more
+    /// streams for wait for one of them to be done. At least one file is
+    /// always received.
+    pub fn set_max_streams(&mut self, max_streams: usize) {
+        self.max_streams = max_streams;
+    }
+
+    /// Sets how to reconnect once the connection to the sender is lost.
+    pub fn set_reconnect_policy(&mut self, reconnect_policy: ReconnectPolicy) {
+        self.reconnect_policy = reconnect_policy;
+    }
+
+    /// Cancels a running transfer. The sender learns why.
+    ///
+    /// Fails with [`Error::UnknownTransfer`] if the transfer is not running.
+    pub fn cancel(&self, transfer_id: TransferId, reason: CancelReason) -> Result<()> {
+        self.cancellations.cancel(transfer_id, reason)
+    }
 
-        Ok(Self { p2p_endpoint })
+    /// Asks the sender to stop sending the file blocks of a running transfer,
+    /// until it is resumed.
+    ///
+    /// Fails with [`Error::UnknownTransfer`] if the transfer is not running.
+    pub fn pause(&self, transfer_id: TransferId) -> Result<()> {
+        self.pauses.set_paused(transfer_id, true)
     }
 
+    /// Resumes a transfer paused with [`Self::pause`].
+    pub fn resume(&self, transfer_id: TransferId) -> Result<()> {
+        self.pauses.set_paused(transfer_id, false)
+    }
+
+    /// Asks before receiving each file or directory, instead of accepting everything.
+    ///
+    /// Every offer is sent to the returned channel, and must be answered before
+    /// the transfer starts. Each offer is also announced with [`Event::TransferOffered`].
+    pub fn ask_before_receiving(&mut self) -> mpsc::UnboundedReceiver<TransferOffer> {
+        let (offers_tx, offers_rx) = mpsc::unbounded_channel();
+        self.offers_tx = Some(offers_tx);
+
+        offers_rx
+    }
+
+    /// Lists the senders advertising themselves on the local network,
+    /// listening for announcements during `listen_for`.
+    ///
+    /// Only finds senders if this receiver was started with
+    /// [`crate::p2p::endpoint::DiscoveryMode::LocalNetwork`], and the senders too.
+    pub async fn nearby_senders(&self, listen_for: Duration) -> Vec<NodeAddr> {
+        let mut discovered = self.p2p_endpoint.discovery_stream();
+        let mut senders = BTreeMap::new();
+
+        let listen = async {
+            while let Some(item) = discovered.next().await {
+                // Lagging behind only means some announcements were missed
+                let Ok(item) = item else {
+                    continue;
+                };
+
+                let is_sender = item
+                    .user_data()
+                    .is_some_and(|user_data| user_data.as_ref() == SENDER_USER_DATA);
+                if item.provenance() == mdns::NAME && is_sender {
+                    senders.insert(item.node_id(), item.into_node_addr());
+                }
+            }
+        };
+        let _ = tokio::time::timeout(listen_for, listen).await;
+
+        senders.into_values().collect()
+    }
+
+    /// A rendezvous server, reached through the endpoint of the receiver.
+    ///
+    /// The ticket of a short code is then received with [`crate::code::ShortCode::receive_ticket`].
+    pub fn rendezvous(&self, server: impl Into<NodeAddr>) -> RemoteRendezvous {
+        RemoteRendezvous::new(self.p2p_endpoint.clone(), server)
+    }
+
+    /// Receives everything the sender of `ticket` sends, until either side closes the connection.
+    ///
+    /// If the connection is lost, reconnects according to the [`ReconnectPolicy`].
     pub async fn retrieve(&self, ticket: Ticket) -> Result<()> {
-        let connection = self
-            .p2p_endpoint
-            .connect(ticket.node_id.clone(), ALPN)
-            .await?;
+        let file_saver = match &self.download_dir {
+            Some(download_dir) => FileSaver::with_download_dir(download_dir.clone()).await?,
+            None => FileSaver::new().await?,
+        };
+        let mut connection = self.p2p_endpoint.connect(ticket.node_addr(), ALPN).await?;
 
         #[cfg(feature = "tracing")]
         info!("Connection established");
 
-        let file_saver = FileSaver::new().await;
+        let context = StreamContext {
+            secret_key: self.p2p_endpoint.secret_key().clone(),
+            ticket,
+            file_saver,
+            offers_tx: self.offers_tx.clone(),
+            cancellations: self.cancellations.clone(),
+            pauses: self.pauses.clone(),
+            partial_policy: self.partial_policy,
+            conflict_policy: self.conflict_policy,
+            conflicts_tx: self.conflicts_tx.clone(),
+            size_limits: self.size_limits,
+            selection: self.selection.clone(),
+            session: SessionUsage::default(),
+            batch: BatchProgress::default(),
+            streams: Arc::new(Semaphore::new(self.max_streams.max(1))),
+        };
+
+        loop {
+            let err = match self.receive_streams(&connection, &context).await {
+                Err(Error::ConnectionError(err)) if is_connection_lost(&err) => err,
+                result => return result,
+            };
+
+            #[cfg(feature = "tracing")]
+            info!("Connection lost: {err}");
+
+            connection = self
+                .reconnect(&context.ticket, Error::ConnectionError(err))
+                .await?;
+        }
+    }
+
+    /// Lists the files the sender of `ticket` is about to send, without
+    /// receiving any of them. Listing does not use up the ticket.
+    ///
+    /// Waits for the sender to queue a file if it has none.
+    pub async fn list(&self, ticket: Ticket) -> Result<Manifest> {
+        let connection = self.p2p_endpoint.connect(ticket.node_addr(), ALPN).await?;
+
+        let result = self.read_manifest(&connection, &ticket).await;
+        // The sender keeps every file for the next receiver
+        connection.close(CloseReason::Unknown.to_code(), b"listed");
+
+        result
+    }
+
+    /// Reads the first manifest announced on the control stream of `connection`.
+    async fn read_manifest(&self, connection: &Connection, ticket: &Ticket) -> Result<Manifest> {
+        let (stream_tx, stream_rx) = connection
+            .accept_bi()
+            .await
+            .map_err(Self::connection_error)?;
+        let mut encrypted_stream = EncryptionStream::initiate(
+            false,
+            self.p2p_endpoint.secret_key(),
+            &ticket.node_id,
+            stream_tx,
+            stream_rx,
+            ticket,
+        )
+        .await?;
+
+        let (mut entries, mut last) = match encrypted_stream.wait_for_opening().await? {
+            StreamOpening::Manifest(entries, last) => (entries, last),
+            // Senders whose control stream failed open the stream of a file right away
+            StreamOpening::Item(_) => return Err(Error::UnsupportedByPeer),
+        };
+        while !last {
+            let (part, part_last) = encrypted_stream.wait_for_manifest().await?;
+            entries.extend(part);
+            last = part_last;
+        }
+
+        Ok(Manifest { entries })
+    }
+
+    /// Connects to the sender again, waiting longer after every failed attempt.
+    ///
+    /// Fails with the last error once out of attempts.
+    async fn reconnect(&self, ticket: &Ticket, mut err: Error) -> Result<Connection> {
+        for attempt in 1.. {
+            let Some(delay) = self.reconnect_policy.delay(attempt) else {
+                break;
+            };
+
+            get_event_handler().send_event(Event::Reconnecting(attempt));
+            tokio::time::sleep(delay).await;
+
+            match self.p2p_endpoint.connect(ticket.node_addr(), ALPN).await {
+                Ok(connection) => {
+                    #[cfg(feature = "tracing")]
+                    info!("Connection established again");
+
+                    get_event_handler().send_event(Event::Reconnected);
+
+                    return Ok(connection);
+                }
+                Err(connect_err) => err = connect_err.into(),
+            }
+        }
+
+        Err(err)
+    }
+
+    /// Receives every stream the sender opens on `connection`, until it is closed.
+    ///
+    /// Streams still running when the connection is lost are dropped, their
+    /// files resume once the sender sends them again.
+    async fn receive_streams(
+        &self,
+        connection: &Connection,
+        context: &StreamContext,
+    ) -> Result<()> {
         // The set of all file decryptor streams.
         let mut file_streams: JoinSet<Result<()>> = JoinSet::new();
 
@@ -46,7 +448,10 @@ impl P2pReceiver {
                             #[cfg(feature = "tracing")]
                             info!("File downloaded and saved successfully.");
                         },
-                        Err(err) => panic!("err: {err:?}")
+                        Err(_err) => {
+                            #[cfg(feature = "tracing")]
+                            error!("File transfer failed: {_err}");
+                        }
                     }
                 },
                 res = connection.accept_bi() => {
@@ -59,94 +464,478 @@ impl P2pReceiver {
                             info!("Connecting to receiver stream");
 
                             // New file
-                            let mut encrypted_stream = EncryptionStream::initiate(
-                                false,
-                                self.p2p_endpoint.secret_key(),
-                                &connection.remote_node_id().unwrap(),
+                            file_streams.spawn(Self::receive_stream(
                                 stream_tx,
                                 stream_rx,
-                                &ticket,
-                            )
-                            .await
-                            .expect("noise handshake succeeds");
+                                context.clone(),
+                            ));
+                        },
+                        Err(ConnectionError::LocallyClosed) => {
+                            #[cfg(feature = "tracing")]
+                            info!("Connection closed");
 
-                            let file_metadata = encrypted_stream.get_file_metadata().await.unwrap();
+                            return Ok(());
+                        }
+                        Err(err) => return Err(Self::connection_error(err)),
+                    }
+                },
+            }
+        }
+    }
 
-                            #[cfg(feature = "tracing")]
-                            info!("File metadata acquired. Opening file...");
+    /// The error to report once `connection` fails, telling why the sender
+    /// closed it if it did, e.g. it rejected our ticket.
+    fn connection_error(err: ConnectionError) -> Error {
+        match err {
+            ConnectionError::ApplicationClosed(close)
+                if CloseReason::from_code(close.error_code) != CloseReason::Unknown =>
+            {
+                CloseReason::from_code(close.error_code).into()
+            }
+            err => err.into(),
+        }
+    }
 
-                            let (mut file, seek, hash) = file_saver.prepare_file(&file_metadata).await.unwrap();
+    /// Runs the handshake on a newly accepted stream, and receives the file
+    /// or directory sent over it, or the manifests if it is the control stream.
+    ///
+    /// If anything goes wrong, the stream is aborted and the peer learns why.
+    async fn receive_stream(
+        stream_tx: SendStream,
+        stream_rx: RecvStream,
+        context: StreamContext,
+    ) -> Result<()> {
+        let mut encrypted_stream = EncryptionStream::initiate(
+            false,
+            &context.secret_key,
+            &context.ticket.node_id,
+            stream_tx,
+            stream_rx,
+            &context.ticket,
+        )
+        .await?;
 
-                            if let Some(partial_hash) = hash {
-                                #[cfg(feature = "tracing")]
-                                info!("Partial hash detected");
+        let file_metadata = match encrypted_stream.wait_for_opening().await {
+            Ok(StreamOpening::Item(file_metadata)) => file_metadata,
+            Ok(StreamOpening::Manifest(entries, last)) => {
+                let result =
+                    Self::receive_manifests(&mut encrypted_stream, entries, last, &context).await;
+                if let Err(err) = &result {
+                    encrypted_stream.abort(err);
+                }
 
-                                encrypted_stream.set_file_hasher(partial_hash);
-                            }
+                return result;
+            }
+            Err(err) => {
+                encrypted_stream.abort(&err);
 
-                            #[cfg(feature = "tracing")]
-                            info!("Letting sender know we are ready to begin transfer");
-                            encrypted_stream.send_ready(seek).await.unwrap();
+                return Err(err);
+            }
+        };
+        encrypted_stream.set_cancel_token(
+            context
+                .cancellations
+                .register(encrypted_stream.transfer_id()),
+        );
+        encrypted_stream.set_pause_token(context.pauses.register(encrypted_stream.transfer_id()));
 
-                            get_event_handler().send_event(Event::PreparingFile(
-                                encrypted_stream.transfer_id(),
-                                file_metadata.clone(),
-                                false
-                            ));
+        // The control stream is not counted, it only carries manifests
+        let _permit = context
+            .streams
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+        let result = Self::receive_item(&mut encrypted_stream, file_metadata, &context).await;
+        match &result {
+            Ok(()) => {}
+            // The stream was already closed when the transfer was cancelled
+            Err(Error::TransferCancelled(reason)) => {
+                get_event_handler().send_event(Event::TransferCancelled(
+                    encrypted_stream.transfer_id(),
+                    *reason,
+                ));
+            }
+            Err(Error::TransferRefused(reason)) => {
+                get_event_handler().send_event(Event::TransferRefused(
+                    encrypted_stream.transfer_id(),
+                    *reason,
+                ));
+            }
+            Err(err) => encrypted_stream.abort(err),
+        }
 
-                            let file_saver_c = file_saver.clone();
-                            let mut total_bytes_received = 0;
-                            let fut = async move {
-                                loop {
-                                    #[cfg(feature = "tracing")]
-                                    info!("Reading file block from stream");
-                                    match encrypted_stream.recv_next_file_block(&mut file).await {
-                                        Ok(0) => {
-                                            file_saver_c.finish_file(&file_metadata).await.unwrap();
-
-                                            #[cfg(feature = "tracing")]
-                                            info!("Transfer completed");
-
-                                            get_event_handler().send_event(Event::TransferComplete(
-                                                encrypted_stream.transfer_id()
-                                            ));
-
-                                            break;
-                                        },
-                                        Ok(bytes_received) => {
-                                            // TODO: Ability to pause transfer
-                                            total_bytes_received += bytes_received;
-                                            get_event_handler().send_event(Event::TransferUpdate(
-                                                encrypted_stream.transfer_id(),
-                                                total_bytes_received as u64
-                                            ));
-                                        },
-                                        Err(e) => { return Err(e); }
-                                    }
-                                }
-
-                                Ok(())
-                            };
-
-                            file_streams.spawn(fut);
-                        },
-                        Err(ConnectionError::LocallyClosed) => {
-                            #[cfg(feature = "tracing")]
-                            info!("Stream closed")
-                        }
-                        Err(err) => {
-                            #[cfg(feature = "tracing")]
-                            error!("Something strange happeend while accepting stream: {err:?}");
+        result
+    }
 
-                            break;
-                        }
-                    }
-                },
-                else => {
+    /// Reads the manifests the sender announces on its control stream, starting
+    /// with the given part, until the connection is closed.
+    ///
+    /// Each manifest is answered with the files of the [`FileSelection`].
+    async fn receive_manifests(
+        encrypted_stream: &mut EncryptionStream,
+        mut entries: Vec<ManifestEntry>,
+        mut last: bool,
+        context: &StreamContext,
+    ) -> Result<()> {
+        let mut pending = Vec::new();
+        loop {
+            pending.extend(entries);
+            if last {
+                let (requested, selected): (Vec<_>, Vec<_>) = std::mem::take(&mut pending)
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, entry)| context.selection.is_selected(&entry.relative_path))
+                    .map(|(index, entry)| (index as u32, entry))
+                    .unzip();
+                if let Err(reason) = Self::check_manifest(&selected, context).await? {
+                    #[cfg(feature = "tracing")]
+                    info!("Refusing the files announced: {reason:?}");
+
+                    encrypted_stream.send_refuse(reason).await?;
+                    get_event_handler().send_event(Event::ManifestRefused(reason));
+                    (entries, last) = encrypted_stream.wait_for_manifest().await?;
+                    continue;
                 }
+                encrypted_stream.send_file_request(&requested).await?;
+                let manifest = context.batch.announce(selected);
+
+                #[cfg(feature = "tracing")]
+                info!(
+                    "Sender announced {} file(s), {} bytes in total",
+                    manifest.file_count(),
+                    manifest.total_size()
+                );
+
+                get_event_handler().send_event(Event::ManifestReceived(manifest));
             }
+
+            (entries, last) = encrypted_stream.wait_for_manifest().await?;
         }
+    }
+
+    /// Whether the announced `entries` fit together in the size limits and the
+    /// free space. Files are only hashed right before they are sent, so those
+    /// that can be resumed are counted whole.
+    async fn check_manifest(
+        entries: &[ManifestEntry],
+        context: &StreamContext,
+    ) -> Result<std::result::Result<(), RefuseReason>> {
+        let mut largest = 0;
+        let mut needed: u64 = 0;
+        for entry in entries {
+            largest = largest.max(entry.file_size);
+            needed = needed.saturating_add(entry.file_size);
+        }
+        let available_space = context.file_saver.available_space().await?;
+
+        Ok(context
+            .size_limits
+            .fits(largest, needed, available_space, &context.session))
+    }
+
+    /// Refuses the next file, which stops the transfer.
+    async fn refuse(encrypted_stream: &mut EncryptionStream, reason: RefuseReason) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        info!("Refusing file: {reason:?}");
+
+        encrypted_stream.send_refuse(reason).await?;
+        encrypted_stream.finish()?;
+
+        Err(Error::TransferRefused(reason))
+    }
+
+    /// Waits for the answer to an offer. Offers that are dropped are declined.
+    async fn ask(
+        offers_tx: &mpsc::UnboundedSender<TransferOffer>,
+        transfer_id: TransferId,
+        metadata: &FlapFileMetadata,
+    ) -> OfferDecision {
+        get_event_handler().send_event(Event::TransferOffered(transfer_id, metadata.clone()));
+
+        let (decision_tx, decision_rx) = oneshot::channel();
+        let offer = TransferOffer {
+            transfer_id,
+            metadata: metadata.clone(),
+            decision_tx,
+        };
+        if offers_tx.send(offer).is_err() {
+            return OfferDecision::Decline;
+        }
+
+        decision_rx.await.unwrap_or(OfferDecision::Decline)
+    }
+
+    /// Where to save the file described by `state`, if `relative_path` is taken
+    /// by another file.
+    async fn resolve_conflict(
+        context: &StreamContext,
+        transfer_id: TransferId,
+        relative_path: &Path,
+        state: &PartialState,
+    ) -> Result<Destination> {
+        let file_saver = &context.file_saver;
+        if !file_saver.is_taken(relative_path, state).await? {
+            return Ok(Destination::Free(relative_path.to_path_buf()));
+        }
+
+        let policy = match &context.conflicts_tx {
+            Some(conflicts_tx) => {
+                get_event_handler().send_event(Event::FileConflict(
+                    transfer_id,
+                    relative_path.to_path_buf(),
+                ));
+
+                let (policy_tx, policy_rx) = oneshot::channel();
+                let conflict = FileConflict {
+                    transfer_id,
+                    path: relative_path.to_path_buf(),
+                    policy_tx,
+                };
+                match conflicts_tx.send(conflict) {
+                    Ok(()) => policy_rx.await.unwrap_or(ConflictPolicy::Skip),
+                    Err(_) => ConflictPolicy::Skip,
+                }
+            }
+            None => context.conflict_policy,
+        };
+
+        Ok(match policy {
+            ConflictPolicy::Overwrite => Destination::Overwrite(relative_path.to_path_buf()),
+            ConflictPolicy::Skip => Destination::Skip,
+            ConflictPolicy::Rename => {
+                Destination::Free(file_saver.free_path(relative_path, state).await?)
+            }
+        })
+    }
+
+    /// Receives every file of a file or directory sent over a single stream.
+    async fn receive_item(
+        encrypted_stream: &mut EncryptionStream,
+        mut file_metadata: FlapFileMetadata,
+        context: &StreamContext,
+    ) -> Result<()> {
+        let file_saver = &context.file_saver;
+
+        #[cfg(feature = "tracing")]
+        info!("File metadata acquired. Opening file...");
+
+        // Names come from the sender, and must never escape the download directory.
+        validate_metadata(&file_metadata)?;
+
+        // Chosen by the names the sender gave, before the item is renamed
+        let selected: Vec<_> = file_metadata
+            .files()
+            .into_iter()
+            .map(|(relative_path, _)| context.selection.is_selected(&relative_path))
+            .collect();
+
+        if let Some(offers_tx) = &context.offers_tx {
+            let transfer_id = encrypted_stream.transfer_id();
+            match Self::ask(offers_tx, transfer_id, &file_metadata).await {
+                OfferDecision::Accept => {}
+                OfferDecision::Rename(file_name) => {
+                    validate_file_name(&file_name)?;
+                    file_metadata.file_name = file_name;
+                }
+                OfferDecision::Decline => {
+                    get_event_handler().send_event(Event::TransferDeclined(transfer_id));
+
+                    encrypted_stream.send_decline().await?;
+                    encrypted_stream.finish()?;
+
+                    return Ok(());
+                }
+            }
+        }
+
+        if !file_metadata.is_file() {
+            file_saver.prepare_dir(&file_metadata).await?;
+        }
+
+        get_event_handler().send_event(Event::PreparingFile(
+            encrypted_stream.transfer_id(),
+            file_metadata.clone(),
+            false,
+        ));
+
+        let mut total_bytes_received = 0;
+
+        for ((relative_path, metadata), selected) in file_metadata.files().into_iter().zip(selected)
+        {
+            // The sender announces the hash of each file right before it,
+            // even of those that are skipped
+            let root_hash = encrypted_stream.wait_for_file_hash().await?;
+
+            // The sender only sends items with a selected file, but not all
+            // files of a directory may be selected
+            if !selected {
+                #[cfg(feature = "tracing")]
+                info!("Skipping unselected file");
+
+                encrypted_stream.send_skip().await?;
+                continue;
+            }
+
+            let state = PartialState {
+                transfer_id: encrypted_stream.transfer_id(),
+                file_size: metadata.file_size,
+                root_hash,
+                bytes_verified: 0,
+            };
+
+            let transfer_id = encrypted_stream.transfer_id();
+            let (relative_path, overwrite) =
+                match Self::resolve_conflict(context, transfer_id, &relative_path, &state).await? {
+                    Destination::Free(path) => (path, false),
+                    Destination::Overwrite(path) => (path, true),
+                    Destination::Skip => {
+                        encrypted_stream.send_skip().await?;
+                        continue;
+                    }
+                };
+
+            // Checked before anything is written, and only what is missing
+            // from the partial file is written
+            let resumable = file_saver.resumable_len(&relative_path, &state).await?;
+            let needed = state.file_size.saturating_sub(resumable);
+            let available_space = file_saver.available_space().await?;
+            let mut reservation = match context.size_limits.check(
+                state.file_size,
+                needed,
+                available_space,
+                &context.session,
+            ) {
+                Ok(reservation) => reservation,
+                Err(reason) => return Self::refuse(encrypted_stream, reason).await,
+            };
+
+            let (file, state) = file_saver.prepare_file(&relative_path, state).await?;
+            let bytes_before = total_bytes_received;
+            let received = Self::receive_file(
+                encrypted_stream,
+                file,
+                state,
+                &relative_path,
+                overwrite,
+                context,
+                &mut total_bytes_received,
+            )
+            .await;
+            // What was not received is given back when the reservation is dropped
+            reservation.use_bytes((total_bytes_received - bytes_before) as u64);
+
+            if matches!(received, Err(Error::TransferCancelled(_)))
+                && context.partial_policy == PartialFilePolicy::Delete
+            {
+                file_saver.discard_partial(&relative_path).await?;
+            }
+            received?;
+        }
+
+        #[cfg(feature = "tracing")]
+        info!("Transfer completed");
+
+        get_event_handler().send_event(Event::TransferComplete(encrypted_stream.transfer_id()));
 
         Ok(())
     }
+
+    /// Receives a single file into its partial `file`, whose verified bytes are given by `state`.
+    ///
+    /// Unless told to `overwrite` it, whatever is saved at `relative_path` in
+    /// the meantime is a conflict.
+    async fn receive_file(
+        encrypted_stream: &mut EncryptionStream,
+        mut file: File,
+        mut state: PartialState,
+        relative_path: &Path,
+        overwrite: bool,
+        context: &StreamContext,
+        total_bytes_received: &mut usize,
+    ) -> Result<()> {
+        let file_saver = &context.file_saver;
+        let local_len = state.bytes_verified;
+        // Every block of the partial file was checked as it arrived, so only
+        // the last one is proven to the sender
+        let boundary_hash = if local_len > 0 {
+            #[cfg(feature = "tracing")]
+            info!("Partial file detected");
+
+            boundary_hash(&mut file, local_len).await?
+        } else {
+            FileHash::default()
+        };
+
+        #[cfg(feature = "tracing")]
+        info!("Letting sender know we are ready to begin transfer");
+        encrypted_stream
+            .send_ready(local_len, boundary_hash)
+            .await?;
+
+        let seek = encrypted_stream.wait_for_file_start().await?;
+        if seek != local_len {
+            if seek != 0 {
+                return Err(ProtocolError::InvalidResumeOffset(seek).into());
+            }
+
+            #[cfg(feature = "tracing")]
+            info!("Partial file does not match, receiving the whole file");
+
+            // The file is opened to append, so this is where it starts over
+            file.set_len(0).await?;
+            state.bytes_verified = 0;
+            file_saver.save_state(relative_path, &state).await?;
+        }
+        encrypted_stream.set_receiving_file(state.root_hash, state.file_size, seek);
+
+        let mut bytes_saved = state.bytes_verified;
+        loop {
+            #[cfg(feature = "tracing")]
+            info!("Reading file block from stream");
+            match encrypted_stream.recv_next_file_block(&mut file).await {
+                Ok(0) => {
+                    let destination = if overwrite {
+                        Destination::Overwrite(relative_path.to_path_buf())
+                    } else {
+                        let transfer_id = encrypted_stream.transfer_id();
+                        Self::resolve_conflict(context, transfer_id, relative_path, &state).await?
+                    };
+                    match destination {
+                        Destination::Free(saved_as) | Destination::Overwrite(saved_as) => {
+                            file_saver.finish_file(relative_path, &saved_as).await?
+                        }
+                        Destination::Skip => file_saver.discard_partial(relative_path).await?,
+                    }
+
+                    return Ok(());
+                }
+                Ok(bytes_received) => {
+                    // Blocks are only written once verified
+                    state.bytes_verified += bytes_received as u64;
+                    if state.bytes_verified - bytes_saved >= PARTIAL_STATE_SAVE_INTERVAL {
+                        file_saver.save_state(relative_path, &state).await?;
+                        bytes_saved = state.bytes_verified;
+                    }
+
+                    *total_bytes_received += bytes_received;
+                    get_event_handler().send_event(Event::TransferUpdate(
+                        encrypted_stream.transfer_id(),
+                        *total_bytes_received as u64,
+                    ));
+                    if let Some((bytes_received, total_size)) =
+                        context.batch.add_received(bytes_received as u64)
+                    {
+                        get_event_handler()
+                            .send_event(Event::BatchUpdate(bytes_received, total_size));
+                    }
+                }
+                Err(err) => {
+                    // So that the transfer can resume from here later
+                    file_saver.save_state(relative_path, &state).await?;
+
+                    return Err(err);
+                }
+            }
+        }
+    }
 }
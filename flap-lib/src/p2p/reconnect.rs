@@ -0,0 +1,85 @@
+//! Reconnecting to the sender once the connection to it is lost.
+//!
+//! The sender queues again the items it was sending, and sends them once the
+//! receiver is back. Unfinished files then resume from their partial file.
+
+use std::time::Duration;
+
+use iroh::endpoint::ConnectionError;
+
+use crate::error::CloseReason;
+
+/// How a [`crate::p2p::receiver::P2pReceiver`] reconnects once the connection is lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// How many times in a row to try reconnecting before giving up. 0 never reconnects.
+    pub max_attempts: u32,
+    /// How long to wait before the first attempt. Doubles with every failed attempt.
+    pub initial_delay: Duration,
+    /// The longest to wait between two attempts.
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    /// Gives up after about a minute and a half.
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// How long to wait before `attempt`, counted from 1. `None` once out of attempts.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt == 0 || attempt > self.max_attempts {
+            return None;
+        }
+
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+
+        Some(
+            self.initial_delay
+                .saturating_mul(factor)
+                .min(self.max_delay),
+        )
+    }
+}
+
+/// Whether the connection was lost, rather than closed on purpose by either side.
+pub(crate) fn is_connection_lost(err: &ConnectionError) -> bool {
+    match err {
+        ConnectionError::TimedOut
+        | ConnectionError::Reset
+        | ConnectionError::ConnectionClosed(_) => true,
+        // Closed without a reason, e.g. by the sender's network going away
+        ConnectionError::ApplicationClosed(close) => {
+            CloseReason::from_code(close.error_code) == CloseReason::Unknown
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_until_max() {
+        let policy = ReconnectPolicy {
+            max_attempts: 40,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+        };
+
+        assert_eq!(policy.delay(0), None);
+        assert_eq!(policy.delay(1), Some(Duration::from_secs(1)));
+        assert_eq!(policy.delay(2), Some(Duration::from_secs(2)));
+        assert_eq!(policy.delay(4), Some(Duration::from_secs(8)));
+        assert_eq!(policy.delay(5), Some(Duration::from_secs(10)));
+        assert_eq!(policy.delay(40), Some(Duration::from_secs(10)));
+        assert_eq!(policy.delay(41), None);
+    }
+}
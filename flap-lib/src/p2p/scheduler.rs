@@ -0,0 +1,369 @@
+//! Which queued files a sender sends next, and how many at once.
+//!
+//! Every receiver connected to a [`crate::p2p::sender::P2pSender`] takes files
+//! from the same queue. A receiver only takes its share of the queued files,
+//! and only once it started sending the previous ones, so that receivers
+//! connected at the same time each get some. Only receivers that asked for
+//! files count, and files a receiver did not ask for are left to the others.
+//! Receivers bound to a ticket that was replaced only get the files queued
+//! before it was, see [`crate::p2p::policy`].
+//! The streams of every receiver then share the same limit, handed out in turn.
+
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::fs::metadata::FlapFileMetadata;
+
+/// How many files are sent or received at once by default.
+pub const DEFAULT_MAX_STREAMS: usize = 4;
+
+/// In which order queued files are sent, among files of the same priority.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueueOrder {
+    /// In the order they were queued.
+    #[default]
+    Queued,
+    /// Smallest first, so that small files are not held up by large ones.
+    SmallestFirst,
+}
+
+/// How a sender schedules its streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedulerConfig {
+    /// How many files are sent at once, to every receiver together.
+    /// At least one file is always sent.
+    pub max_streams: usize,
+    pub order: QueueOrder,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_streams: DEFAULT_MAX_STREAMS,
+            order: QueueOrder::default(),
+        }
+    }
+}
+
+/// A file or directory waiting to be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct QueuedItem {
+    pub file_path: PathBuf,
+    /// Higher priorities are sent first.
+    pub priority: i32,
+    /// Read once when queued. `None` if the item could not be read, in which
+    /// case it fails once it is sent.
+    pub metadata: Option<FlapFileMetadata>,
+    /// The generation of the ticket handed out when the item was queued.
+    generation: u64,
+    /// When the item was first queued. Kept when it is queued again.
+    position: u64,
+}
+
+impl QueuedItem {
+    /// The size of the file, or of every file of the directory.
+    pub fn size(&self) -> u64 {
+        self.metadata
+            .as_ref()
+            .map_or(0, |metadata| metadata.file_size)
+    }
+}
+
+/// The queue shared by every receiver, and the streams they may open.
+#[derive(Debug, Clone)]
+pub(crate) struct Scheduler {
+    config: SchedulerConfig,
+    state: Arc<Mutex<SchedulerState>>,
+    queued: Arc<Notify>,
+    streams: Arc<Semaphore>,
+}
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+    queue: Vec<QueuedItem>,
+    next_position: u64,
+    /// Receivers currently connected that asked for files, sharing the queue.
+    receivers: usize,
+}
+
+/// A connected receiver, counted once it asks for files and until dropped.
+#[derive(Debug)]
+pub(crate) struct ConnectedReceiver {
+    state: Arc<Mutex<SchedulerState>>,
+    counted: bool,
+    /// The generation of the ticket it is bound to. Items queued for later
+    /// tickets are never given to it.
+    generation: u64,
+    /// The positions of the items it did not ask for, never given to it again.
+    unwanted: HashSet<u64>,
+}
+
+impl ConnectedReceiver {
+    /// Counts the receiver among those sharing the queue, once it asked for files.
+    pub fn wants_files(&mut self) {
+        if !self.counted {
+            self.counted = true;
+            self.state.lock().expect("lock is not poisoned").receivers += 1;
+        }
+    }
+}
+
+impl Drop for ConnectedReceiver {
+    fn drop(&mut self) {
+        if self.counted {
+            self.state.lock().expect("lock is not poisoned").receivers -= 1;
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Self {
+            config,
+            state: Arc::default(),
+            queued: Arc::default(),
+            streams: Arc::new(Semaphore::new(config.max_streams.max(1))),
+        }
+    }
+
+    /// Queues an item for the receivers of the ticket of `generation`, and of the tickets after it.
+    pub fn push(
+        &self,
+        file_path: PathBuf,
+        priority: i32,
+        generation: u64,
+        metadata: Option<FlapFileMetadata>,
+    ) {
+        let mut state = self.state.lock().expect("lock is not poisoned");
+        let position = state.next_position;
+        state.next_position += 1;
+        state.queue.push(QueuedItem {
+            file_path,
+            priority,
+            metadata,
+            generation,
+            position,
+        });
+        drop(state);
+
+        self.queued.notify_waiters();
+    }
+
+    /// Puts items back in the queue, for the next receiver to take them.
+    pub fn requeue(&self, items: impl IntoIterator<Item = QueuedItem>) {
+        let mut state = self.state.lock().expect("lock is not poisoned");
+        let len = state.queue.len();
+        state.queue.extend(items);
+        let requeued = state.queue.len() > len;
+        drop(state);
+
+        if requeued {
+            self.queued.notify_waiters();
+        }
+    }
+
+    /// Puts items a receiver did not ask for back in the queue, for the other
+    /// receivers only.
+    pub fn leave(&self, receiver: &mut ConnectedReceiver, items: Vec<QueuedItem>) {
+        receiver
+            .unwanted
+            .extend(items.iter().map(|item| item.position));
+        self.requeue(items);
+    }
+
+    /// A new connected receiver, bound to the ticket of `generation`, which is
+    /// not counted until it asks for files.
+    pub fn connect(&self, generation: u64) -> ConnectedReceiver {
+        ConnectedReceiver {
+            state: self.state.clone(),
+            counted: false,
+            generation,
+            unwanted: HashSet::new(),
+        }
+    }
+
+    /// Takes the share of the queued items of `receiver`, in the order they
+    /// should be sent. Waits for an item to be queued if there are none.
+    ///
+    /// With a single receiver connected, that is every queued item.
+    pub async fn next_batch(&self, receiver: &ConnectedReceiver) -> Vec<QueuedItem> {
+        loop {
+            // Registered first, so that items queued in the meantime are not missed
+            let queued = self.queued.notified();
+            tokio::pin!(queued);
+            queued.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().expect("lock is not poisoned");
+                let order = self.config.order;
+                state.queue.sort_by_key(|item| {
+                    let size = match order {
+                        QueueOrder::Queued => 0,
+                        QueueOrder::SmallestFirst => item.size(),
+                    };
+                    (Reverse(item.priority), size, item.position)
+                });
+
+                let (wanted, unwanted): (Vec<_>, Vec<_>) = std::mem::take(&mut state.queue)
+                    .into_iter()
+                    .partition(|item| {
+                        item.generation <= receiver.generation
+                            && !receiver.unwanted.contains(&item.position)
+                    });
+                state.queue = unwanted;
+                if !wanted.is_empty() {
+                    // Until it asks for files, the receiver is not counted yet
+                    let receivers = state.receivers + usize::from(!receiver.counted);
+                    let share = wanted.len().div_ceil(receivers.max(1));
+                    let mut wanted = wanted.into_iter();
+                    let batch: Vec<_> = wanted.by_ref().take(share).collect();
+                    state.queue.extend(wanted);
+                    drop(state);
+
+                    // Left for the other receivers
+                    self.queued.notify_waiters();
+
+                    return batch;
+                }
+            }
+            queued.await;
+        }
+    }
+
+    /// Waits for a stream to be free. Receivers waiting at the same time get one in turn.
+    pub async fn acquire_stream(&self) -> OwnedSemaphorePermit {
+        self.streams
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(file_name: &str, file_size: u64) -> Option<FlapFileMetadata> {
+        Some(FlapFileMetadata {
+            is_file: true,
+            dir_file_entries: None,
+            file_size,
+            file_name: file_name.to_string(),
+        })
+    }
+
+    fn paths(batch: &[QueuedItem]) -> Vec<&str> {
+        batch
+            .iter()
+            .map(|item| item.file_path.to_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn batches_follow_priority_then_order() {
+        for (order, expected) in [
+            (QueueOrder::Queued, ["urgent", "large", "small", "medium"]),
+            (
+                QueueOrder::SmallestFirst,
+                ["urgent", "small", "medium", "large"],
+            ),
+        ] {
+            let scheduler = Scheduler::new(SchedulerConfig {
+                max_streams: 1,
+                order,
+            });
+            let receiver = scheduler.connect(0);
+            for (path, priority, size) in [
+                ("large", 0, 3000),
+                ("small", 0, 10),
+                ("urgent", 1, 5000),
+                ("medium", 0, 500),
+            ] {
+                scheduler.push(path.into(), priority, 0, file(path, size));
+            }
+
+            let batch = scheduler.next_batch(&receiver).await;
+            assert_eq!(paths(&batch), expected);
+
+            // Requeued items keep their place
+            scheduler.requeue(batch.into_iter().rev());
+            assert_eq!(paths(&scheduler.next_batch(&receiver).await), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn receivers_share_the_queue() {
+        let scheduler = Scheduler::new(SchedulerConfig::default());
+        let mut first = scheduler.connect(0);
+        let mut second = scheduler.connect(0);
+        first.wants_files();
+        second.wants_files();
+        for path in ["a", "b", "c"] {
+            scheduler.push(path.into(), 0, 0, file(path, 0));
+        }
+
+        assert_eq!(paths(&scheduler.next_batch(&first).await), ["a", "b"]);
+        assert_eq!(paths(&scheduler.next_batch(&second).await), ["c"]);
+
+        // Alone again, the remaining receiver takes everything
+        drop(first);
+        for path in ["d", "e"] {
+            scheduler.push(path.into(), 0, 0, file(path, 0));
+        }
+        assert_eq!(paths(&scheduler.next_batch(&second).await), ["d", "e"]);
+    }
+
+    #[tokio::test]
+    async fn receivers_that_want_nothing_do_not_count() {
+        let scheduler = Scheduler::new(SchedulerConfig::default());
+        let mut wanting = scheduler.connect(0);
+        wanting.wants_files();
+        // Such as a receiver that only lists the files
+        let listing = scheduler.connect(0);
+        for path in ["a", "b", "c", "d"] {
+            scheduler.push(path.into(), 0, 0, file(path, 0));
+        }
+
+        // Counted as a second receiver while it has not asked for anything
+        let batch = scheduler.next_batch(&listing).await;
+        assert_eq!(paths(&batch), ["a", "b"]);
+        scheduler.requeue(batch);
+        assert_eq!(
+            paths(&scheduler.next_batch(&wanting).await),
+            ["a", "b", "c", "d"]
+        );
+
+        // Items a receiver did not ask for are only left to the others
+        let mut picky = scheduler.connect(0);
+        scheduler.push("e".into(), 0, 0, file("e", 0));
+        let batch = scheduler.next_batch(&picky).await;
+        assert_eq!(paths(&batch), ["e"]);
+        scheduler.leave(&mut picky, batch);
+        scheduler.push("f".into(), 0, 0, file("f", 0));
+        assert_eq!(paths(&scheduler.next_batch(&picky).await), ["f"]);
+        assert_eq!(paths(&scheduler.next_batch(&wanting).await), ["e"]);
+    }
+
+    #[tokio::test]
+    async fn receivers_of_replaced_tickets_only_get_earlier_items() {
+        let scheduler = Scheduler::new(SchedulerConfig::default());
+        let retired = scheduler.connect(0);
+        scheduler.push("a".into(), 0, 0, file("a", 0));
+        scheduler.push("b".into(), 0, 1, file("b", 0));
+
+        assert_eq!(paths(&scheduler.next_batch(&retired).await), ["a"]);
+        let current = scheduler.connect(1);
+        assert_eq!(paths(&scheduler.next_batch(&current).await), ["b"]);
+
+        // Left over items are still sent to the receivers of later tickets
+        scheduler.push("c".into(), 0, 0, file("c", 0));
+        assert_eq!(paths(&scheduler.next_batch(&current).await), ["c"]);
+    }
+}
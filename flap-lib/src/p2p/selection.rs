@@ -0,0 +1,141 @@
+//! Choosing which of the announced files to receive.
+//!
+//! Once the sender announces files in a [`crate::p2p::manifest::Manifest`],
+//! the receiver answers with the files it wants. The sender only sends those,
+//! and keeps the others queued for the next receiver.
+
+use std::path::Path;
+
+/// Which files a receiver wants.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum FileSelection {
+    /// Every file the sender sends.
+    #[default]
+    All,
+    /// Files matching any of the patterns.
+    ///
+    /// Patterns without a `/` are matched against the name of the file, such
+    /// as `*.pdf`. Others are matched against its path relative to the download
+    /// directory, such as `album/*.jpg`. `*` and `?` never match a `/`, while
+    /// `**` does.
+    Only(Vec<String>),
+}
+
+impl FileSelection {
+    pub fn only(patterns: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self::Only(patterns.into_iter().map(Into::into).collect())
+    }
+
+    /// Whether the file at `relative_path` is wanted.
+    pub fn is_selected(&self, relative_path: &Path) -> bool {
+        let Self::Only(patterns) = self else {
+            return true;
+        };
+
+        let components: Vec<_> = relative_path
+            .iter()
+            .map(|component| component.to_string_lossy())
+            .collect();
+        let path = components.join("/");
+        let file_name = components.last().map(|name| name.as_ref()).unwrap_or("");
+
+        patterns.iter().any(|pattern| {
+            if pattern.contains('/') {
+                glob_match(pattern, &path)
+            } else {
+                glob_match(pattern, file_name)
+            }
+        })
+    }
+}
+
+/// A part of a pattern, see [`FileSelection::Only`].
+#[derive(Debug, Clone, Copy)]
+enum Token {
+    /// `**`
+    AnyPath,
+    /// `*`
+    AnyName,
+    /// `?`
+    AnyChar,
+    Char(char),
+}
+
+/// Matches `text` against `pattern`, see [`FileSelection::Only`].
+///
+/// The text comes from the sender, so this takes time proportional to the length
+/// of the pattern times the length of the text, whatever they are.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '*' if chars.next_if_eq(&'*').is_some() => Token::AnyPath,
+            '*' => Token::AnyName,
+            '?' => Token::AnyChar,
+            c => Token::Char(c),
+        });
+    }
+    let text: Vec<char> = text.chars().collect();
+
+    // Whether the tokens after the current one match the text from each position
+    let mut rest_matches = vec![false; text.len() + 1];
+    rest_matches[text.len()] = true;
+    for token in tokens.into_iter().rev() {
+        let mut matches = vec![false; text.len() + 1];
+        for start in (0..=text.len()).rev() {
+            let next = text.get(start);
+            matches[start] = match token {
+                Token::AnyPath => rest_matches[start] || (next.is_some() && matches[start + 1]),
+                Token::AnyName => {
+                    rest_matches[start] || (next.is_some_and(|&c| c != '/') && matches[start + 1])
+                }
+                Token::AnyChar => next.is_some_and(|&c| c != '/') && rest_matches[start + 1],
+                Token::Char(expected) => next == Some(&expected) && rest_matches[start + 1],
+            };
+        }
+        rest_matches = matches;
+    }
+
+    rest_matches[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn selects_files_matching_patterns() {
+        let path = |path: &str| PathBuf::from_iter(path.split('/'));
+        let selection = FileSelection::only(["*.pdf", "album/*.jpg", "notes/**", "report-202?"]);
+
+        for selected in [
+            "a.pdf",
+            "docs/deep/b.pdf",
+            "album/c.jpg",
+            "notes/x/y.txt",
+            "report-2024",
+        ] {
+            assert!(selection.is_selected(&path(selected)), "{selected}");
+        }
+        for unselected in [
+            "a.pdf.txt",
+            "album/sub/c.jpg",
+            "other/album/c.jpg",
+            "notes",
+            "report-20245",
+            "c.jpg",
+        ] {
+            assert!(!selection.is_selected(&path(unselected)), "{unselected}");
+        }
+
+        assert!(FileSelection::All.is_selected(&path("anything")));
+        // Patterns that would backtrack a lot are matched quickly
+        let slow = FileSelection::only(["*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b"]);
+        assert!(!slow.is_selected(&path(&"a".repeat(1000))));
+
+        assert!(!FileSelection::Only(Vec::new()).is_selected(&path("anything")));
+    }
+}
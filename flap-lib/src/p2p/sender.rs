@@ -1,5 +1,5 @@
 use std::{
-    collections::HashSet,
+    collections::{HashSet, VecDeque},
     io::SeekFrom,
     ops::Deref,
     path::{Path, PathBuf},
@@ -8,54 +8,63 @@ use std::{
 
 use bytes::BytesMut;
 use iroh::{
-    Watcher,
+    NodeAddr, NodeId, Watcher,
     endpoint::Connection,
     protocol::{AcceptError, Router},
 };
-use tokio::{
-    fs::File,
-    io::AsyncSeekExt,
-    sync::{Mutex, mpsc},
-};
+use tokio::{fs::File, io::AsyncSeekExt, sync::Mutex, task::JoinSet};
 
 use crate::{
-    crypto::{blake3::Blake3, encryption_stream::EncryptionStream, master_key::MasterKey},
-    error::{Error, Result},
+    code::{ShortCode, rendezvous::Rendezvous, server::RemoteRendezvous},
+    crypto::{
+        blake3::HashTree,
+        encryption_stream::{EncryptionStream, FILE_BLOCK_SIZE, MAX_FILE_METADATA_SIZE},
+        transfer_id::TransferId,
+    },
+    error::{CloseReason, Error, ProtocolError, Result},
     event::{Event, get_event_handler},
     fs::metadata::FlapFileMetadata,
-    p2p::{ALPN, endpoint::P2pEndpoint},
+    p2p::{
+        ALPN,
+        cancel::{CancelReason, Cancellations},
+        endpoint::{P2pEndpoint, P2pEndpointConfig, SENDER_USER_DATA},
+        manifest::Manifest,
+        pause::Pauses,
+        policy::{TicketIssuer, TicketPolicy},
+        scheduler::{QueuedItem, Scheduler, SchedulerConfig},
+    },
     ticket::Ticket,
 };
 
 #[cfg(feature = "tracing")]
 use tracing::{error, info};
 
+/// The stream manifests are sent on, opened along with the first of them.
+enum ControlStream {
+    NotOpened,
+    Open(Box<EncryptionStream>),
+    /// The stream failed. Files are still sent, without being announced first.
+    Unavailable,
+}
+
 #[derive(Debug, Clone)]
 pub struct P2pSender {
     p2p_endpoint: P2pEndpoint,
-    files_queue_tx: mpsc::UnboundedSender<PathBuf>,
-    files_queue_rx: Arc<Mutex<mpsc::UnboundedReceiver<PathBuf>>>,
+    scheduler: Scheduler,
     files_added: Arc<Mutex<HashSet<PathBuf>>>,
-    pub ticket: Ticket,
+    tickets: Arc<Mutex<TicketIssuer>>,
+    cancellations: Cancellations,
+    pauses: Pauses,
 }
 
 impl P2pSender {
-    pub async fn new() -> Result<Self> {
-        let p2p_endpoint = P2pEndpoint::start().await?;
-        let node_addr = p2p_endpoint.node_addr().initialized().await;
-
-        let ticket = Ticket::make(node_addr.node_id, MasterKey::generate());
-
-        let (files_queue_tx, files_queue_rx) = mpsc::unbounded_channel();
-        let files_queue_rx = Arc::new(Mutex::new(files_queue_rx));
-        let files_added = Arc::new(Mutex::new(HashSet::new()));
-        let p2p_sender = Self {
-            p2p_endpoint: p2p_endpoint.clone(),
-            files_queue_tx,
-            files_queue_rx,
-            files_added,
-            ticket,
-        };
+    pub async fn new(
+        config: &P2pEndpointConfig,
+        policy: TicketPolicy,
+        scheduling: SchedulerConfig,
+    ) -> Result<Self> {
+        let p2p_endpoint = P2pEndpoint::start(config).await?;
+        let p2p_sender = Self::with_endpoint(p2p_endpoint.clone(), policy, scheduling).await;
 
         let router = Router::builder(p2p_endpoint.deref().clone())
             .accept(ALPN, p2p_sender.clone())
@@ -67,119 +76,504 @@ impl P2pSender {
         Ok(p2p_sender)
     }
 
+    /// A sender on `p2p_endpoint`, which does not accept connections on its own.
+    pub(crate) async fn with_endpoint(
+        p2p_endpoint: P2pEndpoint,
+        policy: TicketPolicy,
+        scheduling: SchedulerConfig,
+    ) -> Self {
+        p2p_endpoint.set_user_data_for_discovery(SENDER_USER_DATA.parse().ok());
+        let node_addr = p2p_endpoint.node_addr().initialized().await;
+
+        let tickets = Arc::new(Mutex::new(TicketIssuer::new(node_addr, policy)));
+
+        let files_added = Arc::new(Mutex::new(HashSet::new()));
+
+        Self {
+            p2p_endpoint,
+            scheduler: Scheduler::new(scheduling),
+            files_added,
+            tickets,
+            cancellations: Cancellations::default(),
+            pauses: Pauses::default(),
+        }
+    }
+
+    /// The ticket to share with the next receiver.
+    ///
+    /// Once the previous ticket is used up or expired, this is a fresh one.
+    pub async fn ticket(&self) -> Ticket {
+        self.tickets.lock().await.ticket()
+    }
+
+    /// A rendezvous server, reached through the endpoint of the sender.
+    pub fn rendezvous(&self, server: impl Into<NodeAddr>) -> RemoteRendezvous {
+        RemoteRendezvous::new(self.p2p_endpoint.clone(), server)
+    }
+
+    /// Gives a ticket to the receiver that enters `code`, see [`ShortCode::send_ticket`].
+    ///
+    /// The ticket, with the master key derived from the code, replaces the current one.
+    pub async fn send_code(&self, code: &ShortCode, rendezvous: &impl Rendezvous) -> Result<()> {
+        code.send_ticket(rendezvous, async |master_key| {
+            self.tickets.lock().await.issue(master_key)
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Cancels a running transfer. The receiver learns why.
+    ///
+    /// Fails with [`Error::UnknownTransfer`] if the transfer is not running.
+    pub fn cancel(&self, transfer_id: TransferId, reason: CancelReason) -> Result<()> {
+        self.cancellations.cancel(transfer_id, reason)
+    }
+
+    /// Stops sending the file blocks of a running transfer, until it is resumed.
+    /// The receiver learns of it.
+    ///
+    /// Fails with [`Error::UnknownTransfer`] if the transfer is not running.
+    pub fn pause(&self, transfer_id: TransferId) -> Result<()> {
+        self.pauses.set_paused(transfer_id, true)
+    }
+
+    /// Resumes a transfer paused with [`Self::pause`].
+    pub fn resume(&self, transfer_id: TransferId) -> Result<()> {
+        self.pauses.set_paused(transfer_id, false)
+    }
+
     pub async fn send(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.send_with_priority(path, 0).await
+    }
+
+    /// Queues a file or directory, to be sent before the files of lower
+    /// priorities. [`Self::send`] queues files with a priority of 0.
+    pub async fn send_with_priority(&self, path: impl AsRef<Path>, priority: i32) -> Result<()> {
         let file_path = path.as_ref().to_path_buf();
 
-        if self.files_added.lock().await.insert(file_path.clone()) {
-            self.files_queue_tx.send(file_path).map_err(|_| {
-                #[cfg(feature = "tracing")]
-                error!("error happened while preparing to send file");
+        if !self.files_added.lock().await.insert(file_path.clone()) {
+            return Err(Error::FileAlreadyAdded);
+        }
 
-                Error::MpscSendError
-            })?;
+        // Files that cannot be read fail once they are sent
+        let metadata = FlapFileMetadata::from_path(&file_path).await.ok();
+        let generation = self.tickets.lock().await.generation();
+        self.scheduler
+            .push(file_path, priority, generation, metadata);
 
-            Ok(())
-        } else {
-            Err(Error::FileAlreadyAdded)
-        }
+        Ok(())
     }
-}
 
-impl iroh::protocol::ProtocolHandler for P2pSender {
-    fn accept(
+    /// Announces every file of `batch` on the control stream, opening it first if needed.
+    ///
+    /// Returns which items of `batch` the receiver asked for. Receivers that
+    /// cannot choose are sent everything.
+    async fn announce(
         &self,
-        connection: Connection,
-    ) -> impl Future<Output = std::result::Result<(), AcceptError>> + Send {
-        let files_queue_rx = self.files_queue_rx.clone();
-        Box::pin(async move {
-            while let Some(file_path) = files_queue_rx.lock().await.recv().await {
-                let (file_stream_tx, file_stream_rx) = connection.open_bi().await.unwrap();
+        connection: &Connection,
+        control_stream: &mut ControlStream,
+        receiver: NodeId,
+        ticket: &Ticket,
+        batch: &[QueuedItem],
+    ) -> Result<Vec<bool>> {
+        // Announcing does not bind the ticket, so that receivers can list the
+        // files before choosing which of them to receive.
+        if let ControlStream::NotOpened = control_stream {
+            let (stream_tx, stream_rx) = connection.open_bi().await?;
+            let encrypted_stream = EncryptionStream::initiate(
+                true,
+                self.p2p_endpoint.secret_key(),
+                &receiver,
+                stream_tx,
+                stream_rx,
+                ticket,
+            )
+            .await?;
+            *control_stream = ControlStream::Open(Box::new(encrypted_stream));
+        }
+        let ControlStream::Open(encrypted_stream) = control_stream else {
+            return Ok(vec![true; batch.len()]);
+        };
 
-                #[cfg(feature = "tracing")]
-                info!("Opened stream");
-
-                let mut encrypted_stream = EncryptionStream::initiate(
-                    true,
-                    self.p2p_endpoint.secret_key(),
-                    &connection.remote_node_id().unwrap(),
-                    file_stream_tx,
-                    file_stream_rx,
-                    &self.ticket,
-                )
-                .await
-                .expect("noise handshake succeeds");
+        // The files of each item, as indices in the manifest
+        let mut manifest = Manifest::default();
+        let mut item_files = Vec::with_capacity(batch.len());
+        for item in batch {
+            let first_file = manifest.file_count();
+            // Files that cannot be read fail once they are sent
+            if let Some(metadata) = &item.metadata {
+                manifest.add(metadata);
+            }
+            item_files.push(first_file..manifest.file_count());
+        }
+
+        #[cfg(feature = "tracing")]
+        info!("Announcing {} file(s)", manifest.file_count());
 
+        let requested = match Self::exchange_manifest(encrypted_stream, &manifest).await {
+            Ok(requested) => requested,
+            // None of the files fit, they are kept for other receivers
+            Err(Error::TransferRefused(_reason)) => {
                 #[cfg(feature = "tracing")]
-                info!("Reading file metadata");
-                let file_metadata = FlapFileMetadata::from_path(&file_path).await;
+                info!("Receiver refused the files announced: {_reason:?}");
+
+                return Ok(vec![false; batch.len()]);
+            }
+            Err(err) => {
+                encrypted_stream.abort(&err);
+                *control_stream = ControlStream::Unavailable;
+
+                return Err(err);
+            }
+        };
+
+        // Items without files, such as empty directories, have nothing to choose from
+        Ok(item_files
+            .into_iter()
+            .map(|mut files| {
+                files.is_empty() || files.any(|index| requested.contains(&(index as u32)))
+            })
+            .collect())
+    }
+
+    /// Sends `manifest`, and waits for the indices of the files the receiver
+    /// asks for.
+    async fn exchange_manifest(
+        encrypted_stream: &mut EncryptionStream,
+        manifest: &Manifest,
+    ) -> Result<HashSet<u32>> {
+        encrypted_stream.send_manifest(manifest).await?;
+
+        let requested = encrypted_stream.wait_for_file_request().await?;
+
+        #[cfg(feature = "tracing")]
+        info!("Receiver asked for {} file(s)", requested.len());
+
+        Ok(requested.into_iter().collect())
+    }
+
+    /// Sends a single queued file or directory over a new stream.
+    ///
+    /// If anything goes wrong, the stream is aborted and the receiver learns why.
+    ///
+    /// Directories are sent on the same stream, one file after the other,
+    /// with the receiver asking for each file so that it can resume them individually.
+    async fn send_item(
+        &self,
+        connection: &Connection,
+        receiver: NodeId,
+        generation: u64,
+        ticket: &Ticket,
+        item: &QueuedItem,
+    ) -> Result<()> {
+        let (file_stream_tx, file_stream_rx) = connection.open_bi().await?;
+
+        #[cfg(feature = "tracing")]
+        info!("Opened stream");
+
+        let mut encrypted_stream = EncryptionStream::initiate(
+            true,
+            self.p2p_endpoint.secret_key(),
+            &receiver,
+            file_stream_tx,
+            file_stream_rx,
+            ticket,
+        )
+        .await?;
+        encrypted_stream
+            .set_cancel_token(self.cancellations.register(encrypted_stream.transfer_id()));
+        encrypted_stream.set_pause_token(self.pauses.register(encrypted_stream.transfer_id()));
 
-                get_event_handler().send_event(Event::PreparingFile(
+        // The receiver proved it knows the ticket, which is now bound to it
+        let result = match self.tickets.lock().await.bind(receiver, generation) {
+            Ok(()) => self.send_files(&mut encrypted_stream, item).await,
+            Err(err) => Err(err),
+        };
+        match &result {
+            Ok(()) => {}
+            // The receiver is done with this stream, nothing to abort
+            Err(Error::TransferDeclined) => {
+                get_event_handler()
+                    .send_event(Event::TransferDeclined(encrypted_stream.transfer_id()));
+                let _ = encrypted_stream.finish();
+            }
+            Err(Error::TransferRefused(reason)) => {
+                get_event_handler().send_event(Event::TransferRefused(
                     encrypted_stream.transfer_id(),
-                    file_metadata.clone(),
-                    true,
+                    *reason,
                 ));
+                let _ = encrypted_stream.finish();
+            }
+            // The stream was already closed when the transfer was cancelled
+            Err(Error::TransferCancelled(reason)) => {
+                get_event_handler().send_event(Event::TransferCancelled(
+                    encrypted_stream.transfer_id(),
+                    *reason,
+                ));
+            }
+            Err(err) => encrypted_stream.abort(err),
+        }
+
+        result
+    }
+
+    async fn send_files(
+        &self,
+        encrypted_stream: &mut EncryptionStream,
+        item: &QueuedItem,
+    ) -> Result<()> {
+        let file_path = &item.file_path;
+        let file_metadata = match &item.metadata {
+            Some(metadata) => metadata.clone(),
+            // Read again, for its error
+            None => FlapFileMetadata::from_path(file_path).await?,
+        };
+
+        if file_metadata.to_bytes().len() > MAX_FILE_METADATA_SIZE {
+            return Err(Error::MetadataTooLarge);
+        }
+
+        get_event_handler().send_event(Event::PreparingFile(
+            encrypted_stream.transfer_id(),
+            file_metadata.clone(),
+            true,
+        ));
+
+        #[cfg(feature = "tracing")]
+        info!("Sending file metadata to receiver");
 
+        encrypted_stream
+            .send_file_metadata(file_metadata.clone())
+            .await?;
+
+        let mut count = 0;
+        let mut file_buf = BytesMut::zeroed(FILE_BLOCK_SIZE);
+
+        for (relative_path, metadata) in file_metadata.files() {
+            #[cfg(feature = "tracing")]
+            info!("Opening file");
+            let mut file = File::open(Self::source_path(file_path, &relative_path)).await?;
+
+            // Hashed right before it is sent, so that only the tree of this file is kept.
+            // The receiver checks each block against its hash, so it is sent first.
+            #[cfg(feature = "tracing")]
+            info!("Hashing file");
+            let tree = HashTree::from_file(&mut file).await?;
+            let hash_sent = encrypted_stream.send_file_hash(tree.root()).await;
+
+            #[cfg(feature = "tracing")]
+            info!("Waiting for receiver's ready...");
+            // The receiver may have declined the item already and stopped
+            // reading, so its answer comes before the hash failing to be sent
+            let ready = encrypted_stream.wait_for_ready().await?;
+            hash_sent?;
+            let Some((mut seek, boundary_hash)) = ready else {
                 #[cfg(feature = "tracing")]
-                info!("Sending file metadata to receiver");
+                info!("Receiver skips this file");
 
-                encrypted_stream
-                    .send_file_metadata(file_metadata)
-                    .await
-                    .expect("file metadata sends");
+                continue;
+            };
 
+            // Blocks are checked on their own, so the transfer can only resume from one
+            if !seek.is_multiple_of(FILE_BLOCK_SIZE as u64) {
+                return Err(ProtocolError::InvalidResumeOffset(seek).into());
+            }
+            // The partial file may be from another file with the same name
+            if seek != 0 && tree.boundary_hash(seek / FILE_BLOCK_SIZE as u64) != Some(boundary_hash)
+            {
                 #[cfg(feature = "tracing")]
-                info!("Waiting for receiver's ready...");
-                let seek = encrypted_stream.wait_for_ready().await.expect("stream ok");
+                info!("Partial file does not match, sending the whole file");
 
+                seek = 0;
+            }
+            encrypted_stream.send_file_start(seek).await?;
+
+            // Hashing read the file up to its end
+            #[cfg(feature = "tracing")]
+            info!("Seeking to the first block to send");
+            file.seek(SeekFrom::Start(seek)).await?;
+            encrypted_stream.set_sending_file(tree, metadata.file_size, seek);
+
+            loop {
                 #[cfg(feature = "tracing")]
-                info!("Opening file");
-                let mut file = File::open(file_path).await.unwrap();
+                info!("Sending file block to stream");
+                match encrypted_stream
+                    .send_next_file_block(&mut file, &mut file_buf)
+                    .await?
+                {
+                    0 => {
+                        #[cfg(feature = "tracing")]
+                        info!("File EOF reached");
 
-                if seek != 0 {
-                    #[cfg(feature = "tracing")]
-                    info!("Partial file detected, seeking to end of file");
+                        break;
+                    }
+                    bytes_read => {
+                        count += bytes_read;
 
-                    encrypted_stream.set_file_hasher(
-                        Blake3::partial_hash(&mut file, Some(seek))
-                            .await
-                            .expect("File can be read and hashed"),
-                    );
-                    file.seek(SeekFrom::Start(seek)).await?;
+                        get_event_handler().send_event(Event::TransferUpdate(
+                            encrypted_stream.transfer_id(),
+                            count as u64,
+                        ));
+                    }
                 }
+            }
+        }
 
-                let mut count = 0;
-                let mut file_buf = BytesMut::zeroed(1 << 15);
+        encrypted_stream.finish()?;
 
-                loop {
-                    #[cfg(feature = "tracing")]
-                    info!("Sending file block to stream");
-                    match encrypted_stream
-                        .send_next_file_block(&mut file, &mut file_buf)
-                        .await
-                    {
-                        Ok(0) => {
-                            #[cfg(feature = "tracing")]
-                            info!("File EOF reached, transfer completed");
+        #[cfg(feature = "tracing")]
+        info!("Transfer completed");
 
-                            get_event_handler().send_event(Event::TransferComplete(
-                                encrypted_stream.transfer_id(),
-                            ));
+        get_event_handler().send_event(Event::TransferComplete(encrypted_stream.transfer_id()));
+
+        Ok(())
+    }
+
+    /// Where the file at `relative_path` (as given by [`FlapFileMetadata::files`]) is on disk.
+    fn source_path(file_path: &Path, relative_path: &Path) -> PathBuf {
+        // The relative path starts with the name of the item that was queued,
+        // which is already the last component of `file_path`.
+        relative_path
+            .components()
+            .skip(1)
+            .fold(file_path.to_path_buf(), |path, component| {
+                path.join(component)
+            })
+    }
 
+    /// Handles a stream that failed, putting its item back in the queue if it
+    /// is to be sent again. Returns whether the connection is over.
+    fn stream_failed(&self, connection: &Connection, item: QueuedItem, err: Error) -> bool {
+        #[cfg(feature = "tracing")]
+        error!("{err}");
+
+        if matches!(err, Error::TicketExpired | Error::TicketAlreadyUsed) {
+            // Still waiting for the receiver the ticket is bound to
+            self.scheduler.requeue([item]);
+            Self::reject(connection, &err);
+
+            return true;
+        }
+
+        if connection.close_reason().is_some() {
+            // Sent again once the receiver reconnects
+            if !matches!(
+                err,
+                Error::TransferDeclined | Error::TransferCancelled(_) | Error::TransferRefused(_)
+            ) {
+                self.scheduler.requeue([item]);
+            }
+
+            return true;
+        }
+
+        false
+    }
+
+    /// Closes the connection of a receiver that may not redeem the ticket.
+    fn reject(connection: &Connection, err: &Error) {
+        #[cfg(feature = "tracing")]
+        info!("Rejecting receiver: {err}");
+
+        connection.close(CloseReason::from(err).to_code(), err.to_string().as_bytes());
+    }
+}
+
+impl iroh::protocol::ProtocolHandler for P2pSender {
+    fn accept(
+        &self,
+        connection: Connection,
+    ) -> impl Future<Output = std::result::Result<(), AcceptError>> + Send {
+        Box::pin(async move {
+            let receiver = connection.remote_node_id()?;
+
+            // Turn away receivers that can no longer redeem the ticket
+            // before they take anything from the queue.
+            let (generation, ticket) = match self.tickets.lock().await.ticket_for(&receiver) {
+                Ok(ticket) => ticket,
+                Err(err) => {
+                    Self::reject(&connection, &err);
+                    return Ok(());
+                }
+            };
+
+            let mut connected = self.scheduler.connect(generation);
+            let mut control_stream = ControlStream::NotOpened;
+            // Items taken from the queue, waiting for a stream
+            let mut pending: VecDeque<QueuedItem> = VecDeque::new();
+            let mut streams: JoinSet<(QueuedItem, Result<()>)> = JoinSet::new();
+
+            loop {
+                tokio::select! {
+                    Some(joined) = streams.join_next() => {
+                        if let Ok((item, Err(err))) = joined
+                            && self.stream_failed(&connection, item, err)
+                        {
                             break;
                         }
-                        Ok(bytes_read) => {
-                            count += bytes_read;
+                    }
+                    permit = self.scheduler.acquire_stream(), if !pending.is_empty() => {
+                        let Some(item) = pending.pop_front() else {
+                            continue;
+                        };
+                        let sender = self.clone();
+                        let (connection, ticket) = (connection.clone(), ticket.clone());
+                        streams.spawn(async move {
+                            let result = sender
+                                .send_item(&connection, receiver, generation, &ticket, &item)
+                                .await;
+                            drop(permit);
 
-                            get_event_handler().send_event(Event::TransferUpdate(
-                                encrypted_stream.transfer_id(),
-                                count as u64,
-                            ));
-                        }
-                        Err(e) => {
-                            error!("{e}");
-                            break;
+                            (item, result)
+                        });
+                    }
+                    // Only once every item taken was started, so that the
+                    // other receivers get the items queued in the meantime
+                    batch = self.scheduler.next_batch(&connected), if pending.is_empty() => {
+                        match self
+                            .announce(&connection, &mut control_stream, receiver, &ticket, &batch)
+                            .await
+                        {
+                            Ok(requested) => {
+                                // Left to the other receivers right away
+                                let mut unrequested = Vec::new();
+                                for (item, requested) in batch.into_iter().zip(requested) {
+                                    if requested {
+                                        pending.push_back(item);
+                                    } else {
+                                        unrequested.push(item);
+                                    }
+                                }
+                                if !pending.is_empty() {
+                                    connected.wants_files();
+                                }
+                                self.scheduler.leave(&mut connected, unrequested);
+                            }
+                            Err(err) => {
+                                #[cfg(feature = "tracing")]
+                                error!("Could not announce files: {err}");
+
+                                if connection.close_reason().is_some() {
+                                    self.scheduler.requeue(batch);
+                                    break;
+                                }
+                                // Not fatal, the files are still sent without being announced
+                                connected.wants_files();
+                                pending.extend(batch);
+                            }
                         }
                     }
+                    // Receivers that only list the files leave without taking any
+                    _ = connection.closed() => break,
+                }
+            }
+
+            // Whatever was not sent is left to the next receiver
+            self.scheduler.requeue(pending);
+            while let Some(joined) = streams.join_next().await {
+                if let Ok((item, Err(err))) = joined {
+                    self.stream_failed(&connection, item, err);
                 }
             }
 
@@ -0,0 +1,1296 @@
+//! End-to-end transfers between a [`P2pSender`] and a [`P2pReceiver`]
+//! running in the same process, connected over localhost only.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use iroh::{
+    Watcher,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler, Router},
+};
+use tempfile::TempDir;
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use crate::{
+    code::{
+        ShortCode,
+        rendezvous::{Phase, Rendezvous, Side},
+        server::{MAX_NAMEPLATES_PER_CONNECTION, RemoteRendezvous, RendezvousServer},
+    },
+    crypto::{encryption_stream::FILE_BLOCK_SIZE, random_array, transfer_id::TransferId},
+    error::{CloseReason, Error},
+    event::{Event, get_event_handler},
+    fs::{
+        metadata::FlapFileMetadata,
+        partial::PartialState,
+        save::{ConflictPolicy, FileSaver, PartialFilePolicy},
+    },
+    p2p::{
+        ALPN,
+        cancel::CancelReason,
+        endpoint::{P2pEndpoint, P2pEndpointConfig},
+        frame::MAX_FRAME_OPTIONAL_DATA_SIZE,
+        limits::{RefuseReason, SizeLimits},
+        policy::TicketPolicy,
+        receiver::P2pReceiver,
+        scheduler::{QueueOrder, SchedulerConfig},
+        selection::FileSelection,
+        sender::P2pSender,
+    },
+    ticket::Ticket,
+};
+
+/// Events are global, so transfers must not run concurrently
+/// or they would steal each other's events.
+static TRANSFER_LOCK: Mutex<()> = Mutex::const_new(());
+
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Events of a single transfer.
+///
+/// Both sides derive the same [`TransferId`] from the handshake,
+/// so the events of the sender and of the receiver are mixed.
+#[derive(Debug, Default)]
+pub(crate) struct TransferEvents {
+    pub file_name: String,
+    pub updates: Vec<u64>,
+    /// Once by the sender, once by the receiver
+    pub completions: usize,
+    /// Once by the sender, once by the receiver
+    pub declines: usize,
+    /// Once by the sender, once by the receiver
+    pub cancellations: Vec<CancelReason>,
+    /// Once by the sender, once by the receiver
+    pub refusals: Vec<RefuseReason>,
+    /// Once by the side that paused, once by the other side
+    pub pauses: usize,
+    pub resumes: usize,
+    /// Files whose name was taken, when asking what to do with them
+    pub conflicts: usize,
+}
+
+impl TransferEvents {
+    pub fn bytes_transferred(&self) -> u64 {
+        self.updates.iter().copied().max().unwrap_or(0)
+    }
+}
+
+/// A sender and a receiver, each with their own directory.
+pub(crate) struct Loopback {
+    pub sender: P2pSender,
+    /// The ticket the receiver uses.
+    pub ticket: Ticket,
+    pub send_dir: TempDir,
+    pub download_dir: TempDir,
+    pub receiver: Option<P2pReceiver>,
+}
+
+/// Accepts the connections to a sender in its place, and keeps them.
+#[derive(Debug, Clone)]
+struct KeepConnections {
+    sender: P2pSender,
+    connections: Arc<std::sync::Mutex<Vec<Connection>>>,
+}
+
+impl ProtocolHandler for KeepConnections {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        self.connections
+            .lock()
+            .expect("lock is not poisoned")
+            .push(connection.clone());
+
+        self.sender.accept(connection).await
+    }
+}
+
+/// The connections to a sender, which tests can drop.
+pub struct DroppableConnections {
+    connections: Arc<std::sync::Mutex<Vec<Connection>>>,
+    _router: Router,
+}
+
+impl DroppableConnections {
+    fn accept(p2p_endpoint: &P2pEndpoint, sender: P2pSender) -> Self {
+        let connections = Arc::default();
+        let handler = KeepConnections {
+            sender,
+            connections: Arc::clone(&connections),
+        };
+        let router = Router::builder(p2p_endpoint.deref().clone())
+            .accept(ALPN, handler)
+            .spawn();
+
+        Self {
+            connections,
+            _router: router,
+        }
+    }
+
+    /// Closes every connection without a reason, as if the network went away.
+    pub fn drop_all(&self) {
+        for connection in self
+            .connections
+            .lock()
+            .expect("lock is not poisoned")
+            .drain(..)
+        {
+            connection.close(CloseReason::Unknown.to_code(), b"dropped");
+        }
+    }
+}
+
+impl Loopback {
+    pub async fn new() -> Self {
+        // The receiver finds the sender through the addresses of the ticket only
+        Self::with_config(&P2pEndpointConfig::loopback(), SchedulerConfig::default()).await
+    }
+
+    pub async fn with_config(config: &P2pEndpointConfig, scheduling: SchedulerConfig) -> Self {
+        let sender = P2pSender::new(config, TicketPolicy::default(), scheduling)
+            .await
+            .unwrap();
+
+        Self::with_sender(config, sender).await
+    }
+
+    /// Like [`Self::new`], with connections to the sender that can be dropped.
+    pub async fn with_droppable_connections() -> (Self, DroppableConnections) {
+        let config = P2pEndpointConfig::loopback();
+        let p2p_endpoint = P2pEndpoint::start(&config).await.unwrap();
+        let sender = P2pSender::with_endpoint(
+            p2p_endpoint.clone(),
+            TicketPolicy::default(),
+            SchedulerConfig::default(),
+        )
+        .await;
+        let connections = DroppableConnections::accept(&p2p_endpoint, sender.clone());
+
+        (Self::with_sender(&config, sender).await, connections)
+    }
+
+    async fn with_sender(config: &P2pEndpointConfig, sender: P2pSender) -> Self {
+        let ticket = sender.ticket().await;
+
+        let download_dir = tempfile::tempdir().unwrap();
+        let receiver_endpoint = P2pEndpoint::start(config).await.unwrap();
+        let receiver =
+            P2pReceiver::with_endpoint(receiver_endpoint, Some(download_dir.path().to_path_buf()));
+
+        Self {
+            sender,
+            ticket,
+            send_dir: tempfile::tempdir().unwrap(),
+            download_dir,
+            receiver: Some(receiver),
+        }
+    }
+
+    /// Writes a partial file in the receiver's directory, as left by a
+    /// transfer of `content` that stopped after `partial_len` bytes.
+    pub async fn create_partial(
+        &self,
+        relative_path: impl AsRef<Path>,
+        content: &[u8],
+        partial_len: usize,
+    ) {
+        let relative_path = relative_path.as_ref();
+        let file_saver = FileSaver::with_download_dir(self.download_dir.path().to_path_buf())
+            .await
+            .unwrap();
+        let state = PartialState {
+            transfer_id: TransferId([0; 32]),
+            file_size: content.len() as u64,
+            root_hash: *blake3::hash(content).as_bytes(),
+            bytes_verified: partial_len as u64,
+        };
+        file_saver.save_state(relative_path, &state).await.unwrap();
+
+        let mut partial_path = self.downloaded(relative_path).into_os_string();
+        partial_path.push(".flap");
+        tokio::fs::write(partial_path, &content[..partial_len])
+            .await
+            .unwrap();
+    }
+
+    /// Creates a file of `len` random bytes in the sender's directory.
+    pub async fn create_file(&self, relative_path: impl AsRef<Path>, len: usize) -> PathBuf {
+        let path = self.send_dir.path().join(relative_path);
+        tokio::fs::create_dir_all(path.parent().unwrap())
+            .await
+            .unwrap();
+
+        let mut content = Vec::with_capacity(len);
+        while content.len() < len {
+            content.extend_from_slice(&random_array::<1024>());
+        }
+        content.truncate(len);
+        tokio::fs::write(&path, content).await.unwrap();
+
+        path
+    }
+
+    /// Starts receiving in the background. Resolves once `transfers` items
+    /// have been fully sent and received, and returns the events of each transfer.
+    pub async fn receive(&mut self, transfers: usize) -> HashMap<TransferId, TransferEvents> {
+        self.receive_with(transfers, |_, _, _| {}).await
+    }
+
+    /// Like [`Self::receive`], calling `on_event` with every event, and
+    /// counting transfers declined, cancelled or refused on both sides as done.
+    pub async fn receive_with(
+        &mut self,
+        transfers: usize,
+        mut on_event: impl FnMut(&Event, &P2pSender, &P2pReceiver),
+    ) -> HashMap<TransferId, TransferEvents> {
+        let _guard = TRANSFER_LOCK.lock().await;
+        let mut events = get_event_handler().get_receiver().await;
+        // Leftovers from another test
+        while events.try_recv().is_ok() {}
+
+        let receiver = Arc::new(self.receiver.take().expect("only receives once"));
+        let ticket = self.ticket.clone();
+        let task: JoinHandle<_> = tokio::spawn({
+            let receiver = receiver.clone();
+            async move { receiver.retrieve(ticket).await }
+        });
+
+        let mut transfer_events: HashMap<TransferId, TransferEvents> = HashMap::new();
+
+        let collect = async {
+            while transfer_events
+                .values()
+                .filter(|events| {
+                    events.completions == 2
+                        || events.declines == 2
+                        || events.cancellations.len() == 2
+                        || events.refusals.len() == 2
+                })
+                .count()
+                < transfers
+            {
+                let event = events.recv().await.expect("event handler is alive");
+                on_event(&event, &self.sender, &receiver);
+
+                match event {
+                    Event::PreparingFile(id, metadata, _) => {
+                        transfer_events.entry(id).or_default().file_name = metadata.file_name;
+                    }
+                    Event::TransferUpdate(id, bytes) => {
+                        transfer_events.entry(id).or_default().updates.push(bytes);
+                    }
+                    Event::TransferComplete(id) => {
+                        transfer_events.entry(id).or_default().completions += 1;
+                    }
+                    Event::TransferOffered(id, metadata) => {
+                        transfer_events.entry(id).or_default().file_name = metadata.file_name;
+                    }
+                    Event::TransferDeclined(id) => {
+                        transfer_events.entry(id).or_default().declines += 1;
+                    }
+                    Event::TransferCancelled(id, reason) => {
+                        transfer_events
+                            .entry(id)
+                            .or_default()
+                            .cancellations
+                            .push(reason);
+                    }
+                    Event::TransferRefused(id, reason) => {
+                        transfer_events.entry(id).or_default().refusals.push(reason);
+                    }
+                    Event::TransferPaused(id) => {
+                        transfer_events.entry(id).or_default().pauses += 1;
+                    }
+                    Event::TransferResumed(id) => {
+                        transfer_events.entry(id).or_default().resumes += 1;
+                    }
+                    Event::FileConflict(id, _) => {
+                        transfer_events.entry(id).or_default().conflicts += 1;
+                    }
+                    Event::Reconnecting(_)
+                    | Event::Reconnected
+                    | Event::ManifestReceived(_)
+                    | Event::ManifestRefused(_)
+                    | Event::BatchUpdate(..) => {}
+                }
+            }
+        };
+
+        tokio::time::timeout(TRANSFER_TIMEOUT, collect)
+            .await
+            .expect("transfers complete in time");
+        task.abort();
+
+        transfer_events
+    }
+
+    pub fn downloaded(&self, relative_path: impl AsRef<Path>) -> PathBuf {
+        self.download_dir.path().join(relative_path)
+    }
+}
+
+async fn assert_same_content(a: &Path, b: &Path) {
+    let a = tokio::fs::read(a).await.unwrap();
+    let b = tokio::fs::read(b).await.unwrap();
+    assert_eq!(a.len(), b.len());
+    assert!(a == b, "file contents differ");
+}
+
+/// Names of every file left in `dir`, recursively.
+fn files_in(dir: &Path) -> HashSet<PathBuf> {
+    let mut files = HashSet::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(files_in(&path));
+        } else {
+            files.insert(path);
+        }
+    }
+
+    files
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transfers_files_of_various_sizes() {
+    let mut loopback = Loopback::new().await;
+
+    let sizes = [
+        ("empty", 0),
+        ("one", 1),
+        ("block", FILE_BLOCK_SIZE),
+        ("block_and_one", FILE_BLOCK_SIZE + 1),
+        ("many_blocks", 3 * FILE_BLOCK_SIZE + 123),
+    ];
+
+    for (name, len) in sizes {
+        let path = loopback.create_file(name, len).await;
+        loopback.sender.send(path).await.unwrap();
+    }
+
+    let transfers = loopback.receive(sizes.len()).await;
+
+    for (name, len) in sizes {
+        assert_same_content(
+            &loopback.send_dir.path().join(name),
+            &loopback.downloaded(name),
+        )
+        .await;
+
+        let events = transfers
+            .values()
+            .find(|events| events.file_name == name)
+            .unwrap();
+        assert_eq!(events.bytes_transferred(), len as u64);
+    }
+
+    // Every `.flap` partial was renamed once complete
+    let expected: HashSet<PathBuf> = sizes
+        .iter()
+        .map(|(name, _)| loopback.downloaded(name))
+        .collect();
+    assert_eq!(files_in(loopback.download_dir.path()), expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transfers_directories() {
+    let mut loopback = Loopback::new().await;
+
+    let files = [
+        ("album/a.jpg", 1000),
+        ("album/sub/b.jpg", FILE_BLOCK_SIZE + 7),
+        ("album/sub/c.jpg", 0),
+    ];
+    for (path, len) in files {
+        loopback.create_file(path, len).await;
+    }
+    tokio::fs::create_dir_all(loopback.send_dir.path().join("album/empty"))
+        .await
+        .unwrap();
+
+    loopback
+        .sender
+        .send(loopback.send_dir.path().join("album"))
+        .await
+        .unwrap();
+    let transfers = loopback.receive(1).await;
+
+    for (path, _) in files {
+        assert_same_content(
+            &loopback.send_dir.path().join(path),
+            &loopback.downloaded(path),
+        )
+        .await;
+    }
+    assert!(loopback.downloaded("album/empty").is_dir());
+
+    let events = transfers.values().next().unwrap();
+    assert_eq!(events.file_name, "album");
+    assert_eq!(
+        events.bytes_transferred(),
+        1000 + FILE_BLOCK_SIZE as u64 + 7
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transfers_directories_with_many_files() {
+    let mut loopback = Loopback::new().await;
+
+    let paths: Vec<_> = (0..2000)
+        .map(|i| format!("many/file-with-a-long-name-{i:04}.txt"))
+        .collect();
+    for path in &paths {
+        loopback.create_file(path, 1).await;
+    }
+
+    let directory = loopback.send_dir.path().join("many");
+    // The metadata is sent in several frames
+    let metadata = FlapFileMetadata::from_path(&directory).await.unwrap();
+    assert!(metadata.to_bytes().len() > MAX_FRAME_OPTIONAL_DATA_SIZE);
+
+    loopback.sender.send(directory).await.unwrap();
+    let transfers = loopback.receive(1).await;
+
+    for path in &paths {
+        assert_same_content(
+            &loopback.send_dir.path().join(path),
+            &loopback.downloaded(path),
+        )
+        .await;
+    }
+    let events = transfers.values().next().unwrap();
+    assert_eq!(events.bytes_transferred(), paths.len() as u64);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn announces_queued_files_up_front() {
+    let mut loopback = Loopback::new().await;
+
+    let files = [
+        ("one", 1000),
+        ("two", 3 * FILE_BLOCK_SIZE + 5),
+        ("album/a.jpg", 10),
+        ("album/sub/b.jpg", FILE_BLOCK_SIZE),
+    ];
+    for (path, len) in files {
+        loopback.create_file(path, len).await;
+    }
+    for item in ["one", "two", "album"] {
+        loopback
+            .sender
+            .send(loopback.send_dir.path().join(item))
+            .await
+            .unwrap();
+    }
+
+    let mut manifests = Vec::new();
+    let mut batch_updates = Vec::new();
+    loopback
+        .receive_with(3, |event, _, _| match event {
+            Event::ManifestReceived(manifest) => manifests.push(manifest.clone()),
+            Event::BatchUpdate(received, total) => batch_updates.push((*received, *total)),
+            _ => {}
+        })
+        .await;
+
+    // Everything was queued before connecting, so it is announced at once
+    let [manifest] = manifests.as_slice() else {
+        panic!("expected a single manifest, got {manifests:?}");
+    };
+    let announced: HashSet<_> = manifest
+        .entries
+        .iter()
+        .map(|entry| (entry.relative_path.clone(), entry.file_size))
+        .collect();
+    let expected: HashSet<_> = files
+        .iter()
+        .map(|(path, len)| (PathBuf::from(path), *len as u64))
+        .collect();
+    assert_eq!(announced, expected);
+
+    let total_size = files.iter().map(|(_, len)| *len as u64).sum();
+    assert_eq!(manifest.total_size(), total_size);
+    assert_eq!(batch_updates.last(), Some(&(total_size, total_size)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn receives_only_selected_files() {
+    let mut loopback = Loopback::new().await;
+
+    let files = [
+        ("a.pdf", 1000),
+        ("b.txt", 500),
+        ("album/c.pdf", 10),
+        ("album/d.jpg", FILE_BLOCK_SIZE),
+    ];
+    for (path, len) in files {
+        loopback.create_file(path, len).await;
+    }
+    for item in ["a.pdf", "b.txt", "album"] {
+        loopback
+            .sender
+            .send(loopback.send_dir.path().join(item))
+            .await
+            .unwrap();
+    }
+
+    let listed_paths = |receiver: P2pReceiver, ticket: Ticket| async move {
+        let manifest = tokio::time::timeout(TRANSFER_TIMEOUT, receiver.list(ticket))
+            .await
+            .expect("listed in time")
+            .unwrap();
+        manifest
+            .entries
+            .into_iter()
+            .map(|entry| entry.relative_path)
+            .collect::<HashSet<_>>()
+    };
+
+    // Listing does not use up the ticket
+    let other_receiver = P2pReceiver::with_endpoint(
+        P2pEndpoint::start(&P2pEndpointConfig::loopback())
+            .await
+            .unwrap(),
+        None,
+    );
+    assert_eq!(
+        listed_paths(other_receiver, loopback.ticket.clone()).await,
+        files.iter().map(|(path, _)| PathBuf::from(path)).collect()
+    );
+
+    let receiver = loopback.receiver.as_mut().unwrap();
+    receiver.set_selection(FileSelection::only(["*.pdf"]));
+    let same_receiver = receiver.clone();
+    let transfers = loopback.receive(2).await;
+
+    for path in ["a.pdf", "album/c.pdf"] {
+        assert_same_content(
+            &loopback.send_dir.path().join(path),
+            &loopback.downloaded(path),
+        )
+        .await;
+    }
+    assert_eq!(
+        files_in(loopback.download_dir.path()),
+        HashSet::from([
+            loopback.downloaded("a.pdf"),
+            loopback.downloaded("album/c.pdf")
+        ])
+    );
+    let album = transfers
+        .values()
+        .find(|events| events.file_name == "album")
+        .unwrap();
+    assert_eq!(album.bytes_transferred(), 10);
+
+    // The file that was not asked for is still queued
+    assert_eq!(
+        listed_paths(same_receiver, loopback.ticket.clone()).await,
+        HashSet::from([PathBuf::from("b.txt")])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sends_higher_priority_files_first() {
+    let scheduling = SchedulerConfig {
+        max_streams: 1,
+        order: QueueOrder::SmallestFirst,
+    };
+    let mut loopback = Loopback::with_config(&P2pEndpointConfig::loopback(), scheduling).await;
+
+    for (name, len, priority) in [("large", 3000, 0), ("small", 10, 0), ("urgent", 5000, 1)] {
+        let path = loopback.create_file(name, len).await;
+        loopback
+            .sender
+            .send_with_priority(path, priority)
+            .await
+            .unwrap();
+    }
+
+    let mut sent = Vec::new();
+    loopback
+        .receive_with(3, |event, _, _| {
+            if let Event::PreparingFile(_, metadata, true) = event {
+                sent.push(metadata.file_name.clone());
+            }
+        })
+        .await;
+
+    assert_eq!(sent, ["urgent", "small", "large"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn saves_to_chosen_download_dir() {
+    let mut loopback = Loopback::new().await;
+    let chosen_dir = loopback.download_dir.path().join("chosen").join("nested");
+    loopback
+        .receiver
+        .as_mut()
+        .unwrap()
+        .set_download_dir(chosen_dir.clone());
+
+    let path = loopback.create_file("file", 1000).await;
+    loopback.sender.send(&path).await.unwrap();
+    loopback.receive(1).await;
+
+    assert_same_content(&path, &chosen_dir.join("file")).await;
+
+    // A file where the directory should be is an error, before connecting
+    let mut other_receiver = P2pReceiver::with_endpoint(
+        P2pEndpoint::start(&P2pEndpointConfig::loopback())
+            .await
+            .unwrap(),
+        None,
+    );
+    other_receiver.set_download_dir(path.clone());
+    let result = other_receiver.retrieve(loopback.ticket.clone()).await;
+    assert!(matches!(result, Err(Error::DownloadDirError(dir, _)) if dir == path));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resolves_file_name_conflicts() {
+    for policy in [
+        ConflictPolicy::Overwrite,
+        ConflictPolicy::Rename,
+        ConflictPolicy::Skip,
+    ] {
+        let mut loopback = Loopback::new().await;
+        let receiver = loopback.receiver.as_mut().unwrap();
+        receiver.set_conflict_policy(policy);
+
+        loopback.create_file("album/taken.txt", 1000).await;
+        loopback.create_file("album/free.txt", 2000).await;
+        tokio::fs::create_dir_all(loopback.downloaded("album"))
+            .await
+            .unwrap();
+        tokio::fs::write(loopback.downloaded("album/taken.txt"), b"other file")
+            .await
+            .unwrap();
+
+        loopback
+            .sender
+            .send(loopback.send_dir.path().join("album"))
+            .await
+            .unwrap();
+        let transfers = loopback.receive(1).await;
+
+        let sent = |path| loopback.send_dir.path().join(path);
+        assert_same_content(
+            &sent("album/free.txt"),
+            &loopback.downloaded("album/free.txt"),
+        )
+        .await;
+        let taken = tokio::fs::read(loopback.downloaded("album/taken.txt"))
+            .await
+            .unwrap();
+        let renamed = loopback.downloaded("album/taken (1).txt");
+        let events = transfers.values().next().unwrap();
+
+        match policy {
+            ConflictPolicy::Overwrite => {
+                assert_eq!(
+                    taken,
+                    tokio::fs::read(sent("album/taken.txt")).await.unwrap()
+                );
+                assert!(!renamed.exists());
+            }
+            ConflictPolicy::Rename => {
+                assert_eq!(taken, b"other file");
+                assert_same_content(&sent("album/taken.txt"), &renamed).await;
+            }
+            ConflictPolicy::Skip => {
+                assert_eq!(taken, b"other file");
+                assert!(!renamed.exists());
+                assert_eq!(events.bytes_transferred(), 2000);
+            }
+        }
+        assert_eq!(events.completions, 2);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn asks_on_conflict() {
+    let mut loopback = Loopback::new().await;
+    let mut conflicts = loopback.receiver.as_mut().unwrap().ask_on_conflict();
+    tokio::spawn(async move {
+        while let Some(conflict) = conflicts.recv().await {
+            conflict.resolve(ConflictPolicy::Overwrite);
+        }
+    });
+
+    let path = loopback.create_file("taken", 1000).await;
+    tokio::fs::write(loopback.downloaded("taken"), b"other file")
+        .await
+        .unwrap();
+
+    loopback.sender.send(&path).await.unwrap();
+    let transfers = loopback.receive(1).await;
+
+    assert_same_content(&path, &loopback.downloaded("taken")).await;
+    assert_eq!(transfers.values().next().unwrap().conflicts, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resumes_from_partial_file() {
+    let mut loopback = Loopback::new().await;
+
+    let len = 4 * FILE_BLOCK_SIZE + 5;
+    let path = loopback.create_file("resumed", len).await;
+    let content = tokio::fs::read(&path).await.unwrap();
+    loopback
+        .create_partial("resumed", &content, 2 * FILE_BLOCK_SIZE + 3)
+        .await;
+
+    loopback.sender.send(&path).await.unwrap();
+    let transfers = loopback.receive(1).await;
+
+    assert_same_content(&path, &loopback.downloaded("resumed")).await;
+    assert!(!loopback.downloaded("resumed.flap").exists());
+    assert!(!loopback.downloaded("resumed.flap.state").exists());
+
+    // Only the missing blocks were sent
+    let events = transfers.values().next().unwrap();
+    assert_eq!(
+        events.bytes_transferred(),
+        (len - 2 * FILE_BLOCK_SIZE) as u64
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restarts_when_partial_file_does_not_match() {
+    let mut loopback = Loopback::new().await;
+
+    let len = 4 * FILE_BLOCK_SIZE + 5;
+    let path = loopback.create_file("stale", len).await;
+    let content = tokio::fs::read(&path).await.unwrap();
+    loopback
+        .create_partial("stale", &content, 2 * FILE_BLOCK_SIZE)
+        .await;
+    // Damaged since it was written
+    tokio::fs::write(
+        loopback.downloaded("stale.flap"),
+        vec![0xaa; 2 * FILE_BLOCK_SIZE],
+    )
+    .await
+    .unwrap();
+
+    loopback.sender.send(&path).await.unwrap();
+    let transfers = loopback.receive(1).await;
+
+    assert_same_content(&path, &loopback.downloaded("stale")).await;
+
+    let events = transfers.values().next().unwrap();
+    assert_eq!(events.bytes_transferred(), len as u64);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn does_not_resume_partial_file_of_another_file() {
+    let mut loopback = Loopback::new().await;
+
+    let len = 4 * FILE_BLOCK_SIZE + 5;
+    let path = loopback.create_file("other", len).await;
+    // Same name and size, other content
+    let other_content = vec![0xaa; len];
+    loopback
+        .create_partial("other", &other_content, 2 * FILE_BLOCK_SIZE)
+        .await;
+
+    loopback.sender.send(&path).await.unwrap();
+    let transfers = loopback.receive(1).await;
+
+    // Conflicts are renamed by default, leaving the other partial file alone
+    assert_same_content(&path, &loopback.downloaded("other (1)")).await;
+    assert!(loopback.downloaded("other.flap").exists());
+
+    let events = transfers.values().next().unwrap();
+    assert_eq!(events.bytes_transferred(), len as u64);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ticket_is_single_use() {
+    let mut loopback = Loopback::new().await;
+
+    let path = loopback.create_file("once", 1000).await;
+    loopback.sender.send(&path).await.unwrap();
+    loopback.receive(1).await;
+
+    let other_receiver = P2pReceiver::with_endpoint(
+        P2pEndpoint::start(&P2pEndpointConfig::loopback())
+            .await
+            .unwrap(),
+        Some(tempfile::tempdir().unwrap().path().to_path_buf()),
+    );
+    let result = tokio::time::timeout(
+        TRANSFER_TIMEOUT,
+        other_receiver.retrieve(loopback.ticket.clone()),
+    )
+    .await
+    .expect("rejected in time");
+    assert!(matches!(result, Err(Error::TicketAlreadyUsed)));
+
+    // The next receiver gets a new ticket
+    let next_ticket = loopback.sender.ticket().await;
+    assert_ne!(
+        next_ticket.master_key().encode_to_string(),
+        loopback.ticket.master_key().encode_to_string()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refuses_files_over_size_limits() {
+    let mut loopback = Loopback::new().await;
+    loopback
+        .receiver
+        .as_mut()
+        .unwrap()
+        .set_size_limits(SizeLimits {
+            max_file_size: Some(1000),
+            max_session_size: Some(1500),
+        });
+    let receiver = loopback.receiver.take().unwrap();
+
+    let _guard = TRANSFER_LOCK.lock().await;
+    let mut events = get_event_handler().get_receiver().await;
+    while events.try_recv().is_ok() {}
+    // `None` once a file is complete on either side
+    let mut next_answer = async || {
+        tokio::time::timeout(TRANSFER_TIMEOUT, async {
+            loop {
+                match events.recv().await.expect("event handler is alive") {
+                    Event::TransferComplete(_) => return None,
+                    Event::ManifestRefused(reason) => return Some(reason),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("answered in time")
+    };
+
+    let task = tokio::spawn({
+        let ticket = loopback.ticket.clone();
+        async move { receiver.retrieve(ticket).await }
+    });
+
+    // Each file is announced on its own, once the previous one was answered.
+    // The first one counts towards the session.
+    let path = loopback.create_file("fits", 800).await;
+    loopback.sender.send(path).await.unwrap();
+    assert_eq!(next_answer().await, None);
+    assert_eq!(next_answer().await, None);
+    for (name, len, reason) in [
+        ("too_large", 2000, RefuseReason::FileTooLarge),
+        ("over_session", 800, RefuseReason::SessionTooLarge),
+    ] {
+        let path = loopback.create_file(name, len).await;
+        loopback.sender.send(path).await.unwrap();
+        assert_eq!(next_answer().await, Some(reason));
+    }
+    task.abort();
+
+    // Nothing is left of the refused files
+    assert_eq!(
+        files_in(loopback.download_dir.path()),
+        HashSet::from([loopback.downloaded("fits")])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refuses_announced_files_that_do_not_fit_together() {
+    let mut loopback = Loopback::new().await;
+    loopback
+        .receiver
+        .as_mut()
+        .unwrap()
+        .set_selection(FileSelection::only(["*.jpg"]));
+    let mut receiver = loopback.receiver.take().unwrap();
+    receiver.set_size_limits(SizeLimits {
+        max_file_size: None,
+        max_session_size: Some(1500),
+    });
+
+    // Each file fits, but not both. Unselected files do not count.
+    for (name, len) in [("a.jpg", 800), ("b.jpg", 800), ("c.txt", 100_000)] {
+        let path = loopback.create_file(name, len).await;
+        loopback.sender.send(path).await.unwrap();
+    }
+
+    let _guard = TRANSFER_LOCK.lock().await;
+    let mut events = get_event_handler().get_receiver().await;
+    while events.try_recv().is_ok() {}
+
+    let task = tokio::spawn({
+        let ticket = loopback.ticket.clone();
+        async move { receiver.retrieve(ticket).await }
+    });
+    let refused = tokio::time::timeout(TRANSFER_TIMEOUT, async {
+        loop {
+            match events.recv().await.expect("event handler is alive") {
+                Event::ManifestRefused(reason) => return reason,
+                Event::PreparingFile(..) => panic!("no file is received"),
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("refused in time");
+    task.abort();
+
+    assert_eq!(refused, RefuseReason::SessionTooLarge);
+    assert!(files_in(loopback.download_dir.path()).is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn declined_items_are_skipped() {
+    let mut loopback = Loopback::new().await;
+    let mut offers = loopback.receiver.as_mut().unwrap().ask_before_receiving();
+
+    for name in ["unwanted", "wanted"] {
+        let path = loopback.create_file(name, 1000).await;
+        loopback.sender.send(path).await.unwrap();
+    }
+
+    let answer = tokio::spawn(async move {
+        while let Some(offer) = offers.recv().await {
+            assert_eq!(offer.file_count(), 1);
+            match offer.metadata.file_name.as_str() {
+                "unwanted" => offer.decline(),
+                _ => offer.rename("renamed".to_string()),
+            }
+        }
+    });
+    let transfers = loopback.receive(2).await;
+    answer.abort();
+
+    assert_same_content(
+        &loopback.send_dir.path().join("wanted"),
+        &loopback.downloaded("renamed"),
+    )
+    .await;
+    assert_eq!(
+        files_in(loopback.download_dir.path()),
+        HashSet::from([loopback.downloaded("renamed")])
+    );
+
+    let declined = transfers
+        .values()
+        .find(|events| events.file_name == "unwanted")
+        .unwrap();
+    assert_eq!(declined.declines, 2);
+    assert!(declined.updates.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sender_cancels_transfer() {
+    let mut loopback = Loopback::new().await;
+
+    let path = loopback.create_file("cancelled", 32 << 20).await;
+    loopback.sender.send(&path).await.unwrap();
+
+    // Once the receiver created the partial file
+    let mut cancelled = false;
+    let transfers = loopback
+        .receive_with(1, |event, sender, _| {
+            if let Event::TransferUpdate(id, _) = event
+                && !cancelled
+            {
+                sender.cancel(*id, CancelReason::UserRequested).unwrap();
+                cancelled = true;
+            }
+        })
+        .await;
+
+    let events = transfers.values().next().unwrap();
+    assert_eq!(events.cancellations, [CancelReason::UserRequested; 2]);
+    assert_eq!(events.completions, 0);
+
+    // Kept by default, so that the transfer can resume
+    assert_eq!(
+        files_in(loopback.download_dir.path()),
+        HashSet::from([
+            loopback.downloaded("cancelled.flap"),
+            loopback.downloaded("cancelled.flap.state")
+        ])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn receiver_cancels_transfer() {
+    let mut loopback = Loopback::new().await;
+    loopback
+        .receiver
+        .as_mut()
+        .unwrap()
+        .set_partial_policy(PartialFilePolicy::Delete);
+
+    let path = loopback.create_file("cancelled", 32 << 20).await;
+    loopback.sender.send(&path).await.unwrap();
+
+    let mut cancelled = false;
+    let transfers = loopback
+        .receive_with(1, |event, _, receiver| {
+            if let Event::TransferUpdate(id, _) = event
+                && !cancelled
+            {
+                receiver.cancel(*id, CancelReason::UserRequested).unwrap();
+                cancelled = true;
+            }
+        })
+        .await;
+
+    let events = transfers.values().next().unwrap();
+    assert_eq!(events.cancellations, [CancelReason::UserRequested; 2]);
+
+    assert!(files_in(loopback.download_dir.path()).is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pauses_and_resumes_transfer() {
+    let mut loopback = Loopback::new().await;
+
+    let path = loopback.create_file("paused", 8 << 20).await;
+    loopback.sender.send(&path).await.unwrap();
+
+    // The receiver pauses, then the sender too. The transfer only
+    // continues once both resumed it.
+    let (mut paused, mut pauses, mut resumes) = (false, 0, 0);
+    let transfers = loopback
+        .receive_with(1, |event, sender, receiver| match event {
+            Event::TransferUpdate(id, _) if !paused => {
+                receiver.pause(*id).unwrap();
+                paused = true;
+            }
+            Event::TransferPaused(id) => {
+                pauses += 1;
+                match pauses {
+                    2 => sender.pause(*id).unwrap(),
+                    4 => receiver.resume(*id).unwrap(),
+                    _ => {}
+                }
+            }
+            Event::TransferResumed(id) => {
+                resumes += 1;
+                if resumes == 2 {
+                    sender.resume(*id).unwrap();
+                }
+            }
+            _ => {}
+        })
+        .await;
+
+    let events = transfers.values().next().unwrap();
+    assert_eq!(events.pauses, 4);
+    assert_eq!(events.resumes, 4);
+    assert_eq!(events.bytes_transferred(), 8 << 20);
+    assert_same_content(&path, &loopback.downloaded("paused")).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reconnects_after_connection_drop() {
+    let (mut loopback, connections) = Loopback::with_droppable_connections().await;
+
+    let len = 8 << 20;
+    let path = loopback.create_file("dropped", len).await;
+    loopback.sender.send(&path).await.unwrap();
+
+    // Halfway through, so that the receiver already has part of the file
+    let (mut dropped, mut reconnecting, mut reconnected) = (false, 0, 0);
+    let transfers = loopback
+        .receive_with(1, |event, _, _| match event {
+            Event::TransferUpdate(_, bytes) if !dropped && *bytes >= len as u64 / 2 => {
+                connections.drop_all();
+                dropped = true;
+            }
+            Event::Reconnecting(_) => reconnecting += 1,
+            Event::Reconnected => reconnected += 1,
+            _ => {}
+        })
+        .await;
+
+    assert_eq!((reconnecting, reconnected), (1, 1));
+    assert_same_content(&path, &loopback.downloaded("dropped")).await;
+
+    // The stream of the second connection only sent what was missing
+    let resumed = transfers
+        .values()
+        .find(|events| events.completions == 2)
+        .unwrap();
+    assert!(resumed.bytes_transferred() < len as u64);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transfers_with_short_code() {
+    let mut loopback = Loopback::new().await;
+    let server = RendezvousServer::spawn(&P2pEndpointConfig::loopback())
+        .await
+        .unwrap();
+    let server_addr = server.endpoint().node_addr().initialized().await;
+
+    let sender_rendezvous = loopback.sender.rendezvous(server_addr.clone());
+    let code = ShortCode::allocate(&sender_rendezvous).await.unwrap();
+    let typed_code: ShortCode = code.to_string().parse().unwrap();
+    let receiver_rendezvous = loopback.receiver.as_ref().unwrap().rendezvous(server_addr);
+
+    let (sent, received) = tokio::join!(
+        loopback.sender.send_code(&code, &sender_rendezvous),
+        typed_code.receive_ticket(&receiver_rendezvous)
+    );
+    sent.unwrap();
+    let ticket = received.unwrap();
+    // Derived from the code on both sides, never sent
+    assert_eq!(
+        ticket.master_key().encode_to_string(),
+        loopback
+            .sender
+            .ticket()
+            .await
+            .master_key()
+            .encode_to_string()
+    );
+    // The nameplate is released once used
+    assert!(matches!(
+        code.receive_ticket(&receiver_rendezvous).await,
+        Err(Error::UnknownCode)
+    ));
+
+    loopback.ticket = ticket;
+    let path = loopback.create_file("coded", 1000).await;
+    loopback.sender.send(&path).await.unwrap();
+    loopback.receive(1).await;
+
+    assert_same_content(&path, &loopback.downloaded("coded")).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rendezvous_server_keeps_sides_to_their_device() {
+    let server = RendezvousServer::spawn(&P2pEndpointConfig::loopback())
+        .await
+        .unwrap();
+    let server_addr = server.endpoint().node_addr().initialized().await;
+    let rendezvous = async || {
+        let p2p_endpoint = P2pEndpoint::start(&P2pEndpointConfig::loopback())
+            .await
+            .unwrap();
+        RemoteRendezvous::new(p2p_endpoint, server_addr.clone())
+    };
+    let (sender, receiver, other) = (rendezvous().await, rendezvous().await, rendezvous().await);
+
+    let nameplate = sender.allocate().await.unwrap();
+    sender
+        .post(
+            nameplate,
+            Side::Sender,
+            Phase::Pake,
+            Bytes::from_static(b"pake"),
+        )
+        .await
+        .unwrap();
+    let message = receiver
+        .fetch(nameplate, Side::Receiver, Phase::Pake)
+        .await
+        .unwrap();
+    assert_eq!(message, &b"pake"[..]);
+
+    // Nobody else can read or write as either side
+    assert!(matches!(
+        other.fetch(nameplate, Side::Receiver, Phase::Pake).await,
+        Err(Error::CodeAlreadyUsed)
+    ));
+    assert!(matches!(
+        other
+            .post(nameplate, Side::Sender, Phase::NodeAddr, Bytes::new())
+            .await,
+        Err(Error::CodeAlreadyUsed)
+    ));
+
+    // A device only holds so many nameplates at once
+    for _ in 0..MAX_NAMEPLATES_PER_CONNECTION {
+        other.allocate().await.unwrap();
+    }
+    assert!(matches!(other.allocate().await, Err(Error::TooManyCodes)));
+}
+
+/// Checks that sending many files at once is not slower than one at a time,
+/// for several stream limits, and prints the throughput of each:
+/// `cargo test --release -- --ignored --nocapture measures_throughput`
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn measures_throughput_of_concurrent_streams() {
+    const FILES: usize = 16;
+    const FILE_LEN: usize = 8 << 20;
+
+    let mut single_stream = None;
+    for max_streams in [1, 2, 4, 8] {
+        let scheduling = SchedulerConfig {
+            max_streams,
+            ..Default::default()
+        };
+        let mut loopback = Loopback::with_config(&P2pEndpointConfig::loopback(), scheduling).await;
+        loopback
+            .receiver
+            .as_mut()
+            .unwrap()
+            .set_max_streams(max_streams);
+
+        for i in 0..FILES {
+            let path = loopback.create_file(format!("file {i}"), FILE_LEN).await;
+            loopback.sender.send(path).await.unwrap();
+        }
+
+        let start = Instant::now();
+        let transfers = loopback.receive(FILES).await;
+        let elapsed = start.elapsed();
+
+        let bytes: u64 = transfers
+            .values()
+            .map(TransferEvents::bytes_transferred)
+            .sum();
+        assert_eq!(bytes, (FILES * FILE_LEN) as u64);
+        let throughput = bytes as f64 / (1 << 20) as f64 / elapsed.as_secs_f64();
+        println!("{max_streams} stream(s): {throughput:.1} MiB/s");
+
+        // Give or take the noise of the machine
+        let single_stream = *single_stream.get_or_insert(throughput);
+        assert!(
+            throughput >= single_stream * 0.75,
+            "{max_streams} stream(s) are slower than one: {throughput:.1} MiB/s"
+        );
+    }
+}
+
+/// Sends a sparse file of `len` bytes, which takes no space on the sender's disk.
+async fn transfers_sparse_file_of(len: u64) {
+    let mut loopback = Loopback::new().await;
+
+    let path = loopback.send_dir.path().join("sparse");
+    let file = std::fs::File::create(&path).unwrap();
+    file.set_len(len).unwrap();
+
+    loopback.sender.send(&path).await.unwrap();
+    let transfers = loopback.receive(1).await;
+
+    let downloaded = loopback.downloaded("sparse");
+    assert_eq!(std::fs::metadata(&downloaded).unwrap().len(), len);
+    let events = transfers.values().next().unwrap();
+    assert_eq!(events.bytes_transferred(), len);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transfers_sparse_file() {
+    // Many blocks, yet quick to send in debug builds
+    transfers_sparse_file_of((8 << 20) + 1).await;
+}
+
+/// Sizes and offsets past 32 bits, which take a while to send:
+/// `cargo test --release -- --ignored transfers_multi_gigabyte_sparse_file`
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn transfers_multi_gigabyte_sparse_file() {
+    transfers_sparse_file_of((3 << 30) + 1).await;
+}
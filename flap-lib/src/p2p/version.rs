@@ -0,0 +1,184 @@
+//! Protocol version negotiation.
+//!
+//! Both peers put a [`ProtocolHello`] in their Noise handshake payload. The
+//! initiator sends its own, the responder answers with its own, and both then
+//! derive the same [`NegotiatedProtocol`] from the two hellos.
+
+use std::ops::BitAnd;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::error::{Error, ProtocolError, Result};
+
+/// The version of the Flap protocol implemented by this crate.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// The oldest version of the Flap protocol this crate can still talk to.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+
+/// Optional protocol features. Only the features supported by both peers
+/// may be used during a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    // Bits 0 to 7 were for directories, declining, cancelling, pausing,
+    // skipping, refusing, manifests and choosing files, which every peer
+    // since `MIN_PROTOCOL_VERSION` supports.
+
+    /// Every capability implemented by this crate.
+    pub const fn supported() -> Self {
+        Self::NONE
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Self(self.0 & rhs.0)
+    }
+}
+
+/// Sent by each peer in its Noise handshake message.
+///
+/// [(u16)(u16)(u32)]
+/// (u16) is the protocol version of the peer
+/// (u16) is the oldest protocol version the peer supports
+/// (u32) is the capabilities of the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolHello {
+    pub version: u16,
+    pub min_version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl Default for ProtocolHello {
+    fn default() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+        }
+    }
+}
+
+impl ProtocolHello {
+    pub const LENGTH: usize = size_of::<u16>() + size_of::<u16>() + size_of::<u32>();
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut bytes = BytesMut::with_capacity(Self::LENGTH);
+        bytes.put_u16(self.version);
+        bytes.put_u16(self.min_version);
+        bytes.put_u32(self.capabilities.bits());
+
+        bytes.into()
+    }
+
+    /// Peers that predate version negotiation send an empty payload,
+    /// which is reported as a version mismatch.
+    pub fn from_bytes(mut bytes: Bytes) -> Result<Self> {
+        if bytes.is_empty() {
+            return Err(Error::ProtocolVersionMismatch {
+                local: PROTOCOL_VERSION,
+                remote: 0,
+            });
+        }
+
+        if bytes.remaining() < Self::LENGTH {
+            return Err(ProtocolError::TruncatedPayload.into());
+        }
+
+        Ok(Self {
+            version: bytes.get_u16(),
+            min_version: bytes.get_u16(),
+            capabilities: Capabilities::from_bits(bytes.get_u32()),
+        })
+    }
+
+    /// Agrees on the highest version both peers support, and on the
+    /// capabilities both peers have.
+    pub fn negotiate(&self, remote: &ProtocolHello) -> Result<NegotiatedProtocol> {
+        let version = self.version.min(remote.version);
+
+        if version < self.min_version.max(remote.min_version) {
+            return Err(Error::ProtocolVersionMismatch {
+                local: self.version,
+                remote: remote.version,
+            });
+        }
+
+        Ok(NegotiatedProtocol {
+            version,
+            capabilities: self.capabilities & remote.capabilities,
+        })
+    }
+}
+
+/// The protocol both peers agreed on for a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegotiatedProtocol {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl NegotiatedProtocol {
+    /// Fails if the remote peer does not support `capability`.
+    pub fn require(&self, capability: Capabilities) -> Result<()> {
+        if self.capabilities.contains(capability) {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedByPeer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        let ours = ProtocolHello::default();
+        let roundtrip = ProtocolHello::from_bytes(ours.to_bytes()).unwrap();
+        assert_eq!(roundtrip, ours);
+
+        // A capability only one of the peers has
+        let capability = Capabilities::from_bits(1 << 8);
+        let other = ProtocolHello {
+            capabilities: capability,
+            ..ours
+        };
+        let negotiated = ours.negotiate(&other).unwrap();
+        assert_eq!(negotiated, other.negotiate(&ours).unwrap());
+        assert!(negotiated.require(capability).is_err());
+        assert!(other.negotiate(&other).unwrap().require(capability).is_ok());
+
+        let newer = ProtocolHello {
+            version: PROTOCOL_VERSION + 1,
+            min_version: PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::supported(),
+        };
+        assert!(matches!(
+            ours.negotiate(&newer),
+            Err(Error::ProtocolVersionMismatch { .. })
+        ));
+
+        assert!(matches!(
+            ProtocolHello::from_bytes(Bytes::new()),
+            Err(Error::ProtocolVersionMismatch { remote: 0, .. })
+        ));
+    }
+}
@@ -1,16 +1,29 @@
 //! Info needed to connect to a device
-//! Right now only includes the iroh endpoint of device
+//!
+//! A ticket looks like `flap/<node id>/<master key>[/<addresses>]`.
+//! The optional addresses segment lets the receiver connect without any
+//! discovery service. Older versions of Flap ignore it.
 
-use std::str::FromStr;
+use std::{collections::BTreeSet, net::SocketAddr, str::FromStr};
 
 use base64ct::{Base64Url, Encoding};
-use iroh::{NodeId, PublicKey};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use iroh::{NodeAddr, NodeId, PublicKey, RelayUrl};
 
 use crate::{crypto::master_key::MasterKey, error::Error};
 
+/// Version of the addresses segment. Segments of unknown versions are ignored.
+const ADDRESSES_VERSION: u8 = 1;
+/// Tickets are meant to be shared by hand, so they only carry a few addresses.
+const MAX_DIRECT_ADDRESSES: usize = 8;
+
 #[derive(Debug, Clone)]
 pub struct Ticket {
     pub node_id: NodeId,
+    /// The relay the sender can be reached through.
+    pub relay_url: Option<RelayUrl>,
+    /// Addresses where the sender can be reached directly.
+    pub direct_addresses: BTreeSet<SocketAddr>,
     master_key: MasterKey,
 }
 
@@ -19,19 +32,186 @@ impl Ticket {
         &self.master_key
     }
 
-    pub fn make(node_id: NodeId, master_key: MasterKey) -> Self {
+    /// Makes a ticket for the given node. If `node_addr` carries a relay or
+    /// direct addresses, they are included in the ticket.
+    pub fn make(node_addr: impl Into<NodeAddr>, master_key: MasterKey) -> Self {
+        let node_addr = node_addr.into();
+
         Self {
-            node_id,
+            node_id: node_addr.node_id,
+            relay_url: node_addr.relay_url,
+            direct_addresses: node_addr
+                .direct_addresses
+                .into_iter()
+                .take(MAX_DIRECT_ADDRESSES)
+                .collect(),
             master_key,
         }
     }
 
+    /// Everything known about how to reach the sender.
+    pub fn node_addr(&self) -> NodeAddr {
+        NodeAddr::from_parts(
+            self.node_id,
+            self.relay_url.clone(),
+            self.direct_addresses.iter().copied(),
+        )
+    }
+
     pub fn convert(&self) -> String {
-        format!(
+        let mut ticket = format!(
             "flap/{}/{}",
             Base64Url::encode_string(self.node_id.as_bytes()),
             self.master_key.encode_to_string()
+        );
+
+        if self.relay_url.is_some() || !self.direct_addresses.is_empty() {
+            ticket.push('/');
+            ticket.push_str(&Base64Url::encode_string(&self.encode_addresses()));
+        }
+
+        ticket
+    }
+
+    /// Everything but the master key, for devices that derive it on their own
+    /// (see [`crate::code`]).
+    ///
+    /// [(32 bytes node id)(addresses)], the addresses as in [`Self::encode_addresses`]
+    pub(crate) fn encode_node_addr(&self) -> Bytes {
+        let mut bytes = BytesMut::new();
+        bytes.put_slice(self.node_id.as_bytes());
+        bytes.put(self.encode_addresses());
+
+        bytes.into()
+    }
+
+    /// Decodes what [`Self::encode_node_addr`] wrote, and adds `master_key` to it.
+    pub(crate) fn decode_node_addr(mut bytes: Bytes, master_key: MasterKey) -> Result<Self, Error> {
+        if bytes.remaining() < size_of::<NodeId>() {
+            return Err(Error::TicketParseError);
+        }
+        let node_id = PublicKey::from_bytes(
+            &bytes.split_to(size_of::<NodeId>())[..]
+                .try_into()
+                .map_err(|_| Error::TicketParseError)?,
         )
+        .map_err(|_| Error::TicketParseError)?;
+        let (relay_url, direct_addresses) = Self::decode_addresses(bytes)?.unwrap_or_default();
+
+        Ok(Self {
+            node_id,
+            relay_url,
+            direct_addresses,
+            master_key,
+        })
+    }
+
+    /// [(u8)(u16)(relay url)(u8)(addresses...)]
+    /// (u8) is [`ADDRESSES_VERSION`]
+    /// (u16) is the length of the relay url that follows, 0 if there is none
+    /// (u8) is the number of direct addresses that follow
+    /// Each address is [(u8)(ip)(u16)], where (u8) is 4 or 6 for the IP version
+    /// IPv6 scope ids are local to a device, so they are not included
+    /// A relay url too long for its (u16) is left out, and only the first
+    /// [`MAX_DIRECT_ADDRESSES`] direct addresses are included
+    fn encode_addresses(&self) -> Bytes {
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(ADDRESSES_VERSION);
+
+        let relay_url = self
+            .relay_url
+            .as_ref()
+            .map(|relay_url| relay_url.to_string())
+            .unwrap_or_default();
+        match u16::try_from(relay_url.len()) {
+            Ok(len) => {
+                bytes.put_u16(len);
+                bytes.put_slice(relay_url.as_bytes());
+            }
+            Err(_) => bytes.put_u16(0),
+        }
+
+        let direct_addresses = self.direct_addresses.iter().take(MAX_DIRECT_ADDRESSES);
+        bytes.put_u8(
+            u8::try_from(direct_addresses.len()).expect("MAX_DIRECT_ADDRESSES fits in a u8"),
+        );
+        for address in direct_addresses {
+            match address {
+                SocketAddr::V4(address) => {
+                    bytes.put_u8(4);
+                    bytes.put_slice(&address.ip().octets());
+                }
+                SocketAddr::V6(address) => {
+                    bytes.put_u8(6);
+                    bytes.put_slice(&address.ip().octets());
+                }
+            }
+            bytes.put_u16(address.port());
+        }
+
+        bytes.into()
+    }
+
+    /// Returns `None` if the segment is from a newer version of Flap.
+    #[allow(clippy::type_complexity)]
+    fn decode_addresses(
+        mut bytes: Bytes,
+    ) -> Result<Option<(Option<RelayUrl>, BTreeSet<SocketAddr>)>, Error> {
+        fn ensure(bytes: &Bytes, len: usize) -> Result<(), Error> {
+            if bytes.remaining() < len {
+                Err(Error::TicketParseError)
+            } else {
+                Ok(())
+            }
+        }
+
+        ensure(&bytes, size_of::<u8>())?;
+        if bytes.get_u8() != ADDRESSES_VERSION {
+            return Ok(None);
+        }
+
+        ensure(&bytes, size_of::<u16>())?;
+        let relay_url_len = bytes.get_u16() as usize;
+        ensure(&bytes, relay_url_len)?;
+        let relay_url = match relay_url_len {
+            0 => None,
+            len => Some(
+                std::str::from_utf8(&bytes.split_to(len))
+                    .map_err(|_| Error::TicketParseError)?
+                    .parse()
+                    .map_err(|_| Error::TicketParseError)?,
+            ),
+        };
+
+        ensure(&bytes, size_of::<u8>())?;
+        let address_count = bytes.get_u8() as usize;
+        if address_count > MAX_DIRECT_ADDRESSES {
+            return Err(Error::TicketParseError);
+        }
+
+        let mut direct_addresses = BTreeSet::new();
+        for _ in 0..address_count {
+            ensure(&bytes, size_of::<u8>())?;
+            let ip = match bytes.get_u8() {
+                4 => {
+                    ensure(&bytes, 4)?;
+                    let mut octets = [0u8; 4];
+                    bytes.copy_to_slice(&mut octets);
+                    std::net::IpAddr::from(octets)
+                }
+                6 => {
+                    ensure(&bytes, 16)?;
+                    let mut octets = [0u8; 16];
+                    bytes.copy_to_slice(&mut octets);
+                    std::net::IpAddr::from(octets)
+                }
+                _ => return Err(Error::TicketParseError),
+            };
+            ensure(&bytes, size_of::<u16>())?;
+            direct_addresses.insert(SocketAddr::new(ip, bytes.get_u16()));
+        }
+
+        Ok(Some((relay_url, direct_addresses)))
     }
 }
 
@@ -41,7 +221,7 @@ impl FromStr for Ticket {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let ticket_string = s.to_owned();
         let split: Vec<&str> = ticket_string.split('/').collect();
-        let flap = split.get(0).ok_or(Error::TicketParseError)?;
+        let flap = split.first().ok_or(Error::TicketParseError)?;
         let node_id_str = split.get(1).ok_or(Error::TicketParseError)?;
         let master_key_str = split.get(2).ok_or(Error::TicketParseError)?;
 
@@ -53,15 +233,114 @@ impl FromStr for Ticket {
             &Base64Url::decode_vec(node_id_str)
                 .map_err(|_| Error::TicketParseError)?
                 .try_into()
-                .unwrap(),
+                .map_err(|_| Error::TicketParseError)?,
         )
         .map_err(|_| Error::TicketParseError)?;
 
         let master_key = master_key_str.parse()?;
 
+        let (relay_url, direct_addresses) = match split.get(3) {
+            Some(addresses_str) => {
+                let addresses =
+                    Base64Url::decode_vec(addresses_str).map_err(|_| Error::TicketParseError)?;
+                Self::decode_addresses(addresses.into())?.unwrap_or_default()
+            }
+            None => Default::default(),
+        };
+
         Ok(Self {
             node_id,
+            relay_url,
+            direct_addresses,
             master_key,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn ticket_roundtrip(secret in any::<[u8; 32]>(), master_key in any::<[u8; 16]>()) {
+            let node_id = SecretKey::from_bytes(&secret).public();
+            let ticket = Ticket::make(node_id, MasterKey(master_key));
+
+            let roundtrip: Ticket = ticket.convert().parse().unwrap();
+            prop_assert_eq!(roundtrip.node_id, ticket.node_id);
+            prop_assert_eq!(roundtrip.master_key().0, master_key);
+            prop_assert_eq!(roundtrip.node_addr(), NodeAddr::new(node_id));
+        }
+
+        #[test]
+        fn ticket_with_addresses_roundtrip(
+            secret in any::<[u8; 32]>(),
+            direct_addresses in prop::collection::btree_set(
+                (any::<std::net::IpAddr>(), any::<u16>()).prop_map(SocketAddr::from),
+                0..=MAX_DIRECT_ADDRESSES,
+            ),
+            relay in prop::option::of("[a-z]{1,16}"),
+        ) {
+            let node_addr = NodeAddr::from_parts(
+                SecretKey::from_bytes(&secret).public(),
+                relay.map(|relay| format!("https://{relay}.example.com").parse().unwrap()),
+                direct_addresses,
+            );
+            let ticket = Ticket::make(node_addr.clone(), MasterKey::generate());
+
+            let roundtrip: Ticket = ticket.convert().parse().unwrap();
+            prop_assert_eq!(roundtrip.node_addr(), node_addr);
+        }
+
+        #[test]
+        fn ticket_keeps_the_first_direct_addresses(
+            secret in any::<[u8; 32]>(),
+            direct_addresses in prop::collection::btree_set(
+                (any::<std::net::IpAddr>(), any::<u16>()).prop_map(SocketAddr::from),
+                MAX_DIRECT_ADDRESSES + 1..=4 * MAX_DIRECT_ADDRESSES,
+            ),
+        ) {
+            let mut ticket = Ticket::make(SecretKey::from_bytes(&secret).public(), MasterKey::generate());
+            ticket.direct_addresses = direct_addresses.clone();
+
+            let roundtrip: Ticket = ticket.convert().parse().unwrap();
+            prop_assert_eq!(
+                roundtrip.direct_addresses,
+                direct_addresses.into_iter().take(MAX_DIRECT_ADDRESSES).collect::<BTreeSet<_>>()
+            );
+        }
+
+        #[test]
+        fn parsing_arbitrary_tickets_never_panics(s in "\\PC*", node_id in "[A-Za-z0-9_-]{0,64}") {
+            let _ = s.parse::<Ticket>();
+            let _ = format!("flap/{node_id}/{s}").parse::<Ticket>();
+        }
+    }
+
+    #[test]
+    fn addresses_are_optional_and_versioned() {
+        let node_id = SecretKey::from_bytes(&[1; 32]).public();
+        let ticket = Ticket::make(
+            NodeAddr::new(node_id).with_direct_addresses(["192.168.1.2:4242".parse().unwrap()]),
+            MasterKey::generate(),
+        );
+        let ticket_string = ticket.convert();
+
+        // Tickets from before addresses were added
+        let (without_addresses, _) = ticket_string.rsplit_once('/').unwrap();
+        let parsed: Ticket = without_addresses.parse().unwrap();
+        assert_eq!(parsed.node_addr(), NodeAddr::new(node_id));
+
+        // Tickets from a newer version of Flap
+        let newer = format!("{without_addresses}/{}", Base64Url::encode_string(&[2, 0]));
+        let parsed: Ticket = newer.parse().unwrap();
+        assert_eq!(parsed.node_addr(), NodeAddr::new(node_id));
+
+        let truncated = format!("{without_addresses}/{}", Base64Url::encode_string(&[1, 0]));
+        assert!(truncated.parse::<Ticket>().is_err());
+    }
+}